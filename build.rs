@@ -0,0 +1,37 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Size classes can be replaced by a comma separated list of block sizes in bytes, e.g.
+// `SAMPLE_ALLOC_SIZE_CLASSES=8,16,24,40,72,136,256,512,1024,2048,4096,8192,16384`.
+// The list is validated on compile time, and must reach the default largest class.
+const SIZE_CLASSES_ENV: &str = "SAMPLE_ALLOC_SIZE_CLASSES";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed={}", SIZE_CLASSES_ENV);
+
+    let custom_size_of_class = match env::var(SIZE_CLASSES_ENV) {
+        Ok(value) => {
+            let sizes = value
+                .split(',')
+                .map(|item| match item.trim().parse::<usize>() {
+                    Ok(size) => size,
+                    Err(err) => panic!("{}: invalid size {:?}: {}", SIZE_CLASSES_ENV, item, err),
+                })
+                .collect::<Vec<_>>();
+            format!("Some(&{:?})", sizes)
+        }
+        Err(_) => "None".to_string(),
+    };
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(
+        Path::new(&out_dir).join("size_classes.rs"),
+        format!(
+            "const CUSTOM_SIZE_OF_CLASS: Option<&[usize]> = {};\n",
+            custom_size_of_class
+        ),
+    )
+    .unwrap();
+}
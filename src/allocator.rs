@@ -1,4 +1,7 @@
 use std::error::Error;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::ptr;
 use std::result::Result;
 
 use crate::internal;
@@ -7,12 +10,58 @@ use crate::sys::SysMemEnv;
 
 pub trait Allocator {
     unsafe fn alloc(&mut self, size: usize) -> Result<AnyNonNullPtr, Box<dyn Error>>;
+    unsafe fn alloc_aligned(
+        &mut self,
+        size: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>>;
     unsafe fn free(&mut self, p: AnyNonNullPtr) -> Result<(), Box<dyn Error>>;
 }
 
 pub struct Config {
     pub min_heap_size: usize,
     pub max_heap_size: usize,
+    pub use_huge_pages: bool,
+    pub large_blocks_cache_size: usize,
+    // Fills blocks on subheaps with patterns on free and allocation, to detect writes after free.
+    pub poison_blocks: bool,
+    // Holds freed blocks up to the size before releasing them, to catch uses after free. Zero disables it.
+    // Blocks are poisoned with it, whether poison_blocks is set or not.
+    pub quarantine_size: usize,
+    // Maps a guard page before or after each large block, to fault on overflows of it.
+    pub large_block_guard_page: internal::layout::arena::GuardPage,
+    // Called with live blocks on dropping or releasing the allocator, if any.
+    // Nothing is reported at process exit, since there is no global allocator mode.
+    pub leak_sink: Option<LeakSink>,
+    // Allocations are sampled once per the bytes, and all of them with zero.
+    #[cfg(feature = "site-tracking")]
+    pub site_sample_interval: usize,
+}
+
+pub type LeakSink = fn(&LeakCheck);
+
+// Live blocks grouped by size classes, and by sites when they are tracked.
+#[derive(Debug)]
+pub struct LeakCheck {
+    pub leaks: internal::layout::arena::leaks::LeakReport,
+    #[cfg(feature = "site-tracking")]
+    pub sites: internal::sites::SiteReport,
+}
+
+impl LeakCheck {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.leaks.is_empty()
+    }
+}
+
+impl fmt::Display for LeakCheck {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}", self.leaks)?;
+        #[cfg(feature = "site-tracking")]
+        write!(formatter, "{}", self.sites)?;
+        Ok(())
+    }
 }
 
 pub unsafe fn init<Env: SysMemEnv>(
@@ -22,10 +71,20 @@ pub unsafe fn init<Env: SysMemEnv>(
     SampleAllocWithEnv::<Env>::init(env, config)
 }
 
+pub unsafe fn open<Env: SysMemEnv>(
+    env: Env,
+    context_space: AnyNonNullPtr,
+) -> Result<SampleAllocWithEnv<Env>, Box<dyn Error>> {
+    SampleAllocWithEnv::<Env>::open(env, context_space)
+}
+
 #[derive(Debug)]
 pub struct SampleAllocWithEnv<Env> {
     env: Env,
     internal: internal::allocator::SampleAlloc,
+    leak_sink: Option<LeakSink>,
+    #[cfg(feature = "site-tracking")]
+    sites: internal::sites::SiteTracker,
 }
 
 impl<Env> SampleAllocWithEnv<Env>
@@ -41,10 +100,123 @@ where
                 keep_segments_count: (config.min_heap_size
                     / internal::layout::segment::SEGMENT_SIZE)
                     + 12,
+                use_huge_pages: config.use_huge_pages,
+                large_blocks_cache_size: config.large_blocks_cache_size,
+                poisons_blocks: config.poison_blocks,
+                quarantine_size: config.quarantine_size,
+                large_block_guard_page: config.large_block_guard_page,
             },
         )?;
 
-        Ok(SampleAllocWithEnv { env, internal })
+        Ok(SampleAllocWithEnv {
+            env,
+            internal,
+            leak_sink: config.leak_sink,
+            #[cfg(feature = "site-tracking")]
+            sites: internal::sites::SiteTracker::new(config.site_sample_interval),
+        })
+    }
+
+    unsafe fn open(mut env: Env, context_space: AnyNonNullPtr) -> Result<Self, Box<dyn Error>> {
+        let internal = internal::allocator::SampleAlloc::open(&mut env, context_space)?;
+
+        Ok(SampleAllocWithEnv {
+            env,
+            internal,
+            leak_sink: None,
+            #[cfg(feature = "site-tracking")]
+            sites: internal::sites::SiteTracker::new(internal::sites::DEFAULT_SAMPLE_INTERVAL),
+        })
+    }
+
+    pub unsafe fn stats(&self) -> internal::layout::arena::Stats {
+        self.internal.stats()
+    }
+
+    // No allocation is in flight while the allocator is borrowed.
+    pub unsafe fn for_each_live_block<F>(&self, f: F)
+    where
+        F: FnMut(AnyNonNullPtr, usize, internal::layout::block::Type),
+    {
+        self.internal.for_each_live_block(f)
+    }
+
+    #[allow(unused)]
+    pub unsafe fn verify(&self) -> internal::layout::arena::verifier::Report {
+        self.internal.verify()
+    }
+
+    #[allow(unused)]
+    pub unsafe fn dump(&self) -> internal::layout::arena::dump::HeapDump {
+        self.internal.dump()
+    }
+
+    pub unsafe fn fragmentation_report(
+        &self,
+    ) -> internal::layout::arena::fragmentation::FragmentationReport {
+        self.internal.fragmentation_report()
+    }
+
+    // Only sampled allocations which are not freed yet are reported.
+    #[allow(unused)]
+    #[cfg(feature = "site-tracking")]
+    pub fn site_report(&self) -> internal::sites::SiteReport {
+        self.sites.report()
+    }
+
+    #[allow(unused)]
+    pub unsafe fn flush_quarantine(&mut self) -> Result<(), Box<dyn Error>> {
+        self.internal.flush_quarantine_with_env(&mut self.env)
+    }
+
+    #[inline]
+    pub fn env(&mut self) -> &mut Env {
+        &mut self.env
+    }
+
+    #[allow(unused)]
+    pub unsafe fn check_leaks(&self) -> LeakCheck {
+        check_leaks(self)
+    }
+
+    pub unsafe fn release(self) -> Result<(), Box<dyn Error>> {
+        report_leaks_to_sink(&self);
+
+        // Not to report leaks again on drop, after the arena is released.
+        let manager = ManuallyDrop::new(self);
+        let mut env = ptr::read(&manager.env);
+        let internal = ptr::read(&manager.internal);
+        #[cfg(feature = "site-tracking")]
+        drop(ptr::read(&manager.sites));
+        internal.release_with_env(&mut env)
+    }
+}
+
+// The arena is not released on drop, since it may be reopened from the context space.
+impl<Env> Drop for SampleAllocWithEnv<Env> {
+    fn drop(&mut self) {
+        unsafe { report_leaks_to_sink(self) }
+    }
+}
+
+unsafe fn report_leaks_to_sink<Env>(manager: &SampleAllocWithEnv<Env>) {
+    let leak_sink = match manager.leak_sink {
+        Some(leak_sink) => leak_sink,
+        None => return,
+    };
+
+    let leak_check = check_leaks(manager);
+    if !leak_check.is_empty() {
+        leak_sink(&leak_check);
+    }
+}
+
+// Also called on drop, where the env is not bound to `SysMemEnv`.
+unsafe fn check_leaks<Env>(manager: &SampleAllocWithEnv<Env>) -> LeakCheck {
+    LeakCheck {
+        leaks: manager.internal.leak_report(),
+        #[cfg(feature = "site-tracking")]
+        sites: manager.sites.report(),
     }
 }
 
@@ -53,10 +225,444 @@ where
     Env: SysMemEnv,
 {
     unsafe fn alloc(&mut self, size: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
-        self.internal.alloc_with_env(&mut self.env, size)
+        let block_ptr = self.internal.alloc_with_env(&mut self.env, size)?;
+        #[cfg(feature = "site-tracking")]
+        self.sites.record_alloc(block_ptr.as_addr(), size);
+        Ok(block_ptr)
+    }
+
+    unsafe fn alloc_aligned(
+        &mut self,
+        size: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        let block_ptr =
+            self.internal
+                .alloc_aligned_with_env(&mut self.env, size, alignment_size)?;
+        #[cfg(feature = "site-tracking")]
+        self.sites.record_alloc(block_ptr.as_addr(), size);
+        Ok(block_ptr)
     }
 
     unsafe fn free(&mut self, p: AnyNonNullPtr) -> Result<(), Box<dyn Error>> {
-        self.internal.free_with_env(&mut self.env, p)
+        self.internal.free_with_env(&mut self.env, p)?;
+        #[cfg(feature = "site-tracking")]
+        self.sites.record_free(p.as_addr());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::mem::size_of;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::internal::layout::arena::GuardPage;
+    use crate::internal::layout::block;
+    use crate::internal::layout::constants::ALIGNMENT_SIZE;
+    use crate::internal::layout::segment;
+    use crate::internal::layout::subheap;
+    use crate::sys;
+    use crate::util;
+
+    fn test_config() -> Config {
+        Config {
+            min_heap_size: 1 << 18,
+            max_heap_size: 500 << 20,
+            use_huge_pages: false,
+            large_blocks_cache_size: 8 << 20,
+            poison_blocks: false,
+            quarantine_size: 0,
+            large_block_guard_page: GuardPage::None,
+            leak_sink: None,
+            #[cfg(feature = "site-tracking")]
+            site_sample_interval: 512 << 10,
+        }
+    }
+
+    #[test]
+    fn frees_segments_past_the_keep_limit() {
+        unsafe {
+            let mut manager = init(sys::new_env(), test_config()).unwrap();
+            let keep_count = test_config().min_heap_size / segment::SEGMENT_SIZE + 12;
+            let class_of_size = subheap::CLASS_COUNT - 1;
+            let size = subheap::SUBHEAP_SIZE_OF_CLASS[class_of_size];
+            let count = segment::BLOCK_COUNT_OF_CLASS[class_of_size] * keep_count * 4;
+
+            for _ in 0..2 {
+                let ptrs: Vec<AnyNonNullPtr> =
+                    (0..count).map(|_| manager.alloc(size).unwrap()).collect();
+                for ptr in ptrs {
+                    manager.free(ptr).unwrap();
+                }
+
+                let stats = manager.stats();
+                assert_eq!(stats.kept_segment_count, keep_count);
+                assert_eq!(
+                    stats.kept_segment_count + stats.free_segment_count,
+                    stats.allocated_segment_count
+                );
+            }
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn decommits_whole_huge_pages_of_free_segments() {
+        unsafe {
+            let mut env = sys::recording::SysMemEnvWithRecording::new(sys::new_env());
+            let huge_page_size = match env.get_huge_page_size().unwrap() {
+                Some(huge_page_size) => huge_page_size,
+                // THP is not available on this system.
+                None => return,
+            };
+            let config = Config {
+                use_huge_pages: true,
+                ..test_config()
+            };
+            let mut manager = init(env, config).unwrap();
+
+            let ptrs: Vec<AnyNonNullPtr> =
+                (0..5000).map(|_| manager.alloc(4 << 10).unwrap()).collect();
+            for ptr in ptrs {
+                manager.free(ptr).unwrap();
+            }
+
+            let decommit_lens: Vec<usize> = manager
+                .env()
+                .calls()
+                .iter()
+                .filter(|recorded| recorded.call.kind == sys::SysCallKind::SoftDecommit)
+                .map(|recorded| recorded.call.len)
+                .collect();
+            assert!(!decommit_lens.is_empty());
+            assert!(decommit_lens.iter().all(|&len| len == huge_page_size));
+
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn allocates_spans_on_freed_segments() {
+        unsafe {
+            let config = Config {
+                max_heap_size: 32 << 20,
+                ..test_config()
+            };
+            let mut manager = init(sys::new_env(), config).unwrap();
+
+            let mut ptrs = Vec::new();
+            while let Ok(ptr) = manager.alloc(4 << 10) {
+                ptrs.push(ptr);
+            }
+            for ptr in ptrs.drain(..) {
+                manager.free(ptr).unwrap();
+            }
+            let allocated_segment_count = manager.stats().allocated_segment_count;
+
+            while let Ok(ptr) = manager.alloc(300 << 10) {
+                ptrs.push(ptr);
+            }
+            let stats = manager.stats();
+            assert_eq!(stats.allocated_segment_count, allocated_segment_count);
+            assert!(stats.span_segment_count * 10 >= allocated_segment_count * 9);
+            assert!(manager.verify().is_ok());
+
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn reports_double_free_of_cached_aligned_large_block() {
+        unsafe {
+            let mut manager = init(sys::new_env(), test_config()).unwrap();
+            let ptr = manager.alloc_aligned(2 << 20, 1 << 16).unwrap();
+            manager.free(ptr).unwrap();
+            assert_eq!(manager.stats().large_block_count, 0);
+
+            let err = manager.free(ptr).unwrap_err();
+            assert!(err.to_string().contains("already freed"), "{}", err);
+
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn rounds_large_blocks_to_the_alignment_only() {
+        unsafe {
+            let mut manager = init(sys::new_env(), test_config()).unwrap();
+            let size = (2 << 20) + 8;
+            let ptr = manager.alloc(size).unwrap();
+
+            let mut block_sizes = Vec::new();
+            manager.for_each_live_block(|_, block_size, _| block_sizes.push(block_size));
+            assert_eq!(block_sizes, [size]);
+
+            manager.free(ptr).unwrap();
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn walks_all_live_blocks() {
+        unsafe {
+            let mut manager = init(sys::new_env(), test_config()).unwrap();
+            let mut expected_blocks = HashMap::new();
+            for (size, block_type) in [
+                (subheap::SUBHEAP_SIZE_OF_CLASS[0], block::Type::OnSubHeap),
+                (
+                    subheap::SUBHEAP_SIZE_OF_CLASS[subheap::CLASS_COUNT - 1],
+                    block::Type::OnSubHeap,
+                ),
+                (300 << 10, block::Type::OnSpan),
+                (2 << 20, block::Type::FreeSize),
+            ] {
+                for _ in 0..3 {
+                    let ptr = manager.alloc(size).unwrap();
+                    expected_blocks.insert(ptr.as_addr(), block_type);
+                }
+            }
+            let freed_ptr = manager.alloc(subheap::SUBHEAP_SIZE_OF_CLASS[0]).unwrap();
+            manager.free(freed_ptr).unwrap();
+
+            let mut live_blocks = HashMap::new();
+            manager.for_each_live_block(|block_ptr, _, block_type| {
+                live_blocks.insert(block_ptr.as_addr(), block_type);
+            });
+            assert_eq!(live_blocks, expected_blocks);
+
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn reports_writes_over_the_first_word_of_quarantined_blocks() {
+        unsafe {
+            let config = Config {
+                poison_blocks: true,
+                quarantine_size: 1 << 20,
+                ..test_config()
+            };
+            let mut manager = init(sys::new_env(), config).unwrap();
+            let mut ptr = manager.alloc(8).unwrap();
+            manager.free(ptr).unwrap();
+            *ptr.as_mut_ptr::<u8>() = 1;
+
+            let err = manager.flush_quarantine().unwrap_err();
+            assert!(err.is::<internal::error::WriteAfterFree>(), "{}", err);
+            assert_eq!(manager.stats().quarantined_block_count, 0);
+            assert!(manager.verify().is_ok());
+
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn releases_quarantined_blocks_once_the_ring_is_full() {
+        unsafe {
+            let config = Config {
+                quarantine_size: 1 << 30,
+                ..test_config()
+            };
+            let mut manager = init(sys::new_env(), config).unwrap();
+            for _ in 0..1000 {
+                let ptr = manager.alloc(8).unwrap();
+                manager.free(ptr).unwrap();
+            }
+            assert!(manager.stats().quarantined_block_count < 1000);
+
+            manager.flush_quarantine().unwrap();
+            assert_eq!(manager.stats().quarantined_block_count, 0);
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn faults_on_accesses_over_guarded_blocks() {
+        unsafe {
+            for guard_page in [GuardPage::Before, GuardPage::After] {
+                let config = Config {
+                    large_block_guard_page: guard_page,
+                    ..test_config()
+                };
+                let mut manager = init(sys::new_env(), config).unwrap();
+                for size in [(2 << 20) + 8, (2 << 20) + 4096 + 8, 3 << 20] {
+                    let ptr = manager.alloc(size).unwrap();
+                    assert!(util::bits::is_aligned(ptr.as_addr(), ALIGNMENT_SIZE));
+                    assert!(!sys::faults_on_write(ptr));
+                    assert!(!sys::faults_on_write(ptr.add(size - 1)));
+                    if guard_page == GuardPage::After {
+                        assert!(sys::faults_on_write(ptr.add(size)));
+                    } else {
+                        assert!(sys::faults_on_write(
+                            ptr.sub(size_of::<block::HeaderForFreeSize>() + 1)
+                        ));
+                    }
+                    manager.free(ptr).unwrap();
+                }
+                manager.release().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn faults_on_accesses_to_headers_of_quarantined_blocks() {
+        unsafe {
+            let config = Config {
+                quarantine_size: 16 << 20,
+                ..test_config()
+            };
+            let mut manager = init(sys::new_env(), config).unwrap();
+            let ptrs = [
+                manager.alloc((2 << 20) + 8).unwrap(),
+                manager.alloc_aligned((2 << 20) + 8, 1 << 16).unwrap(),
+            ];
+            for ptr in ptrs {
+                manager.free(ptr).unwrap();
+                assert!(sys::faults_on_write(ptr));
+                assert!(sys::faults_on_write(
+                    ptr.sub(size_of::<block::HeaderForFreeSize>())
+                ));
+            }
+
+            manager.flush_quarantine().unwrap();
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn poisons_quarantined_blocks_without_the_poisoning_mode() {
+        unsafe {
+            let config = Config {
+                poison_blocks: false,
+                quarantine_size: 1 << 20,
+                ..test_config()
+            };
+            let mut manager = init(sys::new_env(), config).unwrap();
+            let mut ptr = manager.alloc(8).unwrap();
+            manager.free(ptr).unwrap();
+            *ptr.as_mut_ptr::<u8>() = 1;
+
+            let err = manager.flush_quarantine().unwrap_err();
+            assert!(err.is::<internal::error::WriteAfterFree>(), "{}", err);
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn reports_stats_of_classes_and_large_blocks() {
+        unsafe {
+            let mut manager = init(sys::new_env(), test_config()).unwrap();
+            let class_of_size = 0;
+            let size = subheap::SUBHEAP_SIZE_OF_CLASS[class_of_size];
+            let ptrs = [manager.alloc(size).unwrap(), manager.alloc(size).unwrap()];
+            let large_ptr = manager.alloc(2 << 20).unwrap();
+
+            let stats = manager.stats();
+            let class_stats = stats.classes[class_of_size];
+            assert_eq!(class_stats.block_size, size);
+            assert_eq!(class_stats.used_block_count, 2);
+            assert_eq!(class_stats.segment_count, 1);
+            assert_eq!(class_stats.partial_segment_count, 1);
+            assert_eq!(stats.large_block_count, 1);
+            assert!(stats.large_block_mapped_size > 2 << 20);
+            assert_eq!(
+                stats.used_budget_size + stats.available_size,
+                test_config().max_heap_size
+            );
+            assert!(stats.reserved_size >= stats.used_budget_size);
+
+            for ptr in ptrs.into_iter().chain([large_ptr]) {
+                manager.free(ptr).unwrap();
+            }
+            let stats = manager.stats();
+            assert_eq!(stats.classes[class_of_size].used_block_count, 0);
+            assert_eq!(stats.large_block_count, 0);
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn keeps_the_heap_consistent_after_aligned_allocations() {
+        unsafe {
+            let mut manager = init(sys::new_env(), test_config()).unwrap();
+            let ptr = manager.alloc(1 << 18).unwrap();
+            let aligned_ptr = manager.alloc_aligned(1 << 20, 1 << 16).unwrap();
+            assert!(util::bits::is_aligned(aligned_ptr.as_addr(), 1 << 16));
+            manager.free(aligned_ptr).unwrap();
+            manager.free(ptr).unwrap();
+
+            let report = manager.verify();
+            assert!(report.is_ok(), "{:?}", report);
+
+            let dump = manager.dump();
+            let mut dump_bytes = Vec::new();
+            dump.write_to(&mut dump_bytes).unwrap();
+            assert_eq!(
+                internal::layout::arena::dump::HeapDump::read_from(&mut dump_bytes.as_slice())
+                    .unwrap(),
+                dump
+            );
+
+            let leak_check = manager.check_leaks();
+            assert!(leak_check.is_empty(), "{}", leak_check);
+            manager.release().unwrap();
+        }
+    }
+
+    #[test]
+    fn checks_live_blocks_as_leaks() {
+        unsafe {
+            let mut manager = init(sys::new_env(), test_config()).unwrap();
+            let ptrs = [manager.alloc(24).unwrap(), manager.alloc(2 << 20).unwrap()];
+
+            let leak_check = manager.check_leaks();
+            assert_eq!(leak_check.leaks.total().count, 2);
+            assert_eq!(leak_check.leaks.large_blocks.size, 2 << 20);
+            assert!(leak_check.to_string().starts_with("Leaked 2 blocks"));
+
+            for ptr in ptrs {
+                manager.free(ptr).unwrap();
+            }
+            assert!(manager.check_leaks().is_empty());
+            manager.release().unwrap();
+        }
+    }
+
+    static SUNK_LEAK_COUNTS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    fn sink_leaks(leak_check: &LeakCheck) {
+        SUNK_LEAK_COUNTS
+            .lock()
+            .unwrap()
+            .push(leak_check.leaks.total().count);
+    }
+
+    #[test]
+    fn passes_leaks_to_the_sink_on_drop_and_release() {
+        unsafe {
+            let config = || Config {
+                leak_sink: Some(sink_leaks),
+                ..test_config()
+            };
+            let mut manager = init(sys::new_env(), config()).unwrap();
+            manager.alloc(24).unwrap();
+            manager.release().unwrap();
+
+            let mut manager = init(sys::new_env(), config()).unwrap();
+            let ptr = manager.alloc(24).unwrap();
+            manager.free(ptr).unwrap();
+            manager.release().unwrap();
+
+            let mut manager = init(sys::new_env(), config()).unwrap();
+            manager.alloc(24).unwrap();
+            manager.alloc(48).unwrap();
+            drop(manager);
+
+            // Not called without leaks, and only once on releasing.
+            assert_eq!(*SUNK_LEAK_COUNTS.lock().unwrap(), [1, 2]);
+        }
     }
 }
@@ -1,13 +1,21 @@
 use std::error::Error;
+use std::ptr;
 use std::result::Result;
+use std::slice;
 
+use crate::internal::error;
 use crate::internal::layout::arena;
 use crate::internal::layout::block;
+use crate::internal::layout::constants::ALIGNMENT_SIZE;
 use crate::internal::layout::segment;
 use crate::internal::layout::subheap;
 use crate::sys::ptr::AnyNonNullPtr;
 use crate::sys::SysMemEnv;
 
+// Free blocks on subheaps are filled with it in the poisoning mode, and checked on reuse.
+const FREE_POISON: u8 = 0xde;
+const ALLOC_POISON: u8 = 0xab;
+
 #[derive(Debug)]
 pub struct SampleAlloc {
     arena: arena::Arena,
@@ -22,6 +30,48 @@ impl SampleAlloc {
         Ok(Self { arena })
     }
 
+    pub unsafe fn open<Env: SysMemEnv>(
+        env: &mut Env,
+        context_space: AnyNonNullPtr,
+    ) -> Result<Self, Box<dyn Error>> {
+        let arena = arena::Arena::open(env, context_space)?;
+        Ok(Self { arena })
+    }
+
+    pub unsafe fn release_with_env<Env: SysMemEnv>(
+        self,
+        env: &mut Env,
+    ) -> Result<(), Box<dyn Error>> {
+        self.arena.release(env)
+    }
+
+    pub unsafe fn stats(&self) -> arena::Stats {
+        self.arena.stats()
+    }
+
+    pub unsafe fn for_each_live_block<F>(&self, f: F)
+    where
+        F: FnMut(AnyNonNullPtr, usize, block::Type),
+    {
+        self.arena.for_each_live_block(f)
+    }
+
+    pub unsafe fn verify(&self) -> arena::verifier::Report {
+        self.arena.verify()
+    }
+
+    pub unsafe fn dump(&self) -> arena::dump::HeapDump {
+        self.arena.dump()
+    }
+
+    pub unsafe fn fragmentation_report(&self) -> arena::fragmentation::FragmentationReport {
+        self.arena.fragmentation_report()
+    }
+
+    pub unsafe fn leak_report(&self) -> arena::leaks::LeakReport {
+        self.arena.leak_report()
+    }
+
     fn heap_overflow(&mut self) -> Box<dyn Error> {
         Box::new(std::io::Error::new(
             std::io::ErrorKind::OutOfMemory,
@@ -35,6 +85,12 @@ impl SampleAlloc {
         size: usize,
     ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
         match subheap::class_of_size(size) {
+            None if size <= segment::MAX_SPAN_BLOCK_SIZE => {
+                match self.arena.alloc_block_of_span(env, size)? {
+                    Some(block_ptr) => Ok(block_ptr),
+                    None => Err(self.heap_overflow())?,
+                }
+            }
             None => match self.arena.alloc_block_of_free_size(env, size)? {
                 Some(block_ptr) => Ok(block_ptr),
                 None => Err(self.heap_overflow())?,
@@ -43,28 +99,116 @@ impl SampleAlloc {
         }
     }
 
+    pub unsafe fn alloc_aligned_with_env<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        size: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        if alignment_size <= ALIGNMENT_SIZE {
+            return self.alloc_with_env(env, size);
+        }
+
+        match self
+            .arena
+            .alloc_block_of_free_size_aligned(env, size, alignment_size)?
+        {
+            Some(block_ptr) => Ok(block_ptr),
+            None => Err(self.heap_overflow())?,
+        }
+    }
+
+    // On failure, large blocks stay allocated, while blocks on segments are freed anyway.
     pub unsafe fn free_with_env<Env: SysMemEnv>(
         &mut self,
         env: &mut Env,
         ptr: AnyNonNullPtr,
     ) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "debug-checks")]
+        self.arena.check_block_to_free(ptr)?;
+
+        let quarantines_blocks = self.arena.quarantines_blocks();
         match self.arena.block_type(ptr) {
-            block::Type::FreeSize => self.arena.free_block_of_free_size(env, ptr),
+            block::Type::FreeSize if quarantines_blocks => {
+                self.arena.quarantine_block_of_free_size(env, ptr)?
+            }
+            block::Type::FreeSize => return self.arena.free_block_of_free_size(env, ptr),
+            block::Type::OnSpan => return self.arena.free_block_of_span(env, ptr),
             block::Type::OnSubHeap => {
-                let (mut seg, block_index) = self.arena.segment_with_block_index(ptr);
-                let cls = seg.subheap_class();
-                if seg.free_block_and_check_empty(block_index) {
-                    self.arena.remove_segment_from_subheap(cls, &mut seg);
-                    self.arena.free_unused_segment(env, &mut seg)?;
-                } else if seg.is_floated() {
-                    self.arena.insert_free_segment_to_subheap(cls, &mut seg);
+                let (seg, block_index) = self.arena.segment_with_block_index(ptr);
+                let mut block_ptr = seg.block_ptr(block_index);
+                if self.arena.poisons_blocks() {
+                    ptr::write_bytes(block_ptr.as_mut_ptr::<u8>(), FREE_POISON, seg.block_size());
                 }
-                Ok(())
+                if !quarantines_blocks {
+                    return free_on_subheap_with_env(self, env, seg, block_index);
+                }
+                self.arena
+                    .quarantine_block_on_subheap(block_ptr, seg.block_size());
             }
         }
+
+        self.release_quarantined_blocks(env, false)
+    }
+
+    // Releases all blocks in the quarantine, e.g. before checking leaks.
+    pub unsafe fn flush_quarantine_with_env<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+    ) -> Result<(), Box<dyn Error>> {
+        self.release_quarantined_blocks(env, true)
+    }
+
+    // All popped blocks are released even if some of them were written after free.
+    unsafe fn release_quarantined_blocks<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        flushes: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        while let Some(mut block_ptr) = self.arena.pop_quarantined_block(env, flushes)? {
+            let (seg, block_index) = self.arena.segment_with_block_index(block_ptr);
+            if self.arena.poisons_blocks() {
+                let block =
+                    slice::from_raw_parts_mut(block_ptr.as_mut_ptr::<u8>(), seg.block_size());
+                if result.is_ok() && block.iter().any(|byte| *byte != FREE_POISON) {
+                    result = Err(Box::new(error::WriteAfterFree {
+                        addr: block_ptr.as_addr(),
+                        segment_index: seg.index.get(),
+                        class_of_size: seg.subheap_class(),
+                        block_index,
+                    }) as Box<dyn Error>);
+                }
+                block.fill(FREE_POISON);
+            }
+            free_on_subheap_with_env(self, env, seg, block_index)?;
+        }
+        result
     }
 }
 
+unsafe fn free_on_subheap_with_env<Env: SysMemEnv>(
+    manager: &mut SampleAlloc,
+    env: &mut Env,
+    mut seg: segment::Segment,
+    block_index: usize,
+) -> Result<(), Box<dyn Error>> {
+    let cls = seg.subheap_class();
+    manager.arena.subheap(cls).used_block_count -= 1;
+    // A full segment is out of the subheap.
+    let is_in_subheap = manager.arena.is_segment_in_subheap(cls, &seg);
+    if seg.free_block_and_check_empty(block_index) {
+        manager.arena.subheap(cls).segment_count -= 1;
+        if is_in_subheap {
+            manager.arena.remove_segment_from_subheap(cls, &mut seg);
+        }
+        manager.arena.free_unused_segment(env, &mut seg)?;
+    } else if !is_in_subheap {
+        manager.arena.insert_free_segment_to_subheap(cls, &mut seg);
+    }
+    Ok(())
+}
+
 unsafe fn alloc_on_subheap_with_env<Env: SysMemEnv>(
     manager: &mut SampleAlloc,
     env: &mut Env,
@@ -72,7 +216,7 @@ unsafe fn alloc_on_subheap_with_env<Env: SysMemEnv>(
 ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
     let (mut seg, block_index) = match manager.arena.subheap(class_of_size).next_free_segment() {
         Some(next_seg_ptr) => {
-            let mut seg = manager.arena.segment(next_seg_ptr);
+            let seg = manager.arena.segment(next_seg_ptr);
             let block_index = match seg.find_free_block() {
                 Some(index) => index,
                 None => panic!("unreachable: subheap free segments have free blocks."),
@@ -82,6 +226,8 @@ unsafe fn alloc_on_subheap_with_env<Env: SysMemEnv>(
         None => match manager.arena.pop_free_segment(env)? {
             Some(mut free_seg) => {
                 segment::Segment::init_single(&mut free_seg, class_of_size);
+                poison_free_blocks(manager, &free_seg);
+                manager.arena.subheap(class_of_size).segment_count += 1;
                 manager
                     .arena
                     .insert_free_segment_to_subheap(class_of_size, &mut free_seg);
@@ -93,6 +239,8 @@ unsafe fn alloc_on_subheap_with_env<Env: SysMemEnv>(
                     None => Err(manager.heap_overflow())?,
                 };
                 segment::Segment::init_single(&mut free_seg, class_of_size);
+                poison_free_blocks(manager, &free_seg);
+                manager.arena.subheap(class_of_size).segment_count += 1;
                 manager
                     .arena
                     .insert_free_segment_to_subheap(class_of_size, &mut free_seg);
@@ -100,10 +248,437 @@ unsafe fn alloc_on_subheap_with_env<Env: SysMemEnv>(
             }
         },
     };
+    manager.arena.subheap(class_of_size).used_block_count += 1;
     if seg.mark_block_and_check_full(block_index) {
         manager
             .arena
             .remove_segment_from_subheap(class_of_size, &mut seg);
     }
-    Ok(seg.block_ptr(block_index))
+
+    let mut block_ptr = seg.block_ptr(block_index);
+    if manager.arena.poisons_blocks() {
+        let block = slice::from_raw_parts_mut(block_ptr.as_mut_ptr::<u8>(), seg.block_size());
+        // The broken block is left used, not to be handed out again. It is kept until the arena is
+        // released, and counted in leak reports.
+        if block.iter().any(|byte| *byte != FREE_POISON) {
+            return Err(Box::new(error::WriteAfterFree {
+                addr: block_ptr.as_addr(),
+                segment_index: seg.index.get(),
+                class_of_size,
+                block_index,
+            }));
+        }
+        block.fill(ALLOC_POISON);
+    }
+    Ok(block_ptr)
+}
+
+// All blocks of a new segment are poisoned at once, so that every free block has the pattern.
+unsafe fn poison_free_blocks(manager: &SampleAlloc, seg: &segment::Segment) {
+    if !manager.arena.poisons_blocks() {
+        return;
+    }
+
+    let mut blocks_ptr = seg.block_ptr(0);
+    let blocks_size = segment::BLOCK_COUNT_OF_CLASS[seg.subheap_class()] * seg.block_size();
+    ptr::write_bytes(blocks_ptr.as_mut_ptr::<u8>(), FREE_POISON, blocks_size);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "debug-checks")]
+    use std::ptr::NonNull;
+
+    use crate::sys;
+    use crate::sys::fault_injecting::InjectedFault;
+    use crate::sys::fault_injecting::SysMemEnvWithFaults;
+    use crate::sys::recording::SysMemEnvWithRecording;
+    use crate::sys::SysCallKind;
+
+    type RecordingEnv = SysMemEnvWithRecording<sys::SysMemEnvImpl>;
+    type FaultyEnv = SysMemEnvWithFaults<RecordingEnv>;
+
+    fn test_arena_config() -> arena::Config {
+        arena::Config {
+            min_heap_size: 1 << 18,
+            max_heap_size: 64 << 20,
+            keep_segments_count: 16,
+            use_huge_pages: false,
+            large_blocks_cache_size: 0,
+            poisons_blocks: false,
+            quarantine_size: 0,
+            large_block_guard_page: arena::GuardPage::None,
+        }
+    }
+
+    unsafe fn init_with_recording(config: arena::Config) -> (RecordingEnv, SampleAlloc) {
+        let mut env = SysMemEnvWithRecording::new(sys::new_env());
+        let manager = SampleAlloc::init(&mut env, config).unwrap();
+        (env, manager)
+    }
+
+    // Sizes which are not multiples of pages, on subheaps, spans and free size blocks.
+    const SIZES: [usize; 6] = [
+        24,
+        4 << 10,
+        300 << 10,
+        (2 << 20) + 8,
+        (3 << 20) + 4096 + 8,
+        (5 << 20) - 8,
+    ];
+
+    unsafe fn alloc_and_free_all(env: &mut RecordingEnv, manager: &mut SampleAlloc) {
+        for size in SIZES {
+            let ptr = manager.alloc_with_env(env, size).unwrap();
+            manager.free_with_env(env, ptr).unwrap();
+        }
+        for alignment_size in [1 << 12, 1 << 16, 1 << 22] {
+            let ptr = manager
+                .alloc_aligned_with_env(env, (2 << 20) + 8, alignment_size)
+                .unwrap();
+            assert_eq!(ptr.as_addr() % alignment_size, 0);
+            manager.free_with_env(env, ptr).unwrap();
+        }
+    }
+
+    #[test]
+    fn restores_the_budget_on_free() {
+        unsafe {
+            let (mut env, mut manager) = init_with_recording(test_arena_config());
+            // The first large block commits a page of the table of large blocks, which is kept.
+            let ptr = manager.alloc_with_env(&mut env, SIZES[3]).unwrap();
+            manager.free_with_env(&mut env, ptr).unwrap();
+
+            for size in SIZES.into_iter().skip(3) {
+                let available_size = manager.stats().available_size;
+                let mapped_size = env.mapped_size();
+                let ptr = manager.alloc_with_env(&mut env, size).unwrap();
+                assert!(manager.stats().available_size < available_size);
+
+                manager.free_with_env(&mut env, ptr).unwrap();
+                assert_eq!(manager.stats().available_size, available_size);
+                assert_eq!(env.mapped_size(), mapped_size);
+            }
+
+            manager.release_with_env(&mut env).unwrap();
+        }
+    }
+
+    #[test]
+    fn reuses_soft_decommitted_segments_without_commits() {
+        unsafe {
+            let (mut env, mut manager) = init_with_recording(test_arena_config());
+            let class_of_size = subheap::CLASS_COUNT - 1;
+            let size = subheap::SUBHEAP_SIZE_OF_CLASS[class_of_size];
+            // Over the kept segments, and within the max heap size of the largest segment size.
+            let count = segment::BLOCK_COUNT_OF_CLASS[class_of_size] * 32;
+
+            let ptrs: Vec<AnyNonNullPtr> = (0..count)
+                .map(|_| manager.alloc_with_env(&mut env, size).unwrap())
+                .collect();
+            for ptr in ptrs {
+                manager.free_with_env(&mut env, ptr).unwrap();
+            }
+            let allocated_segment_count = manager.stats().allocated_segment_count;
+            assert!(manager.stats().free_segment_count > 0);
+
+            env.clear_calls();
+            let ptrs: Vec<AnyNonNullPtr> = (0..count)
+                .map(|_| manager.alloc_with_env(&mut env, size).unwrap())
+                .collect();
+            assert_eq!(
+                manager.stats().allocated_segment_count,
+                allocated_segment_count
+            );
+            let kinds: Vec<SysCallKind> = env
+                .calls()
+                .iter()
+                .map(|recorded| recorded.call.kind)
+                .collect();
+            assert!(kinds.contains(&SysCallKind::Recommit));
+            assert!(!kinds.contains(&SysCallKind::Commit));
+
+            for ptr in ptrs {
+                manager.free_with_env(&mut env, ptr).unwrap();
+            }
+            manager.release_with_env(&mut env).unwrap();
+        }
+    }
+
+    #[test]
+    fn releases_every_mapped_byte() {
+        unsafe {
+            let (mut env, mut manager) = init_with_recording(test_arena_config());
+            alloc_and_free_all(&mut env, &mut manager);
+            manager.release_with_env(&mut env).unwrap();
+
+            assert_eq!(env.mapped_size(), 0);
+            assert!(env.calls().iter().all(|recorded| recorded.succeeded));
+        }
+    }
+
+    #[test]
+    fn releases_every_mapped_byte_of_live_blocks() {
+        unsafe {
+            let (mut env, mut manager) = init_with_recording(test_arena_config());
+            for size in SIZES {
+                manager.alloc_with_env(&mut env, size).unwrap();
+            }
+            manager.release_with_env(&mut env).unwrap();
+
+            assert_eq!(env.mapped_size(), 0);
+        }
+    }
+
+    #[test]
+    fn releases_every_mapped_byte_with_the_cache() {
+        unsafe {
+            let config = arena::Config {
+                large_blocks_cache_size: 8 << 20,
+                ..test_arena_config()
+            };
+            let (mut env, mut manager) = init_with_recording(config);
+            alloc_and_free_all(&mut env, &mut manager);
+            alloc_and_free_all(&mut env, &mut manager);
+            manager.release_with_env(&mut env).unwrap();
+
+            assert_eq!(env.mapped_size(), 0);
+        }
+    }
+
+    #[test]
+    fn releases_every_mapped_byte_with_guard_pages() {
+        unsafe {
+            for guard_page in [arena::GuardPage::Before, arena::GuardPage::After] {
+                let config = arena::Config {
+                    large_block_guard_page: guard_page,
+                    ..test_arena_config()
+                };
+                let (mut env, mut manager) = init_with_recording(config);
+                alloc_and_free_all(&mut env, &mut manager);
+                manager.release_with_env(&mut env).unwrap();
+
+                assert_eq!(env.mapped_size(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn releases_every_mapped_byte_with_the_quarantine() {
+        unsafe {
+            let config = arena::Config {
+                quarantine_size: 4 << 20,
+                ..test_arena_config()
+            };
+            let (mut env, mut manager) = init_with_recording(config);
+            alloc_and_free_all(&mut env, &mut manager);
+            manager.release_with_env(&mut env).unwrap();
+
+            assert_eq!(env.mapped_size(), 0);
+        }
+    }
+
+    #[test]
+    fn keeps_a_block_written_after_free_out_of_allocations() {
+        unsafe {
+            let config = arena::Config {
+                poisons_blocks: true,
+                ..test_arena_config()
+            };
+            let (mut env, mut manager) = init_with_recording(config);
+            // The live block keeps the segment, and the freed one is the first to be reused.
+            let mut ptr = manager.alloc_with_env(&mut env, SIZES[0]).unwrap();
+            let live_ptr = manager.alloc_with_env(&mut env, SIZES[0]).unwrap();
+            manager.free_with_env(&mut env, ptr).unwrap();
+            *ptr.as_mut_ptr::<u8>() = 0;
+
+            let err = manager.alloc_with_env(&mut env, SIZES[0]).unwrap_err();
+            assert_eq!(
+                err.downcast::<error::WriteAfterFree>().unwrap().addr,
+                ptr.as_addr()
+            );
+            let other_ptr = manager.alloc_with_env(&mut env, SIZES[0]).unwrap();
+            assert_ne!(other_ptr, ptr);
+            manager.free_with_env(&mut env, other_ptr).unwrap();
+            manager.free_with_env(&mut env, live_ptr).unwrap();
+            let class_of_size = subheap::class_of_size(SIZES[0]).unwrap();
+            assert_eq!(manager.leak_report().classes[class_of_size].count, 1);
+            assert!(manager.verify().is_ok());
+            manager.release_with_env(&mut env).unwrap();
+            assert_eq!(env.mapped_size(), 0);
+        }
+    }
+
+    #[cfg(feature = "debug-checks")]
+    unsafe fn reason_of_invalid_free(
+        env: &mut RecordingEnv,
+        manager: &mut SampleAlloc,
+        ptr: AnyNonNullPtr,
+    ) -> &'static str {
+        let err = manager.free_with_env(env, ptr).unwrap_err();
+        err.downcast::<error::InvalidPointer>().unwrap().reason
+    }
+
+    #[cfg(feature = "debug-checks")]
+    #[test]
+    fn rejects_invalid_frees() {
+        unsafe {
+            let (mut env, mut manager) = init_with_recording(test_arena_config());
+            let ptr_on_subheap = manager.alloc_with_env(&mut env, SIZES[0]).unwrap();
+            let ptr_on_span = manager.alloc_with_env(&mut env, SIZES[2]).unwrap();
+
+            let misaligned_ptr = ptr_on_subheap.wrapping_add(1);
+            let reason = reason_of_invalid_free(&mut env, &mut manager, misaligned_ptr);
+            assert_eq!(reason, "not at a block boundary");
+
+            let inner_span_ptr = ptr_on_span.wrapping_add(ALIGNMENT_SIZE);
+            let reason = reason_of_invalid_free(&mut env, &mut manager, inner_span_ptr);
+            assert_eq!(reason, "not at the begin of a span");
+
+            // Beyond the allocated segments, and out of the segment space.
+            let (seg, _) = manager.arena.segment_with_block_index(ptr_on_subheap);
+            let unallocated_segment_count =
+                manager.stats().allocated_segment_count - seg.index.get();
+            let unallocated_ptr =
+                ptr_on_subheap.wrapping_add(unallocated_segment_count * segment::SEGMENT_SIZE);
+            let reason = reason_of_invalid_free(&mut env, &mut manager, unallocated_ptr);
+            assert_eq!(reason, "not allocated by the arena");
+            let mut foreign_block = [0u64; 4];
+            let foreign_ptr = AnyNonNullPtr::new(NonNull::from(&mut foreign_block[0]));
+            let reason = reason_of_invalid_free(&mut env, &mut manager, foreign_ptr);
+            assert_eq!(reason, "not allocated by the arena");
+
+            for ptr in [ptr_on_subheap, ptr_on_span] {
+                manager.free_with_env(&mut env, ptr).unwrap();
+                let reason = reason_of_invalid_free(&mut env, &mut manager, ptr);
+                assert_eq!(reason, "already freed");
+            }
+
+            assert!(manager.verify().is_ok());
+            manager.release_with_env(&mut env).unwrap();
+            assert_eq!(env.mapped_size(), 0);
+        }
+    }
+
+    // Allocate and free blocks of every kind, and returns whether all calls succeeded.
+    unsafe fn alloc_and_free_all_with_faults(
+        env: &mut FaultyEnv,
+        manager: &mut SampleAlloc,
+    ) -> bool {
+        let mut ptrs = Vec::new();
+        let mut succeeded = true;
+        for size in SIZES {
+            match manager.alloc_with_env(env, size) {
+                Ok(ptr) => ptrs.push(ptr),
+                Err(_) => succeeded = false,
+            }
+        }
+        for alignment_size in [1 << 12, 1 << 16, 1 << 22] {
+            match manager.alloc_aligned_with_env(env, (2 << 20) + 8, alignment_size) {
+                Ok(ptr) => ptrs.push(ptr),
+                Err(_) => succeeded = false,
+            }
+        }
+        for ptr in ptrs {
+            succeeded &= manager.free_with_env(env, ptr).is_ok();
+        }
+        succeeded
+    }
+
+    #[test]
+    fn fails_to_init_without_leaking() {
+        unsafe {
+            for call_index in 0.. {
+                let mut env = SysMemEnvWithFaults::new(SysMemEnvWithRecording::new(sys::new_env()));
+                env.fail_at_call(call_index);
+                match SampleAlloc::init(&mut env, test_arena_config()) {
+                    Ok(manager) => {
+                        assert!(env.call_count() <= call_index);
+                        env.clear_faults();
+                        manager.release_with_env(&mut env).unwrap();
+                        break;
+                    }
+                    Err(err) => assert!(err.is::<InjectedFault>()),
+                }
+                assert_eq!(env.inner().mapped_size(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn recovers_from_a_fault_on_each_call() {
+        unsafe {
+            for (large_blocks_cache_size, large_block_guard_page, quarantine_size) in [
+                (0, arena::GuardPage::None, 0),
+                (8 << 20, arena::GuardPage::After, 0),
+                (0, arena::GuardPage::Before, 4 << 20),
+            ] {
+                for offset in 0.. {
+                    let mut env =
+                        SysMemEnvWithFaults::new(SysMemEnvWithRecording::new(sys::new_env()));
+                    let config = arena::Config {
+                        large_blocks_cache_size,
+                        large_block_guard_page,
+                        quarantine_size,
+                        poisons_blocks: quarantine_size > 0,
+                        ..test_arena_config()
+                    };
+                    let mut manager = SampleAlloc::init(&mut env, config).unwrap();
+                    // Warm up lists and the cache, to fail on the paths reusing them.
+                    assert!(alloc_and_free_all_with_faults(&mut env, &mut manager));
+
+                    let call_index = env.call_count() + offset;
+                    env.fail_at_call(call_index);
+                    alloc_and_free_all_with_faults(&mut env, &mut manager);
+                    let faulted = env.call_count() > call_index;
+                    env.clear_faults();
+
+                    // Blocks left by failed frees are released with the arena.
+                    assert!(manager.verify().is_ok(), "faulted at #{}", call_index);
+                    assert!(alloc_and_free_all_with_faults(&mut env, &mut manager));
+                    manager.release_with_env(&mut env).unwrap();
+                    assert_eq!(env.inner().mapped_size(), 0, "faulted at #{}", call_index);
+
+                    if !faulted {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn recovers_from_faults_on_each_kind_of_calls() {
+        unsafe {
+            // Releases are not failed every time, since trimmed spaces cannot be unmapped then.
+            for kind in [
+                SysCallKind::Alloc,
+                SysCallKind::Commit,
+                SysCallKind::SoftDecommit,
+                SysCallKind::HardDecommit,
+                SysCallKind::Recommit,
+            ] {
+                let mut env = SysMemEnvWithFaults::new(SysMemEnvWithRecording::new(sys::new_env()));
+                let config = arena::Config {
+                    large_blocks_cache_size: 8 << 20,
+                    large_block_guard_page: arena::GuardPage::After,
+                    ..test_arena_config()
+                };
+                let mut manager = SampleAlloc::init(&mut env, config).unwrap();
+
+                // Run twice to fail on both new and reused spaces.
+                env.fail_calls_matching(move |call| call.kind == kind);
+                let succeeded = alloc_and_free_all_with_faults(&mut env, &mut manager)
+                    & alloc_and_free_all_with_faults(&mut env, &mut manager);
+                assert!(!succeeded, "no fault on {:?}", kind);
+                env.clear_faults();
+
+                assert!(manager.verify().is_ok(), "faulted on {:?}", kind);
+                assert!(alloc_and_free_all_with_faults(&mut env, &mut manager));
+                manager.release_with_env(&mut env).unwrap();
+                assert_eq!(env.inner().mapped_size(), 0, "faulted on {:?}", kind);
+            }
+        }
+    }
 }
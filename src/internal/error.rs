@@ -0,0 +1,53 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct InvalidPointer {
+    pub addr: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidPointer {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "Invalid pointer {:#x}: {}",
+            self.addr, self.reason
+        )
+    }
+}
+
+impl Error for InvalidPointer {}
+
+#[derive(Debug)]
+pub struct InvalidArena {
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidArena {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "Invalid arena: {}", self.reason)
+    }
+}
+
+impl Error for InvalidArena {}
+
+#[derive(Debug)]
+pub struct WriteAfterFree {
+    pub addr: usize,
+    pub segment_index: usize,
+    pub class_of_size: usize,
+    pub block_index: usize,
+}
+
+impl fmt::Display for WriteAfterFree {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "Write after free to the block {:#x}: segment {}, class {}, block {}",
+            self.addr, self.segment_index, self.class_of_size, self.block_index
+        )
+    }
+}
+
+impl Error for WriteAfterFree {}
@@ -0,0 +1,522 @@
+use std::error::Error;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::result::Result;
+
+use crate::internal::layout::arena::context_space_by_header;
+use crate::internal::layout::arena::verifier;
+use crate::internal::layout::arena::verifier::SegmentList;
+use crate::internal::layout::arena::Header;
+use crate::internal::layout::arena::BLOCK_FREE_SIZE_HEADER_SIZE;
+use crate::internal::layout::block;
+use crate::internal::layout::segment;
+use crate::internal::layout::subheap;
+use crate::sys::CommitState;
+
+const DUMP_MAGIC: u64 = u64::from_le_bytes(*b"SAMPLEHD");
+// Bump it on changing the format.
+const DUMP_VERSION: u32 = 1;
+
+const LIST_TAG_NONE: u8 = 0;
+const LIST_TAG_SUBHEAP: u8 = 1;
+const LIST_TAG_KEPT: u8 = 2;
+const LIST_TAG_FREE: u8 = 3;
+const LIST_TAG_FREE_SPANS: u8 = 4;
+
+// A snapshot of the heap layout, which is written in a compact binary format for offline analysis.
+// All integers are little-endian, and sizes and addresses are 64-bit regardless of the platform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapDump {
+    pub segment_size: usize,
+    pub max_heap_size: usize,
+    pub context_space_size: usize,
+    pub reserved_segment_space_size: usize,
+    pub keep_count: usize,
+    pub uses_huge_pages: bool,
+    pub segment_space_begin: usize,
+    pub block_size_of_class: Vec<usize>,
+    pub allocated_segment_count: usize,
+    pub segments: Vec<SegmentDump>,
+    pub large_blocks: Vec<LargeBlockDump>,
+}
+
+// The following segments of spans have no record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentDump {
+    pub index: usize,
+    pub list: Option<SegmentList>,
+    pub kind: SegmentKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SegmentKind {
+    Free {
+        commit_state: CommitState,
+    },
+    Kept,
+    FreeSpan {
+        segment_count: usize,
+        commit_state: CommitState,
+    },
+    // A bit per block, which is set for used blocks.
+    SubHeap {
+        class_of_size: usize,
+        used_block_count: usize,
+        block_count: usize,
+        bitmap: Vec<u8>,
+    },
+    Span {
+        segment_count: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LargeBlockDump {
+    pub addr: usize,
+    pub block_size: usize,
+    pub mapped_size: usize,
+    pub flags: usize,
+}
+
+pub unsafe fn capture(header: &Header) -> HeapDump {
+    let segment_space = &header.segment_space;
+    let segment_lists = verifier::segment_lists(header);
+
+    let mut segments = Vec::new();
+    let mut index = 0;
+    while index < segment_space.allocated_segment_count() {
+        let seg = segment_space.segment_by_index(segment::SegmentIndex::new(index));
+        let kind = match seg.status() {
+            segment::Status::Free => SegmentKind::Free {
+                commit_state: seg.commit_state(),
+            },
+            segment::Status::Kept => SegmentKind::Kept,
+            segment::Status::FreeSpan => {
+                let (segment_count, commit_state) = seg.free_span();
+                SegmentKind::FreeSpan {
+                    segment_count,
+                    commit_state,
+                }
+            }
+            segment::Status::SubHeap => {
+                let class_of_size = seg.subheap_class();
+                let block_count = segment::BLOCK_COUNT_OF_CLASS[class_of_size];
+                let mut bitmap = vec![0; block_count.div_ceil(u8::BITS as usize)];
+                seg.for_each_used_block(|block_index| {
+                    bitmap[block_index / u8::BITS as usize] |=
+                        1 << (block_index % u8::BITS as usize)
+                });
+                SegmentKind::SubHeap {
+                    class_of_size,
+                    used_block_count: seg.used_block_count(),
+                    block_count,
+                    bitmap,
+                }
+            }
+            segment::Status::Span => SegmentKind::Span {
+                segment_count: seg.span_segment_count(),
+            },
+            segment::Status::InSpan => {
+                index += 1;
+                continue;
+            }
+        };
+        let segment_count = match kind {
+            SegmentKind::FreeSpan { segment_count, .. } | SegmentKind::Span { segment_count } => {
+                segment_count
+            }
+            _ => 1,
+        };
+
+        segments.push(SegmentDump {
+            index,
+            list: segment_lists[index],
+            kind,
+        });
+        index += segment_count.max(1);
+    }
+
+    let mut large_blocks = Vec::with_capacity(header.large_blocks.count());
+    header
+        .large_blocks
+        .for_each(context_space_by_header(header), |block_ptr, mapped_size| {
+            let block_header: &block::HeaderForFreeSize = block_ptr.as_ref();
+            large_blocks.push(LargeBlockDump {
+                addr: block_ptr.add(BLOCK_FREE_SIZE_HEADER_SIZE).as_addr(),
+                block_size: block_header.block_size(),
+                mapped_size,
+                flags: block_header.flags(),
+            });
+        });
+
+    HeapDump {
+        segment_size: header.segment_size,
+        max_heap_size: header.max_heap_size,
+        context_space_size: header.context_space_size,
+        reserved_segment_space_size: header.reserved_segment_space_size,
+        keep_count: header.keep_segments.keep_count(),
+        uses_huge_pages: segment_space.uses_huge_pages(),
+        segment_space_begin: segment_space.space_begin().as_addr(),
+        block_size_of_class: subheap::SUBHEAP_SIZE_OF_CLASS.to_vec(),
+        allocated_segment_count: segment_space.allocated_segment_count(),
+        segments,
+        large_blocks,
+    }
+}
+
+// Dumps are written out by embedders, e.g. to inspect the heap offline.
+#[allow(unused)]
+impl HeapDump {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        write_u64(writer, DUMP_MAGIC)?;
+        write_u32(writer, DUMP_VERSION)?;
+
+        write_usize(writer, self.segment_size)?;
+        write_usize(writer, self.max_heap_size)?;
+        write_usize(writer, self.context_space_size)?;
+        write_usize(writer, self.reserved_segment_space_size)?;
+        write_usize(writer, self.keep_count)?;
+        write_u8(writer, self.uses_huge_pages as u8)?;
+        write_usize(writer, self.segment_space_begin)?;
+        write_usize(writer, self.block_size_of_class.len())?;
+        for block_size in &self.block_size_of_class {
+            write_usize(writer, *block_size)?;
+        }
+
+        write_usize(writer, self.allocated_segment_count)?;
+        write_usize(writer, self.segments.len())?;
+        for seg in &self.segments {
+            write_segment(writer, seg)?;
+        }
+
+        write_usize(writer, self.large_blocks.len())?;
+        for large_block in &self.large_blocks {
+            write_usize(writer, large_block.addr)?;
+            write_usize(writer, large_block.block_size)?;
+            write_usize(writer, large_block.mapped_size)?;
+            write_usize(writer, large_block.flags)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        if read_u64(reader)? != DUMP_MAGIC {
+            return Err(invalid_dump("Not a heap dump."));
+        }
+        if read_u32(reader)? != DUMP_VERSION {
+            return Err(invalid_dump("Unsupported version of the heap dump."));
+        }
+
+        let segment_size = read_usize(reader)?;
+        let max_heap_size = read_usize(reader)?;
+        let context_space_size = read_usize(reader)?;
+        let reserved_segment_space_size = read_usize(reader)?;
+        let keep_count = read_usize(reader)?;
+        let uses_huge_pages = read_u8(reader)? != 0;
+        let segment_space_begin = read_usize(reader)?;
+        let class_count = read_usize(reader)?;
+        let block_size_of_class = (0..class_count)
+            .map(|_| read_usize(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let allocated_segment_count = read_usize(reader)?;
+        let segment_record_count = read_usize(reader)?;
+        if segment_record_count > allocated_segment_count {
+            return Err(invalid_dump("Too many segments."));
+        }
+        let segments = (0..segment_record_count)
+            .map(|_| read_segment(reader, class_count))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let large_block_count = read_usize(reader)?;
+        let mut large_blocks = Vec::new();
+        for _ in 0..large_block_count {
+            large_blocks.push(LargeBlockDump {
+                addr: read_usize(reader)?,
+                block_size: read_usize(reader)?,
+                mapped_size: read_usize(reader)?,
+                flags: read_usize(reader)?,
+            });
+        }
+
+        Ok(Self {
+            segment_size,
+            max_heap_size,
+            context_space_size,
+            reserved_segment_space_size,
+            keep_count,
+            uses_huge_pages,
+            segment_space_begin,
+            block_size_of_class,
+            allocated_segment_count,
+            segments,
+            large_blocks,
+        })
+    }
+}
+
+fn write_segment<W: Write>(writer: &mut W, seg: &SegmentDump) -> Result<(), Box<dyn Error>> {
+    write_usize(writer, seg.index)?;
+    match seg.list {
+        None => write_u8(writer, LIST_TAG_NONE)?,
+        Some(SegmentList::SubHeap(class_of_size)) => {
+            write_u8(writer, LIST_TAG_SUBHEAP)?;
+            write_usize(writer, class_of_size)?;
+        }
+        Some(SegmentList::Kept) => write_u8(writer, LIST_TAG_KEPT)?,
+        Some(SegmentList::Free) => write_u8(writer, LIST_TAG_FREE)?,
+        Some(SegmentList::FreeSpans) => write_u8(writer, LIST_TAG_FREE_SPANS)?,
+    }
+
+    match &seg.kind {
+        SegmentKind::Free { commit_state } => {
+            write_u8(writer, segment::Status::Free as u8)?;
+            write_u8(writer, commit_state.into_bits() as u8)?;
+        }
+        SegmentKind::Kept => write_u8(writer, segment::Status::Kept as u8)?,
+        SegmentKind::FreeSpan {
+            segment_count,
+            commit_state,
+        } => {
+            write_u8(writer, segment::Status::FreeSpan as u8)?;
+            write_usize(writer, *segment_count)?;
+            write_u8(writer, commit_state.into_bits() as u8)?;
+        }
+        SegmentKind::SubHeap {
+            class_of_size,
+            used_block_count,
+            block_count,
+            bitmap,
+        } => {
+            write_u8(writer, segment::Status::SubHeap as u8)?;
+            write_usize(writer, *class_of_size)?;
+            write_usize(writer, *used_block_count)?;
+            write_usize(writer, *block_count)?;
+            writer.write_all(bitmap)?;
+        }
+        SegmentKind::Span { segment_count } => {
+            write_u8(writer, segment::Status::Span as u8)?;
+            write_usize(writer, *segment_count)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_segment<R: Read>(
+    reader: &mut R,
+    class_count: usize,
+) -> Result<SegmentDump, Box<dyn Error>> {
+    let index = read_usize(reader)?;
+    let list = match read_u8(reader)? {
+        LIST_TAG_NONE => None,
+        LIST_TAG_SUBHEAP => Some(SegmentList::SubHeap(read_class(reader, class_count)?)),
+        LIST_TAG_KEPT => Some(SegmentList::Kept),
+        LIST_TAG_FREE => Some(SegmentList::Free),
+        LIST_TAG_FREE_SPANS => Some(SegmentList::FreeSpans),
+        _ => return Err(invalid_dump("Invalid list of a segment.")),
+    };
+
+    let status = read_u8(reader)?;
+    let kind = if status == segment::Status::Free as u8 {
+        SegmentKind::Free {
+            commit_state: read_commit_state(reader)?,
+        }
+    } else if status == segment::Status::Kept as u8 {
+        SegmentKind::Kept
+    } else if status == segment::Status::FreeSpan as u8 {
+        SegmentKind::FreeSpan {
+            segment_count: read_usize(reader)?,
+            commit_state: read_commit_state(reader)?,
+        }
+    } else if status == segment::Status::SubHeap as u8 {
+        let class_of_size = read_class(reader, class_count)?;
+        let used_block_count = read_usize(reader)?;
+        let block_count = read_usize(reader)?;
+        if block_count > segment::SEGMENT_SIZE {
+            return Err(invalid_dump("Too many blocks of a segment."));
+        }
+        let mut bitmap = vec![0; block_count.div_ceil(u8::BITS as usize)];
+        reader.read_exact(&mut bitmap)?;
+        SegmentKind::SubHeap {
+            class_of_size,
+            used_block_count,
+            block_count,
+            bitmap,
+        }
+    } else if status == segment::Status::Span as u8 {
+        SegmentKind::Span {
+            segment_count: read_usize(reader)?,
+        }
+    } else {
+        return Err(invalid_dump("Invalid status of a segment."));
+    };
+
+    Ok(SegmentDump { index, list, kind })
+}
+
+fn read_class<R: Read>(reader: &mut R, class_count: usize) -> Result<usize, Box<dyn Error>> {
+    let class_of_size = read_usize(reader)?;
+    if class_of_size >= class_count {
+        return Err(invalid_dump("Invalid class of a segment."));
+    }
+    Ok(class_of_size)
+}
+
+fn read_commit_state<R: Read>(reader: &mut R) -> Result<CommitState, Box<dyn Error>> {
+    let bits = read_u8(reader)? as usize;
+    if bits > CommitState::HardDecommitted.into_bits() {
+        return Err(invalid_dump("Invalid commit state of a segment."));
+    }
+    Ok(CommitState::from_bits(bits))
+}
+
+fn invalid_dump(reason: &'static str) -> Box<dyn Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, reason))
+}
+
+fn write_u8<W: Write>(writer: &mut W, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_usize<W: Write>(writer: &mut W, value: usize) -> io::Result<()> {
+    write_u64(writer, value as u64)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_usize<R: Read>(reader: &mut R) -> Result<usize, Box<dyn Error>> {
+    usize::try_from(read_u64(reader)?)
+        .map_err(|_| invalid_dump("Too large value for the platform."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A segment of each kind, which is not captured from an arena but covers the whole format.
+    fn test_dump() -> HeapDump {
+        HeapDump {
+            segment_size: segment::SEGMENT_SIZE,
+            max_heap_size: 64 << 20,
+            context_space_size: 1 << 16,
+            reserved_segment_space_size: 63 << 20,
+            keep_count: 2,
+            uses_huge_pages: false,
+            segment_space_begin: 0x7000_0000_0000,
+            block_size_of_class: vec![16, 32, 64],
+            allocated_segment_count: 8,
+            segments: vec![
+                SegmentDump {
+                    index: 0,
+                    list: Some(SegmentList::SubHeap(2)),
+                    kind: SegmentKind::SubHeap {
+                        class_of_size: 2,
+                        used_block_count: 3,
+                        block_count: 10,
+                        bitmap: vec![0b1011, 0],
+                    },
+                },
+                SegmentDump {
+                    index: 1,
+                    list: Some(SegmentList::Kept),
+                    kind: SegmentKind::Kept,
+                },
+                SegmentDump {
+                    index: 2,
+                    list: Some(SegmentList::Free),
+                    kind: SegmentKind::Free {
+                        commit_state: CommitState::SoftDecommitted,
+                    },
+                },
+                SegmentDump {
+                    index: 3,
+                    list: Some(SegmentList::FreeSpans),
+                    kind: SegmentKind::FreeSpan {
+                        segment_count: 2,
+                        commit_state: CommitState::HardDecommitted,
+                    },
+                },
+                SegmentDump {
+                    index: 5,
+                    list: None,
+                    kind: SegmentKind::Span { segment_count: 3 },
+                },
+            ],
+            large_blocks: vec![LargeBlockDump {
+                addr: 0x7100_0000_0000,
+                block_size: 2 << 20,
+                mapped_size: (2 << 20) + (1 << 12),
+                flags: block::FLAG_GUARD_PAGE,
+            }],
+        }
+    }
+
+    fn test_dump_bytes() -> Vec<u8> {
+        let mut dump_bytes = Vec::new();
+        test_dump().write_to(&mut dump_bytes).unwrap();
+        dump_bytes
+    }
+
+    fn read_error(dump_bytes: &[u8]) -> io::ErrorKind {
+        let err = HeapDump::read_from(&mut &dump_bytes[..]).unwrap_err();
+        err.downcast::<io::Error>().unwrap().kind()
+    }
+
+    #[test]
+    fn reads_the_written_dump() {
+        let dump_bytes = test_dump_bytes();
+        assert_eq!(
+            HeapDump::read_from(&mut dump_bytes.as_slice()).unwrap(),
+            test_dump()
+        );
+    }
+
+    #[test]
+    fn rejects_a_bad_magic() {
+        let mut dump_bytes = test_dump_bytes();
+        dump_bytes[0] ^= 0xff;
+        assert_eq!(read_error(&dump_bytes), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_another_version() {
+        let mut dump_bytes = test_dump_bytes();
+        dump_bytes[8..12].copy_from_slice(&(DUMP_VERSION + 1).to_le_bytes());
+        assert_eq!(read_error(&dump_bytes), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_a_truncated_dump() {
+        let dump_bytes = test_dump_bytes();
+        assert_eq!(
+            read_error(&dump_bytes[..dump_bytes.len() - 1]),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
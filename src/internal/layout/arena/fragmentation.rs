@@ -0,0 +1,167 @@
+use std::array;
+
+use crate::internal::layout::arena::Stats;
+use crate::internal::layout::segment;
+use crate::internal::layout::subheap;
+
+// Sizes per segment and per block are from the static class tables, and totals are from the live counters.
+// Requested sizes are not recorded, since frees do not know them,
+// so internal fragmentation is only bounded by the worst case of the used blocks rather than measured.
+// Read by embedders, while the binary prints only the external fragmentation.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct ClassFragmentation {
+    pub block_size: usize,
+    pub internal_waste_bound_size_per_block: usize,
+    pub tail_waste_size_per_segment: usize,
+    pub used_size: usize,
+    pub internal_waste_bound_size: usize,
+    pub header_size: usize,
+    pub tail_waste_size: usize,
+    // Free blocks are only in partially used segments, since empty segments are released from the subheap.
+    pub free_block_size: usize,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct FragmentationReport {
+    pub classes: [ClassFragmentation; subheap::CLASS_COUNT],
+    pub used_size: usize,
+    pub internal_waste_bound_size: usize,
+    pub header_size: usize,
+    pub tail_waste_size: usize,
+    pub free_block_size: usize,
+    // Kept segments are committed but hold no blocks.
+    pub kept_segment_size: usize,
+}
+
+impl FragmentationReport {
+    // The ratio of free blocks to all blocks of the subheaps.
+    pub fn external_fragmentation(&self) -> f64 {
+        let block_size = self.used_size + self.free_block_size;
+        if block_size == 0 {
+            0.0
+        } else {
+            self.free_block_size as f64 / block_size as f64
+        }
+    }
+}
+
+pub fn report(stats: &Stats) -> FragmentationReport {
+    let classes: [ClassFragmentation; subheap::CLASS_COUNT] = array::from_fn(|class_of_size| {
+        let class_stats = &stats.classes[class_of_size];
+        let block_size = class_stats.block_size;
+        let internal_waste_bound_size_per_block =
+            subheap::max_internal_waste_size_of_class(class_of_size);
+        let tail_waste_size_per_segment = segment::tail_waste_size_of_class(class_of_size);
+        let free_block_count = class_stats.segment_count * class_stats.block_count_per_segment
+            - class_stats.used_block_count;
+
+        ClassFragmentation {
+            block_size,
+            internal_waste_bound_size_per_block,
+            tail_waste_size_per_segment,
+            used_size: class_stats.used_block_count * block_size,
+            internal_waste_bound_size: class_stats.used_block_count
+                * internal_waste_bound_size_per_block,
+            header_size: class_stats.segment_count * segment::header_size_of_class(class_of_size),
+            tail_waste_size: class_stats.segment_count * tail_waste_size_per_segment,
+            free_block_size: free_block_count * block_size,
+        }
+    });
+
+    FragmentationReport {
+        used_size: classes.iter().map(|cls| cls.used_size).sum(),
+        internal_waste_bound_size: classes
+            .iter()
+            .map(|cls| cls.internal_waste_bound_size)
+            .sum(),
+        header_size: classes.iter().map(|cls| cls.header_size).sum(),
+        tail_waste_size: classes.iter().map(|cls| cls.tail_waste_size).sum(),
+        free_block_size: classes.iter().map(|cls| cls.free_block_size).sum(),
+        kept_segment_size: stats.kept_segment_count * segment::SEGMENT_SIZE,
+        classes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::layout::arena::ClassStats;
+
+    fn stats_of_class(
+        class_of_size: usize,
+        segment_count: usize,
+        used_block_count: usize,
+    ) -> Stats {
+        Stats {
+            classes: array::from_fn(|cls| {
+                let is_target = cls == class_of_size;
+                ClassStats {
+                    block_size: subheap::SUBHEAP_SIZE_OF_CLASS[cls],
+                    block_count_per_segment: segment::BLOCK_COUNT_OF_CLASS[cls],
+                    segment_count: if is_target { segment_count } else { 0 },
+                    partial_segment_count: 0,
+                    used_block_count: if is_target { used_block_count } else { 0 },
+                }
+            }),
+            allocated_segment_count: segment_count + 1,
+            kept_segment_count: 1,
+            free_segment_count: 0,
+            free_span_segment_count: 0,
+            span_count: 0,
+            span_segment_count: 0,
+            large_block_count: 0,
+            large_block_mapped_size: 0,
+            cached_large_block_size: 0,
+            quarantined_block_count: 0,
+            quarantined_size: 0,
+            reserved_size: 0,
+            used_budget_size: 0,
+            available_size: 0,
+        }
+    }
+
+    #[test]
+    fn reports_no_fragmentation_of_an_empty_heap() {
+        let report = report(&stats_of_class(0, 0, 0));
+        assert_eq!(report.used_size, 0);
+        assert_eq!(report.external_fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn reports_free_blocks_and_waste_of_used_segments() {
+        let class_of_size = subheap::CLASS_COUNT - 1;
+        let block_size = subheap::SUBHEAP_SIZE_OF_CLASS[class_of_size];
+        let block_count = segment::BLOCK_COUNT_OF_CLASS[class_of_size];
+        // Two segments, where one is full and the other has a single used block.
+        let used_block_count = block_count + 1;
+        let report = report(&stats_of_class(class_of_size, 2, used_block_count));
+
+        let cls = &report.classes[class_of_size];
+        assert_eq!(cls.used_size, used_block_count * block_size);
+        assert_eq!(cls.free_block_size, (block_count - 1) * block_size);
+        assert_eq!(
+            cls.internal_waste_bound_size,
+            used_block_count * cls.internal_waste_bound_size_per_block
+        );
+        assert_eq!(cls.tail_waste_size, 2 * cls.tail_waste_size_per_segment);
+        assert_eq!(report.kept_segment_size, segment::SEGMENT_SIZE);
+        assert_eq!(
+            report.external_fragmentation(),
+            (block_count - 1) as f64 / (2 * block_count) as f64
+        );
+    }
+
+    #[test]
+    fn accounts_the_whole_segment_of_each_class() {
+        for class_of_size in 0..subheap::CLASS_COUNT {
+            let report = report(&stats_of_class(class_of_size, 1, 0));
+            let cls = &report.classes[class_of_size];
+            assert_eq!(
+                cls.header_size + cls.tail_waste_size + cls.free_block_size,
+                segment::SEGMENT_SIZE
+            );
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::internal::layout::segment;
+use crate::internal::layout::segment_space;
+use crate::sys::CommitState;
+
+#[derive(Debug)]
+pub struct FreeSpansList {
+    begin: Option<segment::SegmentIndex>,
+    segment_count: usize,
+}
+
+impl FreeSpansList {
+    pub fn new() -> Self {
+        Self {
+            begin: None,
+            segment_count: 0,
+        }
+    }
+
+    #[inline]
+    pub fn segment_count(&self) -> usize {
+        self.segment_count
+    }
+
+    #[inline]
+    pub fn begin(&self) -> Option<segment::SegmentIndex> {
+        self.begin
+    }
+
+    pub unsafe fn insert(
+        &mut self,
+        segment_space: &mut segment_space::SegmentSpace,
+        seg: &mut segment::Segment,
+        segment_count: usize,
+        state: CommitState,
+    ) {
+        assert!(segment_count > 0);
+        self.segment_count += segment_count;
+        insert(self, segment_space, seg, segment_count, state)
+    }
+
+    pub unsafe fn take(
+        &mut self,
+        segment_space: &mut segment_space::SegmentSpace,
+        segment_count: usize,
+    ) -> Option<(segment::Segment, CommitState)> {
+        assert!(segment_count > 0);
+        let taken = take(self, segment_space, segment_count);
+        if taken.is_some() {
+            self.segment_count -= segment_count;
+        }
+        taken
+    }
+}
+
+unsafe fn insert(
+    free_spans_list: &mut FreeSpansList,
+    segment_space: &mut segment_space::SegmentSpace,
+    seg: &mut segment::Segment,
+    segment_count: usize,
+    state: CommitState,
+) {
+    let seg_index = seg.index.get();
+
+    // Spans are sorted by addresses, to merge with neighbors.
+    let mut prev_seg: Option<segment::Segment> = None;
+    let mut next_index = free_spans_list.begin;
+    while let Some(current_index) = next_index {
+        if seg.index < current_index {
+            break;
+        }
+        let current_seg = segment_space.segment_by_index(current_index);
+        next_index = current_seg.next();
+        prev_seg = Some(current_seg);
+    }
+
+    let (mut span_seg, mut span_segment_count, mut span_state) = match prev_seg {
+        Some(mut prev_seg) => {
+            let (prev_segment_count, prev_state) = prev_seg.free_span();
+            if prev_seg.index.get() + prev_segment_count == seg_index {
+                seg.set_status(segment::Status::InSpan);
+                (
+                    prev_seg,
+                    prev_segment_count + segment_count,
+                    prev_state.merge(state),
+                )
+            } else {
+                prev_seg.set_next(Some(seg.index));
+                (*seg, segment_count, state)
+            }
+        }
+        None => {
+            free_spans_list.begin = Some(seg.index);
+            (*seg, segment_count, state)
+        }
+    };
+
+    match next_index {
+        Some(next_index) => {
+            let mut next_seg = segment_space.segment_by_index(next_index);
+            if seg_index + segment_count == next_index.get() {
+                next_seg.set_status(segment::Status::InSpan);
+                let (next_segment_count, next_state) = next_seg.free_span();
+                span_segment_count += next_segment_count;
+                span_state = span_state.merge(next_state);
+                span_seg.set_next(next_seg.next());
+            } else {
+                span_seg.set_next(Some(next_index));
+            }
+        }
+        None => {
+            span_seg.set_next(None);
+        }
+    }
+    span_seg.set_free_span(span_segment_count, span_state);
+}
+
+unsafe fn take(
+    free_spans_list: &mut FreeSpansList,
+    segment_space: &mut segment_space::SegmentSpace,
+    segment_count: usize,
+) -> Option<(segment::Segment, CommitState)> {
+    let mut prev_seg: Option<segment::Segment> = None;
+    let mut next_index = free_spans_list.begin;
+    while let Some(current_index) = next_index {
+        let current_seg = segment_space.segment_by_index(current_index);
+        let (current_segment_count, current_state) = current_seg.free_span();
+
+        if current_segment_count >= segment_count {
+            // Take the front of the span, and leave the rest.
+            let rest_index = if current_segment_count == segment_count {
+                current_seg.next()
+            } else {
+                let rest_index = segment::SegmentIndex::new(current_index.get() + segment_count);
+                let mut rest_seg = segment_space.segment_by_index(rest_index);
+                rest_seg.set_next(current_seg.next());
+                rest_seg.set_free_span(current_segment_count - segment_count, current_state);
+                Some(rest_index)
+            };
+            match prev_seg {
+                Some(mut prev_seg) => prev_seg.set_next(rest_index),
+                None => free_spans_list.begin = rest_index,
+            }
+
+            return Some((current_seg, current_state));
+        }
+
+        next_index = current_seg.next();
+        prev_seg = Some(current_seg);
+    }
+
+    None
+}
@@ -1,24 +1,49 @@
-use std::ptr::NonNull;
-
 use crate::internal::layout::segment;
 use crate::internal::layout::segment_space;
 
 #[derive(Debug)]
 pub struct KeepSegmentsList {
+    keep_count: usize,
     should_keep_count: usize,
-    begin: *mut segment::CompactHeader,
-    end: *mut segment::CompactHeader,
+    begin: Option<segment::SegmentIndex>,
+    end: Option<segment::SegmentIndex>,
 }
 
 impl KeepSegmentsList {
-    pub fn new(should_keep_count: usize) -> Self {
+    pub fn new(keep_count: usize) -> Self {
         Self {
-            should_keep_count,
-            begin: std::ptr::null_mut(),
-            end: std::ptr::null_mut(),
+            keep_count,
+            should_keep_count: keep_count,
+            begin: None,
+            end: None,
         }
     }
 
+    #[inline]
+    pub fn kept_count(&self) -> usize {
+        self.keep_count - self.should_keep_count
+    }
+
+    #[inline]
+    pub fn keep_count(&self) -> usize {
+        self.keep_count
+    }
+
+    #[inline]
+    pub fn should_keep_count(&self) -> usize {
+        self.should_keep_count
+    }
+
+    #[inline]
+    pub fn begin(&self) -> Option<segment::SegmentIndex> {
+        self.begin
+    }
+
+    #[inline]
+    pub fn end(&self) -> Option<segment::SegmentIndex> {
+        self.end
+    }
+
     pub unsafe fn insert_and_return_flooded(
         &mut self,
         segment_space: &mut segment_space::SegmentSpace,
@@ -31,7 +56,7 @@ impl KeepSegmentsList {
     pub unsafe fn pop(
         &mut self,
         segment_space: &mut segment_space::SegmentSpace,
-    ) -> Option<NonNull<segment::CompactHeader>> {
+    ) -> Option<segment::SegmentIndex> {
         pop(self, segment_space)
     }
 }
@@ -41,40 +66,38 @@ unsafe fn insert_and_return_flooded(
     segment_space: &mut segment_space::SegmentSpace,
     floated_seg: &mut segment::Segment,
 ) -> Option<segment::Segment> {
-    let seg_ptr = floated_seg.compact_header;
-    match NonNull::new(keep_segments_list.begin) {
+    let seg_index = floated_seg.index;
+    match keep_segments_list.begin {
         None => {
             if keep_segments_list.should_keep_count == 0 {
                 Some(*floated_seg)
             } else {
-                keep_segments_list.begin = seg_ptr.as_ptr();
-                keep_segments_list.end = seg_ptr.as_ptr();
+                keep_segments_list.begin = Some(seg_index);
+                keep_segments_list.end = Some(seg_index);
                 keep_segments_list.should_keep_count -= 1;
 
                 None
             }
         }
-        Some(begin_ptr) => {
-            let end_ptr = NonNull::new_unchecked(keep_segments_list.end);
+        Some(begin_index) => {
+            let end_index = keep_segments_list.end.unwrap_unchecked();
 
-            let mut begin_seg = segment_space.segment_by_cmp_header(begin_ptr);
-            let mut end_seg = segment_space.segment_by_cmp_header(end_ptr);
+            let mut begin_seg = segment_space.segment_by_index(begin_index);
+            let mut end_seg = segment_space.segment_by_index(end_index);
 
-            let middle_ptr = NonNull::new_unchecked(
-                begin_ptr
-                    .as_ptr()
-                    .add((end_ptr.as_ptr().offset_from(begin_ptr.as_ptr()) as usize) / 2),
+            let middle_index = segment::SegmentIndex::new(
+                begin_index.get() + (end_index.get() - begin_index.get()) / 2,
             );
 
-            if seg_ptr < begin_ptr {
-                if begin_ptr == end_ptr && keep_segments_list.should_keep_count == 0 {
-                    keep_segments_list.begin = seg_ptr.as_ptr();
-                    keep_segments_list.end = seg_ptr.as_ptr();
+            if seg_index < begin_index {
+                if begin_index == end_index && keep_segments_list.should_keep_count == 0 {
+                    keep_segments_list.begin = Some(seg_index);
+                    keep_segments_list.end = Some(seg_index);
 
                     Some(begin_seg)
                 } else {
                     floated_seg.append(&mut begin_seg);
-                    keep_segments_list.begin = seg_ptr.as_ptr();
+                    keep_segments_list.begin = Some(seg_index);
 
                     if keep_segments_list.should_keep_count == 0 {
                         Some(force_pop_end_without_updating_count(
@@ -86,30 +109,30 @@ unsafe fn insert_and_return_flooded(
                         None
                     }
                 }
-            } else if end_ptr < seg_ptr {
+            } else if end_index < seg_index {
                 if keep_segments_list.should_keep_count == 0 {
                     Some(*floated_seg)
                 } else {
                     end_seg.append(floated_seg);
-                    keep_segments_list.end = seg_ptr.as_ptr();
+                    keep_segments_list.end = Some(seg_index);
 
                     keep_segments_list.should_keep_count -= 1;
                     None
                 }
-            } else if seg_ptr < middle_ptr {
-                floated_seg.set_prev(begin_ptr.as_ptr());
+            } else if seg_index < middle_index {
+                floated_seg.set_prev(Some(begin_index));
                 floated_seg.set_next(begin_seg.next());
-                match NonNull::new(begin_seg.next()) {
-                    Some(begin_next_ptr) => {
+                match begin_seg.next() {
+                    Some(begin_next_index) => {
                         segment_space
-                            .segment_by_cmp_header(begin_next_ptr)
-                            .set_prev(seg_ptr.as_ptr());
+                            .segment_by_index(begin_next_index)
+                            .set_prev(Some(seg_index));
                     }
                     None => {
-                        keep_segments_list.end = seg_ptr.as_ptr();
+                        keep_segments_list.end = Some(seg_index);
                     }
                 }
-                begin_seg.set_next(seg_ptr.as_ptr());
+                begin_seg.set_next(Some(seg_index));
 
                 if keep_segments_list.should_keep_count == 0 {
                     Some(force_pop_end_without_updating_count(
@@ -122,24 +145,24 @@ unsafe fn insert_and_return_flooded(
                 }
             } else {
                 floated_seg.set_prev(end_seg.prev());
-                match NonNull::new(end_seg.prev()) {
-                    Some(end_prev_ptr) => {
+                match end_seg.prev() {
+                    Some(end_prev_index) => {
                         segment_space
-                            .segment_by_cmp_header(end_prev_ptr)
-                            .set_next(seg_ptr.as_ptr());
+                            .segment_by_index(end_prev_index)
+                            .set_next(Some(seg_index));
                     }
                     None => {
-                        keep_segments_list.begin = seg_ptr.as_ptr();
+                        keep_segments_list.begin = Some(seg_index);
                     }
                 }
 
                 if keep_segments_list.should_keep_count == 0 {
-                    keep_segments_list.end = seg_ptr.as_ptr();
-                    end_seg.set_prev(std::ptr::null_mut());
+                    keep_segments_list.end = Some(seg_index);
+                    end_seg.set_prev(None);
                     Some(end_seg)
                 } else {
-                    floated_seg.set_next(end_ptr.as_ptr());
-                    end_seg.set_prev(seg_ptr.as_ptr());
+                    floated_seg.set_next(Some(end_index));
+                    end_seg.set_prev(Some(seg_index));
                     keep_segments_list.should_keep_count -= 1;
                     None
                 }
@@ -151,62 +174,61 @@ unsafe fn insert_and_return_flooded(
 unsafe fn pop(
     keep_segments_list: &mut KeepSegmentsList,
     segment_space: &mut segment_space::SegmentSpace,
-) -> Option<NonNull<segment::CompactHeader>> {
-    let begin_ptr = match NonNull::new(keep_segments_list.begin) {
-        None => return None,
-        Some(begin_ptr) => begin_ptr,
-    };
-    let begin_seg_header = begin_ptr.as_ref();
-
-    match NonNull::new(begin_seg_header.next) {
-        Some(begin_next_ptr) => {
-            let end_ptr = NonNull::new_unchecked(keep_segments_list.end);
-
-            if begin_next_ptr <= end_ptr {
-                let mut new_begin = segment_space.segment_by_cmp_header(begin_next_ptr);
-                keep_segments_list.begin = new_begin.compact_header.as_ptr();
-                new_begin.set_prev(std::ptr::null_mut());
-            } else if begin_next_ptr.as_ref().next == end_ptr.as_ptr() {
-                let mut new_begin = segment_space.segment_by_cmp_header(end_ptr);
-                let mut new_end = segment_space.segment_by_cmp_header(begin_next_ptr);
-                keep_segments_list.begin = new_begin.compact_header.as_ptr();
-                keep_segments_list.end = new_end.compact_header.as_ptr();
-                new_begin.set_prev(std::ptr::null_mut());
-                new_begin.set_next(new_end.compact_header.as_ptr());
-                new_end.set_prev(new_begin.compact_header.as_ptr());
-                new_end.set_next(std::ptr::null_mut());
+) -> Option<segment::SegmentIndex> {
+    let begin_index = keep_segments_list.begin?;
+    let begin_seg = segment_space.segment_by_index(begin_index);
+
+    match begin_seg.next() {
+        Some(begin_next_index) => {
+            let end_index = keep_segments_list.end.unwrap_unchecked();
+
+            if begin_next_index <= end_index {
+                let mut new_begin = segment_space.segment_by_index(begin_next_index);
+                keep_segments_list.begin = Some(new_begin.index);
+                new_begin.set_prev(None);
+            } else if segment_space.segment_by_index(begin_next_index).next() == Some(end_index) {
+                let mut new_begin = segment_space.segment_by_index(end_index);
+                let mut new_end = segment_space.segment_by_index(begin_next_index);
+                keep_segments_list.begin = Some(new_begin.index);
+                keep_segments_list.end = Some(new_end.index);
+                new_begin.set_prev(None);
+                new_begin.set_next(Some(new_end.index));
+                new_end.set_prev(Some(new_begin.index));
+                new_end.set_next(None);
             } else {
-                let mut new_begin = segment_space.segment_by_cmp_header(end_ptr);
-                let mut new_end = segment_space.segment_by_cmp_header(begin_next_ptr);
+                let mut new_begin = segment_space.segment_by_index(end_index);
+                let mut new_end = segment_space.segment_by_index(begin_next_index);
                 let new_begin_next = new_end.next();
                 let new_end_prev = new_begin.prev();
-                keep_segments_list.begin = new_begin.compact_header.as_ptr();
-                keep_segments_list.end = new_end.compact_header.as_ptr();
-                new_begin.set_prev(std::ptr::null_mut());
+                keep_segments_list.begin = Some(new_begin.index);
+                keep_segments_list.end = Some(new_end.index);
+                new_begin.set_prev(None);
                 new_begin.set_next(new_begin_next);
                 new_end.set_prev(new_end_prev);
-                new_end.set_next(std::ptr::null_mut());
+                new_end.set_next(None);
             }
         }
         None => {
-            keep_segments_list.begin = std::ptr::null_mut();
-            keep_segments_list.end = std::ptr::null_mut();
+            keep_segments_list.begin = None;
+            keep_segments_list.end = None;
         }
     }
     keep_segments_list.should_keep_count += 1;
 
-    Some(begin_ptr)
+    Some(begin_index)
 }
 
 unsafe fn force_pop_end_without_updating_count(
     keep_segments_list: &mut KeepSegmentsList,
     segment_space: &mut segment_space::SegmentSpace,
 ) -> segment::Segment {
-    let mut current_end = segment_space.segment_by_cmp_header(NonNull::new_unchecked(keep_segments_list.end));
+    let mut current_end = segment_space.segment_by_index(keep_segments_list.end.unwrap_unchecked());
     keep_segments_list.end = current_end.prev();
-    NonNull::new_unchecked(current_end.prev()).as_mut().next = std::ptr::null_mut();
+    segment_space
+        .segment_by_index(current_end.prev().unwrap_unchecked())
+        .set_next(None);
 
-    current_end.set_prev(std::ptr::null_mut());
-    current_end.set_next(std::ptr::null_mut());
+    current_end.set_prev(None);
+    current_end.set_next(None);
     current_end
 }
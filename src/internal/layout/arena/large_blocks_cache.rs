@@ -0,0 +1,143 @@
+use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
+
+const LARGE_BLOCKS_CACHE_CAPACITY: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    // Wrapping from the context space, like the large blocks table.
+    mapping_offset: usize,
+    mapped_size: usize,
+    state: CommitState,
+}
+
+// Entries are ordered from the oldest one.
+#[derive(Debug)]
+pub struct LargeBlocksCache {
+    max_cached_size: usize,
+    cached_size: usize,
+    entries_count: usize,
+    entries: [Option<Entry>; LARGE_BLOCKS_CACHE_CAPACITY],
+}
+
+impl LargeBlocksCache {
+    pub fn new(max_cached_size: usize) -> Self {
+        Self {
+            max_cached_size,
+            cached_size: 0,
+            entries_count: 0,
+            entries: [None; LARGE_BLOCKS_CACHE_CAPACITY],
+        }
+    }
+
+    #[inline]
+    pub fn cached_size(&self) -> usize {
+        self.cached_size
+    }
+
+    #[inline]
+    pub fn can_cache(&self, mapped_size: usize) -> bool {
+        mapped_size <= self.max_cached_size
+    }
+
+    pub fn insert(
+        &mut self,
+        context_space: AnyNonNullPtr,
+        block_ptr: AnyNonNullPtr,
+        mapped_size: usize,
+        state: CommitState,
+    ) {
+        assert!(self.entries_count < LARGE_BLOCKS_CACHE_CAPACITY);
+        assert!(self.cached_size + mapped_size <= self.max_cached_size);
+
+        self.entries[self.entries_count] = Some(Entry {
+            mapping_offset: block_ptr.wrapping_offset_from(context_space),
+            mapped_size,
+            state,
+        });
+        self.entries_count += 1;
+        self.cached_size += mapped_size;
+    }
+
+    pub unsafe fn pop_flooded(
+        &mut self,
+        context_space: AnyNonNullPtr,
+        incoming_mapped_size: usize,
+    ) -> Option<(AnyNonNullPtr, usize, CommitState)> {
+        if self.entries_count < LARGE_BLOCKS_CACHE_CAPACITY
+            && self.cached_size + incoming_mapped_size <= self.max_cached_size
+        {
+            return None;
+        }
+
+        let entry = self.remove(0);
+        Some((
+            context_space.wrapping_add(entry.mapping_offset),
+            entry.mapped_size,
+            entry.state,
+        ))
+    }
+
+    // Cached blocks are found by the begin of their mappings, since their headers are reset.
+    pub fn contains(&self, context_space: AnyNonNullPtr, mapping_addr: usize) -> bool {
+        let mapping_offset = mapping_addr.wrapping_sub(context_space.as_addr());
+        self.entries[..self.entries_count]
+            .iter()
+            .any(|entry| match entry {
+                Some(entry) => entry.mapping_offset == mapping_offset,
+                None => false,
+            })
+    }
+
+    pub unsafe fn pop(&mut self, context_space: AnyNonNullPtr) -> Option<(AnyNonNullPtr, usize)> {
+        if self.entries_count == 0 {
+            return None;
+        }
+
+        let entry = self.remove(0);
+        Some((
+            context_space.wrapping_add(entry.mapping_offset),
+            entry.mapped_size,
+        ))
+    }
+
+    pub unsafe fn take(
+        &mut self,
+        context_space: AnyNonNullPtr,
+        mapped_size: usize,
+    ) -> Option<(AnyNonNullPtr, CommitState)> {
+        // Prefer the newest one, which may be still hot.
+        for index in (0..self.entries_count).rev() {
+            match self.entries[index] {
+                Some(entry) if entry.mapped_size == mapped_size => {
+                    self.remove(index);
+                    return Some((
+                        context_space.wrapping_add(entry.mapping_offset),
+                        entry.state,
+                    ));
+                }
+                _ => {
+                    // continue
+                }
+            }
+        }
+
+        None
+    }
+
+    fn remove(&mut self, index: usize) -> Entry {
+        assert!(index < self.entries_count);
+
+        let entry = match self.entries[index] {
+            Some(entry) => entry,
+            None => panic!("unreachable: cache entries are packed."),
+        };
+        self.entries
+            .copy_within(index + 1..self.entries_count, index);
+        self.entries_count -= 1;
+        self.entries[self.entries_count] = None;
+        self.cached_size -= entry.mapped_size;
+
+        entry
+    }
+}
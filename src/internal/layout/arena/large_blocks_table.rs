@@ -0,0 +1,269 @@
+use std::error::Error;
+use std::mem::size_of;
+use std::result::Result;
+use std::slice;
+
+use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::SysMemEnv;
+use crate::util;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    // Wrapping from the context space, since blocks may be mapped below it.
+    block_offset: usize,
+    // The length of the whole mapping, which is released on free.
+    mapped_size: usize,
+}
+
+impl Entry {
+    #[inline]
+    unsafe fn block_ptr(&self, context_space: AnyNonNullPtr) -> AnyNonNullPtr {
+        context_space.wrapping_add(self.block_offset)
+    }
+}
+
+pub const ENTRY_SIZE: usize = size_of::<Entry>();
+
+// Large blocks are indexed by the offsets of their headers in a sorted array in the context space,
+// out of the blocks, so that writes over the blocks never break it.
+// Offsets are from the context space, so that the arena can be mapped at another address.
+// Pages of the array are committed as it grows, like the compact headers of segments.
+#[derive(Debug)]
+pub struct LargeBlocksTable {
+    // immutable
+    entries_offset: usize,
+    capacity: usize,
+
+    // mutable
+    committed_capacity: usize,
+    count: usize,
+    total_mapped_size: usize,
+}
+
+impl LargeBlocksTable {
+    pub fn new(entries_offset: usize, capacity: usize) -> Self {
+        Self {
+            entries_offset,
+            capacity,
+            committed_capacity: 0,
+            count: 0,
+            total_mapped_size: 0,
+        }
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    #[inline]
+    pub fn total_mapped_size(&self) -> usize {
+        self.total_mapped_size
+    }
+
+    #[inline]
+    unsafe fn entries(&self, context_space: AnyNonNullPtr) -> &[Entry] {
+        slice::from_raw_parts(
+            context_space.add(self.entries_offset).as_nonnull().as_ptr(),
+            self.count,
+        )
+    }
+
+    #[inline]
+    unsafe fn entries_mut(&mut self, context_space: AnyNonNullPtr) -> &mut [Entry] {
+        slice::from_raw_parts_mut(
+            context_space.add(self.entries_offset).as_nonnull().as_ptr(),
+            self.count,
+        )
+    }
+
+    // Commits a page of entries if the array is full, from the budget of the arena.
+    // Returns false if no entry can be inserted, since the budget or the capacity is exhausted.
+    pub unsafe fn reserve_entry<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        context_space: AnyNonNullPtr,
+        page_size: usize,
+        available_size: &mut usize,
+    ) -> Result<bool, Box<dyn Error>> {
+        if self.count < self.committed_capacity {
+            return Ok(true);
+        }
+        if self.committed_capacity == self.capacity || *available_size < page_size {
+            return Ok(false);
+        }
+
+        let committed_size = self.committed_capacity * ENTRY_SIZE;
+        assert!(util::bits::is_aligned(committed_size, page_size));
+        env.commit(
+            context_space.add(self.entries_offset + committed_size),
+            page_size,
+        )?;
+        *available_size -= page_size;
+        self.committed_capacity =
+            (self.committed_capacity + page_size / ENTRY_SIZE).min(self.capacity);
+
+        Ok(true)
+    }
+
+    // An entry must be reserved beforehand.
+    pub unsafe fn insert(
+        &mut self,
+        context_space: AnyNonNullPtr,
+        block_ptr: AnyNonNullPtr,
+        mapped_size: usize,
+    ) {
+        assert!(self.count < self.committed_capacity);
+
+        let index = match self.search(context_space, block_ptr.as_addr()) {
+            Ok(_) => panic!("unreachable: large blocks do not overlap."),
+            Err(index) => index,
+        };
+        self.count += 1;
+        let entries = self.entries_mut(context_space);
+        entries.copy_within(index..entries.len() - 1, index + 1);
+        entries[index] = Entry {
+            block_offset: block_ptr.wrapping_offset_from(context_space),
+            mapped_size,
+        };
+        self.total_mapped_size += mapped_size;
+    }
+
+    // Returns the mapped size of the removed block.
+    pub unsafe fn remove(
+        &mut self,
+        context_space: AnyNonNullPtr,
+        block_addr: usize,
+    ) -> Option<usize> {
+        let index = self.search(context_space, block_addr).ok()?;
+        let entries = self.entries_mut(context_space);
+        let mapped_size = entries[index].mapped_size;
+        entries.copy_within(index + 1.., index);
+        self.count -= 1;
+        self.total_mapped_size -= mapped_size;
+
+        Some(mapped_size)
+    }
+
+    // Returns the mapped size of the block.
+    #[inline]
+    pub unsafe fn get(&self, context_space: AnyNonNullPtr, block_addr: usize) -> Option<usize> {
+        let index = self.search(context_space, block_addr).ok()?;
+        Some(self.entries(context_space)[index].mapped_size)
+    }
+
+    #[inline]
+    pub unsafe fn last(&self, context_space: AnyNonNullPtr) -> Option<(AnyNonNullPtr, usize)> {
+        self.entries(context_space)
+            .last()
+            .map(|entry| (entry.block_ptr(context_space), entry.mapped_size))
+    }
+
+    pub unsafe fn for_each<F>(&self, context_space: AnyNonNullPtr, mut f: F)
+    where
+        F: FnMut(AnyNonNullPtr, usize),
+    {
+        for entry in self.entries(context_space) {
+            f(entry.block_ptr(context_space), entry.mapped_size);
+        }
+    }
+
+    #[inline]
+    unsafe fn search(
+        &self,
+        context_space: AnyNonNullPtr,
+        block_addr: usize,
+    ) -> Result<usize, usize> {
+        self.entries(context_space)
+            .binary_search_by_key(&block_addr.wrapping_sub(context_space.as_addr()), |entry| {
+                entry.block_offset
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::NonNull;
+
+    use super::*;
+    use crate::sys;
+
+    const CAPACITY: usize = 1024;
+
+    unsafe fn with_table<F>(f: F)
+    where
+        F: FnOnce(&mut sys::SysMemEnvImpl, AnyNonNullPtr, &mut LargeBlocksTable, usize),
+    {
+        let mut env = sys::new_env();
+        let page_size = env.get_pagesize().unwrap();
+        let space_size = util::bits::min_aligned_size(CAPACITY * ENTRY_SIZE, page_size);
+        let space = env.reserve(space_size).unwrap();
+        let mut table = LargeBlocksTable::new(0, CAPACITY);
+        f(&mut env, space, &mut table, page_size);
+        env.release(space, space_size).unwrap();
+    }
+
+    fn block_ptr(addr: usize) -> AnyNonNullPtr {
+        AnyNonNullPtr::new(NonNull::new(addr as *mut u8).unwrap())
+    }
+
+    #[test]
+    fn finds_blocks_inserted_out_of_order() {
+        unsafe {
+            with_table(|env, space, table, page_size| {
+                let mut available_size = usize::MAX;
+                let addrs = [0x7000, 0x3000, 0x9000, 0x1000, 0x5000];
+                for (index, &addr) in addrs.iter().enumerate() {
+                    assert!(table
+                        .reserve_entry(env, space, page_size, &mut available_size)
+                        .unwrap());
+                    table.insert(space, block_ptr(addr), (index + 1) * page_size);
+                }
+                assert_eq!(table.count(), addrs.len());
+                assert_eq!(table.total_mapped_size(), 15 * page_size);
+
+                let mut sorted_addrs = Vec::new();
+                table.for_each(space, |ptr, _| sorted_addrs.push(ptr.as_addr()));
+                assert_eq!(sorted_addrs, [0x1000, 0x3000, 0x5000, 0x7000, 0x9000]);
+
+                assert_eq!(table.get(space, 0x9000), Some(3 * page_size));
+                assert_eq!(table.get(space, 0x9008), None);
+                assert_eq!(table.remove(space, 0x3000), Some(2 * page_size));
+                assert_eq!(table.remove(space, 0x3000), None);
+                assert_eq!(table.get(space, 0x5000), Some(5 * page_size));
+                assert_eq!(
+                    table.last(space).map(|(ptr, _)| ptr.as_addr()),
+                    Some(0x9000)
+                );
+                assert_eq!(table.total_mapped_size(), 13 * page_size);
+            });
+        }
+    }
+
+    #[test]
+    fn commits_pages_of_entries_from_the_budget() {
+        unsafe {
+            with_table(|env, space, table, page_size| {
+                let entries_per_page = page_size / ENTRY_SIZE;
+                let mut available_size = page_size;
+                for index in 0..entries_per_page {
+                    assert!(table
+                        .reserve_entry(env, space, page_size, &mut available_size)
+                        .unwrap());
+                    table.insert(space, block_ptr((index + 1) * page_size), page_size);
+                }
+                assert_eq!(available_size, 0);
+
+                // The next page is over the budget.
+                assert!(!table
+                    .reserve_entry(env, space, page_size, &mut available_size)
+                    .unwrap());
+
+                table.remove(space, page_size);
+                assert!(table
+                    .reserve_entry(env, space, page_size, &mut available_size)
+                    .unwrap());
+            });
+        }
+    }
+}
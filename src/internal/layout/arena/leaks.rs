@@ -0,0 +1,94 @@
+use std::fmt;
+
+use crate::internal::layout::arena::Arena;
+use crate::internal::layout::block;
+use crate::internal::layout::subheap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LeakedBlocks {
+    pub count: usize,
+    pub size: usize,
+}
+
+impl LeakedBlocks {
+    fn add(&mut self, block_size: usize) {
+        self.count += 1;
+        self.size += block_size;
+    }
+}
+
+// Quarantined blocks are not live, so they are not reported as leaked.
+#[derive(Debug)]
+pub struct LeakReport {
+    pub classes: [LeakedBlocks; subheap::CLASS_COUNT],
+    pub spans: LeakedBlocks,
+    pub large_blocks: LeakedBlocks,
+}
+
+impl LeakReport {
+    pub fn total(&self) -> LeakedBlocks {
+        let all_blocks = self.classes.iter().chain([&self.spans, &self.large_blocks]);
+        LeakedBlocks {
+            count: all_blocks.clone().map(|blocks| blocks.count).sum(),
+            size: all_blocks.map(|blocks| blocks.size).sum(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.total().count == 0
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total();
+        writeln!(
+            formatter,
+            "Leaked {} blocks of {} bytes",
+            total.count, total.size
+        )?;
+
+        for (class_of_size, blocks) in self.classes.iter().enumerate() {
+            if blocks.count > 0 {
+                writeln!(
+                    formatter,
+                    "  class {} ({} bytes): {} blocks of {} bytes",
+                    class_of_size,
+                    subheap::SUBHEAP_SIZE_OF_CLASS[class_of_size],
+                    blocks.count,
+                    blocks.size
+                )?;
+            }
+        }
+        for (name, blocks) in [("spans", &self.spans), ("large blocks", &self.large_blocks)] {
+            if blocks.count > 0 {
+                writeln!(
+                    formatter,
+                    "  {}: {} blocks of {} bytes",
+                    name, blocks.count, blocks.size
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub unsafe fn report(arena: &Arena) -> LeakReport {
+    let mut report = LeakReport {
+        classes: [LeakedBlocks::default(); subheap::CLASS_COUNT],
+        spans: LeakedBlocks::default(),
+        large_blocks: LeakedBlocks::default(),
+    };
+
+    arena.for_each_live_block(|_, block_size, block_type| match block_type {
+        // Block sizes on subheaps are the sizes of their classes.
+        block::Type::OnSubHeap => match subheap::class_of_size(block_size) {
+            Some(class_of_size) => report.classes[class_of_size].add(block_size),
+            None => panic!("unreachable: blocks on subheaps have sizes of classes."),
+        },
+        block::Type::OnSpan => report.spans.add(block_size),
+        block::Type::FreeSize => report.large_blocks.add(block_size),
+    });
+    report
+}
@@ -1,25 +1,55 @@
 use std::array;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
+use std::mem::offset_of;
 use std::mem::size_of;
 use std::ptr::NonNull;
 use std::result::Result;
 
+use crate::internal::error;
 use crate::internal::layout::block;
 use crate::internal::layout::constants::ALIGNMENT_SIZE;
 use crate::internal::layout::segment;
 use crate::internal::layout::segment_space;
 use crate::internal::layout::subheap;
 use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
 use crate::sys::SysMemEnv;
 use crate::util;
 
+pub mod dump;
+pub mod fragmentation;
+mod free_spans_list;
 mod keep_segments_list;
+mod large_blocks_cache;
+mod large_blocks_table;
+pub mod leaks;
+mod quarantine;
+pub mod verifier;
 
 pub struct Config {
     pub min_heap_size: usize,
     pub max_heap_size: usize,
     pub keep_segments_count: usize,
+    pub use_huge_pages: bool,
+    pub large_blocks_cache_size: usize,
+    pub poisons_blocks: bool,
+    // Freed blocks are held up to the size, and not held with zero.
+    // Blocks are poisoned with it, since quarantined blocks on subheaps are checked by the pattern.
+    pub quarantine_size: usize,
+    pub large_block_guard_page: GuardPage,
+}
+
+// Where a guard page is mapped around each large block, so that accesses over the block fault.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardPage {
+    None,
+    // Only the block header is between the guard page and the payload.
+    Before,
+    // The payload is aligned to the end, so that only the rounding of the block size is before the guard page.
+    After,
 }
 
 pub struct Arena {
@@ -36,16 +66,73 @@ impl fmt::Debug for Arena {
     }
 }
 
+const ARENA_MAGIC: u64 = u64::from_le_bytes(*b"SAMPLEAR");
+// Bump it on changing the layout of the context space.
+const ARENA_VERSION: u32 = 8;
+
 #[derive(Debug)]
 pub struct Header {
+    magic: u64,
+    version: u32,
+    segment_size: usize,
+    class_count: usize,
+    max_heap_size: usize,
+    context_space_size: usize,
+    reserved_segment_space_size: usize,
+    poisons_blocks: bool,
+    large_block_guard_page: GuardPage,
     segment_space: segment_space::SegmentSpace,
     keep_segments: keep_segments_list::KeepSegmentsList,
-    free_segments_begin: *mut segment::CompactHeader,
+    free_segments_begin: Option<segment::SegmentIndex>,
+    free_segments_count: usize,
+    free_spans: free_spans_list::FreeSpansList,
+    span_count: usize,
+    span_segments_count: usize,
+    large_blocks_cache: large_blocks_cache::LargeBlocksCache,
+    large_blocks: large_blocks_table::LargeBlocksTable,
+    quarantine: quarantine::Quarantine,
     subheaps: [subheap::SubHeap; subheap::CLASS_COUNT],
 }
 
 const ARENA_HEADER_SIZE: usize = size_of::<Header>();
 
+const _: () = assert!(ALIGNMENT_SIZE >= 4);
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+pub struct ClassStats {
+    pub block_size: usize,
+    pub block_count_per_segment: usize,
+    pub segment_count: usize,
+    // Segments which have both used and free blocks.
+    pub partial_segment_count: usize,
+    pub used_block_count: usize,
+}
+
+// Polled by embedders, e.g. from a metrics endpoint, while the binary prints only a few of them.
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub classes: [ClassStats; subheap::CLASS_COUNT],
+    pub allocated_segment_count: usize,
+    pub kept_segment_count: usize,
+    pub free_segment_count: usize,
+    pub free_span_segment_count: usize,
+    pub span_count: usize,
+    pub span_segment_count: usize,
+    pub large_block_count: usize,
+    pub large_block_mapped_size: usize,
+    pub cached_large_block_size: usize,
+    // Blocks on subheaps are still counted as used while quarantined.
+    pub quarantined_block_count: usize,
+    pub quarantined_size: usize,
+    pub reserved_size: usize,
+    // Taken from the max heap size, including decommitted segments which are kept in the segment space,
+    // so it is not the size of committed pages.
+    pub used_budget_size: usize,
+    pub available_size: usize,
+}
+
 impl Arena {
     pub unsafe fn init<Env: SysMemEnv>(
         env: &mut Env,
@@ -54,6 +141,14 @@ impl Arena {
         init_arena(env, config)
     }
 
+    // Opens the arena which was initialized on the context space, e.g. in a reopened file.
+    pub unsafe fn open<Env: SysMemEnv>(
+        env: &mut Env,
+        context_space: AnyNonNullPtr,
+    ) -> Result<Self, Box<dyn Error>> {
+        open_arena(env, context_space)
+    }
+
     #[inline]
     unsafe fn header(&self) -> &Header {
         self.context_space.as_ref()
@@ -73,8 +168,18 @@ impl Arena {
     }
 
     #[inline]
-    pub unsafe fn segment(&mut self, seg_ptr: NonNull<segment::CompactHeader>) -> segment::Segment {
-        self.header_mut().segment_space.segment_by_cmp_header(seg_ptr)
+    pub unsafe fn poisons_blocks(&self) -> bool {
+        self.header().poisons_blocks
+    }
+
+    #[inline]
+    pub unsafe fn quarantines_blocks(&self) -> bool {
+        self.header().quarantine.is_enabled()
+    }
+
+    #[inline]
+    pub unsafe fn segment(&mut self, seg_index: segment::SegmentIndex) -> segment::Segment {
+        self.header_mut().segment_space.segment_by_index(seg_index)
     }
 
     #[inline]
@@ -87,13 +192,24 @@ impl Arena {
 
     #[inline]
     pub unsafe fn block_type(&mut self, ptr: AnyNonNullPtr) -> block::Type {
-        if self.header().segment_space.ptr_in_space(ptr) {
-            block::Type::OnSubHeap
+        let segment_space = &self.header().segment_space;
+        if segment_space.ptr_in_space(ptr) {
+            if segment_space.segment_by_inner_ptr(ptr).status() == segment::Status::Span {
+                block::Type::OnSpan
+            } else {
+                block::Type::OnSubHeap
+            }
         } else {
             block::Type::FreeSize
         }
     }
 
+    // Pointers out of the segment space are checked against the large blocks on free anyway.
+    #[cfg(feature = "debug-checks")]
+    pub unsafe fn check_block_to_free(&mut self, ptr: AnyNonNullPtr) -> Result<(), Box<dyn Error>> {
+        check_block_to_free_by_header(self.header_mut(), ptr)
+    }
+
     #[inline]
     pub unsafe fn free_unused_segment<Env: SysMemEnv>(
         &mut self,
@@ -124,6 +240,15 @@ impl Arena {
         insert_free_segment_to_subheap_by_header(self.header_mut(), class_of_size, floated_seg)
     }
 
+    #[inline]
+    pub unsafe fn is_segment_in_subheap(
+        &self,
+        class_of_size: usize,
+        seg: &segment::Segment,
+    ) -> bool {
+        is_segment_in_subheap_by_header(self.header(), class_of_size, seg)
+    }
+
     #[inline]
     pub unsafe fn remove_segment_from_subheap(
         &mut self,
@@ -142,6 +267,16 @@ impl Arena {
         alloc_block_free_size_by_header(self.header_mut(), env, block_size)
     }
 
+    #[inline]
+    pub unsafe fn alloc_block_of_free_size_aligned<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        block_size: usize,
+        alignment_size: usize,
+    ) -> Result<Option<AnyNonNullPtr>, Box<dyn Error>> {
+        alloc_block_free_size_aligned_by_header(self.header_mut(), env, block_size, alignment_size)
+    }
+
     #[inline]
     pub unsafe fn free_block_of_free_size<Env: SysMemEnv>(
         &mut self,
@@ -151,12 +286,115 @@ impl Arena {
         free_block_free_size_by_header(self.header_mut(), env, ptr)
     }
 
+    // The block is kept marked as used in the bitmap until it is popped from the quarantine.
+    #[inline]
+    pub unsafe fn quarantine_block_on_subheap(
+        &mut self,
+        block_ptr: AnyNonNullPtr,
+        block_size: usize,
+    ) {
+        let header = self.header_mut();
+        let context_space = context_space_by_header(header);
+        header
+            .quarantine
+            .push(context_space, block_ptr, block_size, None)
+    }
+
+    #[inline]
+    pub unsafe fn quarantine_block_of_free_size<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        ptr: AnyNonNullPtr,
+    ) -> Result<(), Box<dyn Error>> {
+        quarantine_block_free_size_by_header(self.header_mut(), env, ptr)
+    }
+
+    // Large blocks are released here, and blocks on subheaps are returned to be freed by the caller.
+    // Blocks are popped while the quarantine is over its size, or until it is empty on flushing.
+    #[inline]
+    pub unsafe fn pop_quarantined_block<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        flushes: bool,
+    ) -> Result<Option<AnyNonNullPtr>, Box<dyn Error>> {
+        pop_quarantined_block_by_header(self.header_mut(), env, flushes)
+    }
+
+    #[inline]
+    pub unsafe fn alloc_block_of_span<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        block_size: usize,
+    ) -> Result<Option<AnyNonNullPtr>, Box<dyn Error>> {
+        alloc_block_span_by_header(self.header_mut(), env, block_size)
+    }
+
+    #[inline]
+    pub unsafe fn free_block_of_span<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        ptr: AnyNonNullPtr,
+    ) -> Result<(), Box<dyn Error>> {
+        free_block_span_by_header(self.header_mut(), env, ptr)
+    }
+
     pub unsafe fn alloc_new_segment<Env: SysMemEnv>(
         &mut self,
         env: &mut Env,
     ) -> Result<Option<segment::Segment>, Box<dyn Error>> {
         self.header_mut().segment_space.alloc_new_segment(env)
     }
+
+    // Only reads counters, so that it is cheap enough to poll.
+    pub unsafe fn stats(&self) -> Stats {
+        stats_by_header(self.header())
+    }
+
+    pub unsafe fn for_each_large_block<F: FnMut(AnyNonNullPtr, usize)>(&self, mut f: F) {
+        self.header()
+            .large_blocks
+            .for_each(self.context_space, |block_ptr, _| {
+                let block_header: &block::HeaderForFreeSize = block_ptr.as_ref();
+                f(
+                    block_ptr.add(BLOCK_FREE_SIZE_HEADER_SIZE),
+                    block_header.block_size(),
+                );
+            });
+    }
+
+    // Blocks on the subheaps and spans are found by the segment status, and large blocks by their table.
+    pub unsafe fn for_each_live_block<F>(&self, mut f: F)
+    where
+        F: FnMut(AnyNonNullPtr, usize, block::Type),
+    {
+        for_each_live_segment_block_by_header(self.header(), &mut f);
+        self.for_each_large_block(|block_ptr, block_size| {
+            f(block_ptr, block_size, block::Type::FreeSize)
+        });
+    }
+
+    pub unsafe fn verify(&self) -> verifier::Report {
+        verifier::verify(self.header())
+    }
+
+    pub unsafe fn dump(&self) -> dump::HeapDump {
+        dump::capture(self.header())
+    }
+
+    pub unsafe fn fragmentation_report(&self) -> fragmentation::FragmentationReport {
+        fragmentation::report(&self.stats())
+    }
+
+    pub unsafe fn leak_report(&self) -> leaks::LeakReport {
+        leaks::report(self)
+    }
+
+    pub unsafe fn release<Env: SysMemEnv>(mut self, env: &mut Env) -> Result<(), Box<dyn Error>> {
+        release_arena(self.header_mut(), env)?;
+
+        let context_space_size = self.header().context_space_size;
+        env.release(self.context_space, context_space_size)
+    }
 }
 
 unsafe fn init_arena<Env: SysMemEnv>(
@@ -165,7 +403,6 @@ unsafe fn init_arena<Env: SysMemEnv>(
 ) -> Result<Arena, Box<dyn Error>> {
     let page_size = env.get_pagesize()?;
 
-    assert!(ALIGNMENT_SIZE >= 4);
     assert!(util::bits::is_aligned(segment::SEGMENT_SIZE, page_size));
     assert!(util::bits::is_aligned(page_size, ALIGNMENT_SIZE));
     assert!(util::bits::is_aligned(
@@ -175,6 +412,8 @@ unsafe fn init_arena<Env: SysMemEnv>(
     assert!(config.min_heap_size < config.max_heap_size);
     assert!(config.max_heap_size - config.min_heap_size >= segment::SEGMENT_SIZE + page_size);
 
+    let arena_header_size_aligned =
+        util::bits::min_aligned_size(ARENA_HEADER_SIZE, segment::COMPACT_HEADER_SIZE);
     let committed_context_space_size = util::bits::min_aligned_size(ARENA_HEADER_SIZE, page_size);
     assert!(config.max_heap_size > committed_context_space_size);
 
@@ -182,65 +421,455 @@ unsafe fn init_arena<Env: SysMemEnv>(
     let reserved_size_for_segments =
         util::bits::max_aligned_size(init_available_size, segment::SEGMENT_SIZE);
     let max_segment_count = reserved_size_for_segments / segment::SEGMENT_SIZE;
+    assert!(max_segment_count <= segment::MAX_SEGMENT_COUNT);
 
     let segment_compact_header_space_size = max_segment_count * segment::COMPACT_HEADER_SIZE;
 
-    let context_space_size = util::bits::min_aligned_size(
-        ARENA_HEADER_SIZE + segment_compact_header_space_size,
+    // The table of large blocks follows the compact headers, and each of the blocks takes a page at least.
+    let large_blocks_table_offset = util::bits::min_aligned_size(
+        arena_header_size_aligned + segment_compact_header_space_size,
         page_size,
     );
+    let large_blocks_capacity = config.max_heap_size / page_size;
+    let context_space_size = large_blocks_table_offset
+        + util::bits::min_aligned_size(
+            large_blocks_capacity * large_blocks_table::ENTRY_SIZE,
+            page_size,
+        );
+    let segment_space_size = max_segment_count * segment::SEGMENT_SIZE;
+    let huge_page_size = if config.use_huge_pages {
+        env.get_huge_page_size()?
+    } else {
+        None
+    };
+    let (reserved_segment_space_size, commit_granularity) = match huge_page_size {
+        // Reserve whole huge pages so that the last segments are also backed by a huge page.
+        Some(huge_page_size) if util::bits::is_aligned(huge_page_size, segment::SEGMENT_SIZE) => (
+            util::bits::min_aligned_size(segment_space_size, huge_page_size),
+            huge_page_size,
+        ),
+        _ => (segment_space_size, segment::SEGMENT_SIZE),
+    };
+
     let mut context_space = env.reserve(context_space_size)?;
+    // Release the context space on failure, since no arena owns it yet.
+    let segment_space = match env
+        .commit(context_space, committed_context_space_size)
+        .and_then(|_| env.reserve_aligned_space(reserved_segment_space_size, commit_granularity))
+    {
+        Ok(segment_space) => segment_space,
+        Err(err) => {
+            env.release(context_space, context_space_size)?;
+            return Err(err);
+        }
+    };
 
-    env.commit(context_space, committed_context_space_size)?;
-    let arena_header_size_aligned =
-        util::bits::min_aligned_size(ARENA_HEADER_SIZE, segment::COMPACT_HEADER_SIZE);
     let committed_segment_compact_header_count =
         (committed_context_space_size - arena_header_size_aligned) / segment::COMPACT_HEADER_SIZE;
 
-    let segment_space_size = max_segment_count * segment::SEGMENT_SIZE;
-    let segment_space = env.reserve_aligned_space(segment_space_size, segment::SEGMENT_SIZE)?;
-
-    let segment_compact_header_space = context_space.add(arena_header_size_aligned);
-    let segment_space_begin = segment_space;
-    let segment_space_end = segment_space_begin.add(segment_space_size);
     *context_space.as_mut() = Header {
+        magic: ARENA_MAGIC,
+        version: ARENA_VERSION,
+        segment_size: segment::SEGMENT_SIZE,
+        class_count: subheap::CLASS_COUNT,
+        max_heap_size: config.max_heap_size,
+        context_space_size,
+        reserved_segment_space_size,
+        poisons_blocks: config.poisons_blocks || config.quarantine_size > 0,
+        large_block_guard_page: config.large_block_guard_page,
         segment_space: segment_space::SegmentSpace::new(
             page_size,
-            segment_compact_header_space,
-            segment_space_begin,
-            segment_space_end,
+            offset_of!(Header, segment_space),
+            arena_header_size_aligned,
+            segment_space.wrapping_offset_from(context_space),
+            segment_space_size,
             init_available_size,
+            commit_granularity,
             committed_segment_compact_header_count,
             0,
         ),
         keep_segments: keep_segments_list::KeepSegmentsList::new(config.keep_segments_count),
-        free_segments_begin: std::ptr::null_mut(),
+        free_segments_begin: None,
+        free_segments_count: 0,
+        free_spans: free_spans_list::FreeSpansList::new(),
+        span_count: 0,
+        span_segments_count: 0,
+        large_blocks_cache: large_blocks_cache::LargeBlocksCache::new(
+            config.large_blocks_cache_size,
+        ),
+        large_blocks: large_blocks_table::LargeBlocksTable::new(
+            large_blocks_table_offset,
+            large_blocks_capacity,
+        ),
+        quarantine: quarantine::Quarantine::new(config.quarantine_size),
         subheaps: array::from_fn(|_| subheap::SubHeap::init()),
     };
 
-    let _ = Arena { context_space };
+    Ok(Arena { context_space })
+}
+
+unsafe fn open_arena<Env: SysMemEnv>(
+    env: &mut Env,
+    context_space: AnyNonNullPtr,
+) -> Result<Arena, Box<dyn Error>> {
+    let page_size = env.get_pagesize()?;
+    let header: &Header = context_space.as_ref();
+
+    // The header holds offsets rather than pointers, so the arena can be opened at any address.
+    let reason = if header.magic != ARENA_MAGIC {
+        Some("no arena header")
+    } else if header.version != ARENA_VERSION {
+        Some("unsupported version")
+    } else if header.segment_size != segment::SEGMENT_SIZE {
+        Some("mismatched segment size")
+    } else if header.class_count != subheap::CLASS_COUNT {
+        Some("mismatched size classes")
+    } else if header.segment_space.page_size != page_size {
+        Some("mismatched page size")
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        return Err(Box::new(error::InvalidArena { reason }));
+    }
+    protect_large_blocks_by_header(header, env)?;
+
+    Ok(Arena { context_space })
+}
+
+fn stats_by_header(header: &Header) -> Stats {
+    let segment_space = &header.segment_space;
+    let large_block_mapped_size = header.large_blocks.total_mapped_size();
+    let cached_large_block_size = header.large_blocks_cache.cached_size();
+
+    Stats {
+        classes: array::from_fn(|class_of_size| {
+            let subheap_cls = &header.subheaps[class_of_size];
+            ClassStats {
+                block_size: subheap::SUBHEAP_SIZE_OF_CLASS[class_of_size],
+                block_count_per_segment: segment::BLOCK_COUNT_OF_CLASS[class_of_size],
+                segment_count: subheap_cls.segment_count,
+                partial_segment_count: subheap_cls.partial_segment_count,
+                used_block_count: subheap_cls.used_block_count,
+            }
+        }),
+        allocated_segment_count: segment_space.allocated_segment_count(),
+        kept_segment_count: header.keep_segments.kept_count(),
+        free_segment_count: header.free_segments_count,
+        free_span_segment_count: header.free_spans.segment_count(),
+        span_count: header.span_count,
+        span_segment_count: header.span_segments_count,
+        large_block_count: header.large_blocks.count(),
+        large_block_mapped_size,
+        cached_large_block_size,
+        quarantined_block_count: header.quarantine.count(),
+        quarantined_size: header.quarantine.size(),
+        reserved_size: header.context_space_size
+            + header.reserved_segment_space_size
+            + large_block_mapped_size
+            + cached_large_block_size,
+        used_budget_size: header.max_heap_size - segment_space.available_size,
+        available_size: segment_space.available_size,
+    }
+}
+
+unsafe fn for_each_live_segment_block_by_header<F>(header: &Header, f: &mut F)
+where
+    F: FnMut(AnyNonNullPtr, usize, block::Type),
+{
+    let segment_space = &header.segment_space;
+    // Quarantined blocks on subheaps are still marked as used.
+    let mut quarantined_addrs = HashSet::new();
+    header
+        .quarantine
+        .for_each(context_space_by_header(header), |block_ptr, _| {
+            _ = quarantined_addrs.insert(block_ptr.as_addr())
+        });
+
+    let mut index = 0;
+    while index < segment_space.allocated_segment_count() {
+        let seg = segment_space.segment_by_index(segment::SegmentIndex::new(index));
+        match seg.status() {
+            segment::Status::SubHeap => {
+                let block_size = seg.block_size();
+                seg.for_each_used_block(|block_index| {
+                    let block_ptr = seg.block_ptr(block_index);
+                    if !quarantined_addrs.contains(&block_ptr.as_addr()) {
+                        f(block_ptr, block_size, block::Type::OnSubHeap)
+                    }
+                });
+                index += 1;
+            }
+            segment::Status::Span => {
+                let segment_count = seg.span_segment_count();
+                let block_ptr = seg.span_block_ptr();
+                let block_size = segment_count * segment::SEGMENT_SIZE
+                    - block_ptr.offset_bytes_from(seg.seg_ptr()) as usize;
+                f(block_ptr, block_size, block::Type::OnSpan);
+                index += segment_count;
+            }
+            // The following segments of spans have no header, so they are skipped by the count.
+            segment::Status::FreeSpan => index += seg.free_span().0,
+            segment::Status::Free | segment::Status::Kept | segment::Status::InSpan => index += 1,
+        }
+    }
+}
+
+unsafe fn release_arena<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+) -> Result<(), Box<dyn Error>> {
+    let page_size = header.segment_space.page_size;
+    let context_space = context_space_by_header(header);
+    let mut quarantined_mappings = Vec::new();
+    header.quarantine.for_each(context_space, |_, mapping| {
+        quarantined_mappings.extend(mapping)
+    });
+    for (mapping_ptr, mapped_size) in quarantined_mappings {
+        env.release(mapping_ptr, mapped_size)?;
+    }
+    while let Some((block_ptr, mapped_size)) = header.large_blocks.last(context_space) {
+        let (mapping_ptr, _) = large_block_mapping(block_ptr, page_size);
+        env.release(mapping_ptr, mapped_size)?;
+        header
+            .large_blocks
+            .remove(context_space, block_ptr.as_addr());
+    }
+    while let Some((block_ptr, mapped_size)) = header.large_blocks_cache.pop(context_space) {
+        env.release(block_ptr, mapped_size)?;
+    }
 
-    todo!()
+    env.release(
+        header.segment_space.space_begin(),
+        header.reserved_segment_space_size,
+    )
 }
 
 const BLOCK_FREE_SIZE_HEADER_SIZE: usize = size_of::<block::HeaderForFreeSize>();
+
+// The header is at the begin of the context space.
+#[inline]
+fn context_space_by_header(header: &Header) -> AnyNonNullPtr {
+    AnyNonNullPtr::new(NonNull::from(header))
+}
+
+// An entry is reserved before mapping a block, so that the block is always registered once mapped.
+unsafe fn reserve_large_block_entry_by_header<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+) -> Result<bool, Box<dyn Error>> {
+    let context_space = context_space_by_header(header);
+    header.large_blocks.reserve_entry(
+        env,
+        context_space,
+        header.segment_space.page_size,
+        &mut header.segment_space.available_size,
+    )
+}
+
+// Returns the begin of the mapping and the length of it, which are derived from the block size and flags.
+#[inline]
+unsafe fn large_block_mapping(
+    block_ptr: AnyNonNullPtr,
+    page_size: usize,
+) -> (AnyNonNullPtr, usize) {
+    let block_header: &block::HeaderForFreeSize = block_ptr.as_ref();
+
+    let mapping_ptr = if block_header.has_flags(block::FLAG_GUARD_PAGE_BEFORE) {
+        block_ptr.sub(page_size)
+    } else if block_header.has_flags(block::FLAG_ALIGNED_OFFSET) {
+        block_ptr.sub(block_ptr.as_addr() % page_size)
+    } else {
+        block_ptr
+    };
+    let mut mapping_end_addr = util::bits::min_aligned_size(
+        block_ptr.as_addr() + BLOCK_FREE_SIZE_HEADER_SIZE + block_header.block_size(),
+        page_size,
+    );
+    if block_header.has_flags(block::FLAG_GUARD_PAGE) {
+        mapping_end_addr += page_size;
+    }
+
+    (mapping_ptr, mapping_end_addr - mapping_ptr.as_addr())
+}
+
 unsafe fn alloc_block_free_size_by_header<Env: SysMemEnv>(
     header: &mut Header,
     env: &mut Env,
     block_size: usize,
 ) -> Result<Option<AnyNonNullPtr>, Box<dyn Error>> {
+    let block_size = util::bits::min_aligned_size(block_size, ALIGNMENT_SIZE);
     let allocate_size = util::bits::min_aligned_size(
         BLOCK_FREE_SIZE_HEADER_SIZE + block_size,
         header.segment_space.page_size,
     );
 
+    match header.large_block_guard_page {
+        GuardPage::None => {}
+        guard_page => {
+            return alloc_block_free_size_guarded_by_header(
+                header,
+                env,
+                block_size,
+                allocate_size,
+                guard_page,
+            )
+        }
+    }
+
+    if !reserve_large_block_entry_by_header(header, env)? {
+        return Ok(None);
+    }
+    let context_space = context_space_by_header(header);
+
+    if let Some((block_ptr, state)) = header.large_blocks_cache.take(context_space, allocate_size) {
+        let page_size = header.segment_space.page_size;
+        if allocate_size > page_size {
+            if let Err(err) =
+                env.recommit(block_ptr.add(page_size), allocate_size - page_size, state)
+            {
+                header
+                    .large_blocks_cache
+                    .insert(context_space, block_ptr, allocate_size, state);
+                return Err(err);
+            }
+        }
+        block::HeaderForFreeSize::init(block_ptr.as_nonnull(), block_size, 0);
+        header
+            .large_blocks
+            .insert(context_space, block_ptr, allocate_size);
+
+        return Ok(Some(block_ptr.add(BLOCK_FREE_SIZE_HEADER_SIZE)));
+    }
+
     if header.segment_space.available_size < allocate_size {
         return Ok(None);
     }
 
     let block_ptr = env.alloc(allocate_size)?;
     header.segment_space.available_size -= allocate_size;
-    block::HeaderForFreeSize::init(block_ptr.as_nonnull(), block_size);
+    block::HeaderForFreeSize::init(block_ptr.as_nonnull(), block_size, 0);
+    header
+        .large_blocks
+        .insert(context_space, block_ptr, allocate_size);
+
+    Ok(Some(block_ptr.add(BLOCK_FREE_SIZE_HEADER_SIZE)))
+}
+
+// Guarded blocks are always mapped newly, since the cache keeps mappings without guard pages.
+unsafe fn alloc_block_free_size_guarded_by_header<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+    block_size: usize,
+    allocate_size: usize,
+    guard_page: GuardPage,
+) -> Result<Option<AnyNonNullPtr>, Box<dyn Error>> {
+    // Blocks end at the guard page after them, so the aligned size keeps their begin aligned.
+    assert!(util::bits::is_aligned(block_size, ALIGNMENT_SIZE));
+
+    let page_size = header.segment_space.page_size;
+    let mapped_size = allocate_size + page_size;
+    if !reserve_large_block_entry_by_header(header, env)?
+        || header.segment_space.available_size < mapped_size
+    {
+        return Ok(None);
+    }
+
+    let mapping_ptr = env.alloc(mapped_size)?;
+    let (guard_ptr, block_ptr, flags) = if guard_page == GuardPage::Before {
+        (
+            mapping_ptr,
+            mapping_ptr.add(page_size),
+            block::FLAG_GUARD_PAGE_BEFORE,
+        )
+    } else {
+        let guard_ptr = mapping_ptr.add(allocate_size);
+        let block_ptr = guard_ptr.sub(BLOCK_FREE_SIZE_HEADER_SIZE + block_size);
+        let flags = if block_ptr == mapping_ptr {
+            block::FLAG_GUARD_PAGE
+        } else {
+            block::FLAG_GUARD_PAGE | block::FLAG_ALIGNED_OFFSET
+        };
+        (guard_ptr, block_ptr, flags)
+    };
+    if let Err(err) = env.hard_decommit(guard_ptr, page_size) {
+        env.release(mapping_ptr, mapped_size)?;
+        return Err(err);
+    }
+    header.segment_space.available_size -= mapped_size;
+
+    block::HeaderForFreeSize::init(block_ptr.as_nonnull(), block_size, flags);
+    let context_space = context_space_by_header(header);
+    header
+        .large_blocks
+        .insert(context_space, block_ptr, mapped_size);
+
+    Ok(Some(block_ptr.add(BLOCK_FREE_SIZE_HEADER_SIZE)))
+}
+
+unsafe fn alloc_block_free_size_aligned_by_header<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+    block_size: usize,
+    alignment_size: usize,
+) -> Result<Option<AnyNonNullPtr>, Box<dyn Error>> {
+    assert!(util::bits::is_power_of_2(alignment_size));
+
+    let page_size = header.segment_space.page_size;
+    let block_size = util::bits::min_aligned_size(block_size, ALIGNMENT_SIZE);
+
+    // Map an extra space to find an aligned address, and trim the rest.
+    let attempt_size = util::bits::min_aligned_size(
+        BLOCK_FREE_SIZE_HEADER_SIZE + block_size + alignment_size,
+        page_size,
+    );
+    if !reserve_large_block_entry_by_header(header, env)?
+        || header.segment_space.available_size < attempt_size
+    {
+        return Ok(None);
+    }
+
+    let attempt_ptr = env.alloc(attempt_size)?;
+    let attempt_end_addr = attempt_ptr.as_addr() + attempt_size;
+    let ptr_addr = util::bits::min_aligned_size(
+        attempt_ptr.as_addr() + BLOCK_FREE_SIZE_HEADER_SIZE,
+        alignment_size,
+    );
+    let block_ptr = attempt_ptr.add(ptr_addr - BLOCK_FREE_SIZE_HEADER_SIZE - attempt_ptr.as_addr());
+    let mapping_addr = util::bits::max_aligned_size(block_ptr.as_addr(), page_size);
+    let mapping_end_addr = util::bits::min_aligned_size(ptr_addr + block_size, page_size);
+
+    // The rest of the attempt is released on failure, not to leak it.
+    if attempt_ptr.as_addr() < mapping_addr {
+        if let Err(err) = env.release(attempt_ptr, mapping_addr - attempt_ptr.as_addr()) {
+            env.release(attempt_ptr, attempt_size)?;
+            return Err(err);
+        }
+    }
+    if mapping_end_addr < attempt_end_addr {
+        if let Err(err) = env.release(
+            attempt_ptr.add(mapping_end_addr - attempt_ptr.as_addr()),
+            attempt_end_addr - mapping_end_addr,
+        ) {
+            let mapping_ptr = attempt_ptr.add(mapping_addr - attempt_ptr.as_addr());
+            env.release(mapping_ptr, attempt_end_addr - mapping_addr)?;
+            return Err(err);
+        }
+    }
+
+    let mapped_size = mapping_end_addr - mapping_addr;
+    header.segment_space.available_size -= mapped_size;
+
+    let flags = if block_ptr.as_addr() == mapping_addr {
+        0
+    } else {
+        block::FLAG_ALIGNED_OFFSET
+    };
+    block::HeaderForFreeSize::init(block_ptr.as_nonnull(), block_size, flags);
+    let context_space = context_space_by_header(header);
+    header
+        .large_blocks
+        .insert(context_space, block_ptr, mapped_size);
 
     Ok(Some(block_ptr.add(BLOCK_FREE_SIZE_HEADER_SIZE)))
 }
@@ -250,24 +879,302 @@ unsafe fn free_block_free_size_by_header<Env: SysMemEnv>(
     env: &mut Env,
     ptr: AnyNonNullPtr,
 ) -> Result<(), Box<dyn Error>> {
+    let context_space = context_space_by_header(header);
+    let block_addr = ptr.as_addr().wrapping_sub(BLOCK_FREE_SIZE_HEADER_SIZE);
+    let mapped_size = match header.large_blocks.get(context_space, block_addr) {
+        Some(mapped_size) => mapped_size,
+        None => return Err(invalid_large_block_to_free(header, ptr)),
+    };
+
+    // The block stays live on failure, so that it can be freed again.
     let block_ptr = ptr.sub(BLOCK_FREE_SIZE_HEADER_SIZE);
-    let block_whole_size = {
-        let block_header: &block::HeaderForFreeSize = block_ptr.as_ref();
-        BLOCK_FREE_SIZE_HEADER_SIZE + block_header.block_size()
+    let page_size = header.segment_space.page_size;
+    let (mapping_ptr, derived_mapped_size) = large_block_mapping(block_ptr, page_size);
+    assert_eq!(derived_mapped_size, mapped_size);
+
+    let block_header: &block::HeaderForFreeSize = block_ptr.as_ref();
+    // Mappings with guard pages cannot be reused as they are.
+    if header.large_blocks_cache.can_cache(mapped_size)
+        && block_header.flags() & (block::FLAG_GUARD_PAGE | block::FLAG_GUARD_PAGE_BEFORE) == 0
+    {
+        while let Some((flooded_block_ptr, flooded_mapped_size, flooded_state)) = header
+            .large_blocks_cache
+            .pop_flooded(context_space, mapped_size)
+        {
+            if let Err(err) = env.release(flooded_block_ptr, flooded_mapped_size) {
+                header.large_blocks_cache.insert(
+                    context_space,
+                    flooded_block_ptr,
+                    flooded_mapped_size,
+                    flooded_state,
+                );
+                return Err(err);
+            }
+            header.segment_space.available_size += flooded_mapped_size;
+        }
+
+        // Keep the first page, which has the header even at an aligned offset, and decommit the rest.
+        let state = if mapped_size > page_size {
+            env.soft_decommit(mapping_ptr.add(page_size), mapped_size - page_size)?
+        } else {
+            CommitState::Committed
+        };
+        header.large_blocks.remove(context_space, block_addr);
+        header
+            .large_blocks_cache
+            .insert(context_space, mapping_ptr, mapped_size, state);
+
+        return Ok(());
+    }
+
+    env.release(mapping_ptr, mapped_size)?;
+    header.large_blocks.remove(context_space, block_addr);
+    header.segment_space.available_size += mapped_size;
+
+    Ok(())
+}
+
+unsafe fn invalid_large_block_to_free(header: &Header, ptr: AnyNonNullPtr) -> Box<dyn Error> {
+    let block_addr = ptr.as_addr().wrapping_sub(BLOCK_FREE_SIZE_HEADER_SIZE);
+    // The mapping begins at the block, or at the page of it for aligned offsets.
+    let aligned_offset_mapping_addr =
+        util::bits::max_aligned_size(block_addr, header.segment_space.page_size);
+    let context_space = context_space_by_header(header);
+    let reason = if header
+        .large_blocks_cache
+        .contains(context_space, block_addr)
+        || header
+            .large_blocks_cache
+            .contains(context_space, aligned_offset_mapping_addr)
+        || header.quarantine.contains(context_space, ptr.as_addr())
+    {
+        "already freed"
+    } else {
+        "not allocated by the arena"
     };
+    Box::new(error::InvalidPointer {
+        addr: ptr.as_addr(),
+        reason,
+    })
+}
+
+// The whole mapping is decommitted to fault on access, including the header.
+unsafe fn quarantine_block_free_size_by_header<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+    ptr: AnyNonNullPtr,
+) -> Result<(), Box<dyn Error>> {
+    let context_space = context_space_by_header(header);
+    let block_addr = ptr.as_addr().wrapping_sub(BLOCK_FREE_SIZE_HEADER_SIZE);
+    if header.large_blocks.get(context_space, block_addr).is_none() {
+        return Err(invalid_large_block_to_free(header, ptr));
+    }
 
-    env.release(block_ptr, block_whole_size)?;
-    header.segment_space.available_size += block_whole_size;
+    // The header is read before the protection, and never after it.
+    let block_ptr = ptr.sub(BLOCK_FREE_SIZE_HEADER_SIZE);
+    let block_header: &block::HeaderForFreeSize = block_ptr.as_ref();
+    let block_size = block_header.block_size();
+    let (mapping_ptr, mapped_size) = large_block_mapping(block_ptr, header.segment_space.page_size);
+    env.hard_decommit(mapping_ptr, mapped_size)?;
+
+    header.large_blocks.remove(context_space, block_addr);
+    header.quarantine.push(
+        context_space,
+        ptr,
+        block_size,
+        Some((mapping_ptr, mapped_size)),
+    );
+
+    Ok(())
+}
+
+// Protections may be lost across mappings, e.g. of a file, so they are applied again on opening.
+unsafe fn protect_large_blocks_by_header<Env: SysMemEnv>(
+    header: &Header,
+    env: &mut Env,
+) -> Result<(), Box<dyn Error>> {
+    let page_size = header.segment_space.page_size;
+    let mut large_blocks = Vec::with_capacity(header.large_blocks.count());
+    header
+        .large_blocks
+        .for_each(context_space_by_header(header), |block_ptr, mapped_size| {
+            large_blocks.push((block_ptr, mapped_size))
+        });
+    for (block_ptr, mapped_size) in large_blocks {
+        let block_header: &block::HeaderForFreeSize = block_ptr.as_ref();
+        let (mapping_ptr, _) = large_block_mapping(block_ptr, page_size);
+        if block_header.has_flags(block::FLAG_GUARD_PAGE_BEFORE) {
+            env.hard_decommit(mapping_ptr, page_size)?;
+        } else if block_header.has_flags(block::FLAG_GUARD_PAGE) {
+            env.hard_decommit(mapping_ptr.add(mapped_size - page_size), page_size)?;
+        }
+    }
 
+    let mut quarantined_mappings = Vec::new();
+    header
+        .quarantine
+        .for_each(context_space_by_header(header), |_, mapping| {
+            quarantined_mappings.extend(mapping)
+        });
+    for (mapping_ptr, mapped_size) in quarantined_mappings {
+        env.hard_decommit(mapping_ptr, mapped_size)?;
+    }
     Ok(())
 }
 
+unsafe fn pop_quarantined_block_by_header<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+    flushes: bool,
+) -> Result<Option<AnyNonNullPtr>, Box<dyn Error>> {
+    let context_space = context_space_by_header(header);
+    while flushes || header.quarantine.is_over() {
+        let (mapping_ptr, mapped_size) = match header.quarantine.first(context_space) {
+            Some((_, Some(mapping))) => mapping,
+            Some((_, None)) => return Ok(Some(header.quarantine.pop_first(context_space))),
+            None => return Ok(None),
+        };
+
+        // The block is popped after the release, so that it stays quarantined on failure.
+        env.release(mapping_ptr, mapped_size)?;
+        header.segment_space.available_size += mapped_size;
+        header.quarantine.pop_first(context_space);
+    }
+    Ok(None)
+}
+
+unsafe fn alloc_block_span_by_header<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+    block_size: usize,
+) -> Result<Option<AnyNonNullPtr>, Box<dyn Error>> {
+    assert!(block_size <= segment::MAX_SPAN_BLOCK_SIZE);
+
+    let segment_count = segment::Segment::span_segment_count_of_size(block_size);
+    let mut free_span = take_free_span_by_header(header, env, segment_count)?;
+    if free_span.is_none() && header.free_segments_begin.is_some() {
+        move_free_segments_to_free_spans_by_header(header);
+        free_span = take_free_span_by_header(header, env, segment_count)?;
+    }
+    let mut seg = match free_span {
+        Some(seg) => seg,
+        None => match header
+            .segment_space
+            .alloc_new_segments(env, segment_count)?
+        {
+            Some(seg) => seg,
+            // Kept segments are the last resort, since they are kept for subheaps.
+            None => {
+                move_kept_segments_to_free_spans_by_header(header);
+                match take_free_span_by_header(header, env, segment_count)? {
+                    Some(seg) => seg,
+                    None => return Ok(None),
+                }
+            }
+        },
+    };
+    seg.init_span(segment_count);
+    header.span_count += 1;
+    header.span_segments_count += segment_count;
+
+    Ok(Some(seg.span_block_ptr()))
+}
+
+#[cfg(feature = "debug-checks")]
+unsafe fn check_block_to_free_by_header(
+    header: &mut Header,
+    ptr: AnyNonNullPtr,
+) -> Result<(), Box<dyn Error>> {
+    let reason = if header
+        .quarantine
+        .contains(context_space_by_header(header), ptr.as_addr())
+    {
+        Some("already freed")
+    } else {
+        invalid_reason_of_block_to_free(&mut header.segment_space, ptr)
+    };
+    match reason {
+        None => Ok(()),
+        Some(reason) => Err(Box::new(error::InvalidPointer {
+            addr: ptr.as_addr(),
+            reason,
+        })),
+    }
+}
+
+#[cfg(feature = "debug-checks")]
+unsafe fn invalid_reason_of_block_to_free(
+    segment_space: &mut segment_space::SegmentSpace,
+    ptr: AnyNonNullPtr,
+) -> Option<&'static str> {
+    if !segment_space.ptr_in_space(ptr) {
+        return None;
+    }
+
+    let seg_index =
+        ptr.offset_bytes_from(segment_space.space_begin()) as usize / segment::SEGMENT_SIZE;
+    if seg_index >= segment_space.allocated_segment_count() {
+        return Some("not allocated by the arena");
+    }
+
+    let seg = segment_space.segment_by_inner_ptr(ptr);
+    match seg.status() {
+        segment::Status::SubHeap => {
+            if ptr < seg.block_ptr(0) {
+                return Some("not at a block boundary");
+            }
+            let (seg, block_index) = segment::Segment::from_block_ptr(segment_space, ptr);
+            if block_index >= segment::BLOCK_COUNT_OF_CLASS[seg.subheap_class()]
+                || seg.block_ptr(block_index) != ptr
+            {
+                Some("not at a block boundary")
+            } else if !seg.is_block_used(block_index) {
+                Some("already freed")
+            } else {
+                None
+            }
+        }
+        segment::Status::Span if seg.span_block_ptr() == ptr => None,
+        segment::Status::Span | segment::Status::InSpan => Some("not at the begin of a span"),
+        segment::Status::Free | segment::Status::Kept | segment::Status::FreeSpan => {
+            Some("already freed")
+        }
+    }
+}
+
+unsafe fn free_block_span_by_header<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+    ptr: AnyNonNullPtr,
+) -> Result<(), Box<dyn Error>> {
+    let mut seg = header.segment_space.segment_by_inner_ptr(ptr);
+    assert!(seg.span_block_ptr() == ptr);
+
+    let segment_count = seg.span_segment_count();
+    header.span_count -= 1;
+    header.span_segments_count -= segment_count;
+    // The segments are freed even on failure, as decommitted in part.
+    let result = header
+        .segment_space
+        .decommit_segments(env, seg, segment_count);
+    let state = match result {
+        Ok(state) => state,
+        Err(_) => CommitState::SoftDecommitted,
+    };
+    header
+        .free_spans
+        .insert(&mut header.segment_space, &mut seg, segment_count, state);
+
+    result.map(|_| ())
+}
+
 unsafe fn free_unused_segment_by_header<Env: SysMemEnv>(
     header: &mut Header,
     env: &mut Env,
     floated_seg: &mut segment::Segment,
 ) -> Result<(), Box<dyn Error>> {
-    let seg = match header
+    floated_seg.set_status(segment::Status::Kept);
+    let mut seg = match header
         .keep_segments
         .insert_and_return_flooded(&mut header.segment_space, floated_seg)
     {
@@ -275,28 +1182,60 @@ unsafe fn free_unused_segment_by_header<Env: SysMemEnv>(
         Some(flooded_seg) => flooded_seg,
     };
 
-    if header.segment_space.is_last_segment(seg) {
-        todo!()
-    }
-
-    env.soft_decommit(seg.seg_ptr(), segment::SEGMENT_SIZE)?;
-    let mut seg_compact_header = seg.compact_header;
-    match NonNull::new(header.free_segments_begin) {
+    // The segment is freed even on failure, as decommitted in part.
+    let result = header.segment_space.decommit_segment(env, seg);
+    let commit_state = match result {
+        Ok(state) => state,
+        Err(_) => CommitState::SoftDecommitted,
+    };
+    seg.set_status(segment::Status::Free);
+    seg.set_commit_state(commit_state);
+    header.free_segments_count += 1;
+    match header.free_segments_begin {
         None => {
-            seg_compact_header.as_mut().next = std::ptr::null_mut();
-            header.free_segments_begin = seg_compact_header.as_ptr();
+            seg.set_next(None);
+            header.free_segments_begin = Some(seg.index);
         }
-        Some(mut free_segments_begin_ptr) => {
-            if seg_compact_header.as_ptr() < header.free_segments_begin {
-                seg_compact_header.as_mut().next = header.free_segments_begin;
-                header.free_segments_begin = seg_compact_header.as_ptr();
+        Some(free_segments_begin_index) => {
+            if seg.index < free_segments_begin_index {
+                seg.set_next(Some(free_segments_begin_index));
+                header.free_segments_begin = Some(seg.index);
             } else {
-                seg_compact_header.as_mut().next = free_segments_begin_ptr.as_mut().next;
-                free_segments_begin_ptr.as_mut().next = seg_compact_header.as_ptr();
+                let mut free_segments_begin = header
+                    .segment_space
+                    .segment_by_index(free_segments_begin_index);
+                seg.set_next(free_segments_begin.next());
+                free_segments_begin.set_next(Some(seg.index));
             }
         }
     }
-    Ok(())
+    result.map(|_| ())
+}
+
+// Free single segments are merged into free spans, so that spans can be allocated on them.
+unsafe fn move_free_segments_to_free_spans_by_header(header: &mut Header) {
+    while let Some(free_seg_index) = header.free_segments_begin {
+        let mut seg = header.segment_space.segment_by_index(free_seg_index);
+        header.free_segments_begin = seg.next();
+        header.free_segments_count -= 1;
+
+        let state = seg.commit_state();
+        header
+            .free_spans
+            .insert(&mut header.segment_space, &mut seg, 1, state);
+    }
+}
+
+unsafe fn move_kept_segments_to_free_spans_by_header(header: &mut Header) {
+    while let Some(kept_seg_index) = header.keep_segments.pop(&mut header.segment_space) {
+        let mut seg = header.segment_space.segment_by_index(kept_seg_index);
+        header.free_spans.insert(
+            &mut header.segment_space,
+            &mut seg,
+            1,
+            CommitState::Committed,
+        );
+    }
 }
 
 unsafe fn pop_free_segment_by_header<Env: SysMemEnv>(
@@ -308,28 +1247,56 @@ unsafe fn pop_free_segment_by_header<Env: SysMemEnv>(
         None => {
             // continue
         }
-        Some(free_seg_header_ptr) => {
-            return Ok(Some(segment_space.segment_by_cmp_header(free_seg_header_ptr)));
+        Some(free_seg_index) => {
+            return Ok(Some(segment_space.segment_by_index(free_seg_index)));
         }
     }
 
-    match NonNull::new(header.free_segments_begin) {
+    match header.free_segments_begin {
         None => {
             // continue
         }
-        Some(free_seg_header_ptr) => {
-            let seg_compact_header = free_seg_header_ptr.as_ref();
+        Some(free_seg_index) => {
+            let segment = header.segment_space.segment_by_index(free_seg_index);
 
-            header.free_segments_begin = seg_compact_header.next;
+            // Recommit first, so that the segment stays free on failure.
+            env.recommit(
+                segment.seg_ptr(),
+                segment::SEGMENT_SIZE,
+                segment.commit_state(),
+            )?;
 
-            let segment = header.segment_space.segment_by_cmp_header(free_seg_header_ptr);
-            env.force_commit(segment.seg_ptr(), segment::SEGMENT_SIZE)?;
+            header.free_segments_begin = segment.next();
+            header.free_segments_count -= 1;
 
             return Ok(Some(segment));
         }
     }
 
-    Ok(None)
+    take_free_span_by_header(header, env, 1)
+}
+
+// A taken span is inserted back if it fails to be recommitted.
+unsafe fn take_free_span_by_header<Env: SysMemEnv>(
+    header: &mut Header,
+    env: &mut Env,
+    segment_count: usize,
+) -> Result<Option<segment::Segment>, Box<dyn Error>> {
+    let (mut seg, state) = match header
+        .free_spans
+        .take(&mut header.segment_space, segment_count)
+    {
+        None => return Ok(None),
+        Some(taken) => taken,
+    };
+
+    if let Err(err) = env.recommit(seg.seg_ptr(), segment_count * segment::SEGMENT_SIZE, state) {
+        header
+            .free_spans
+            .insert(&mut header.segment_space, &mut seg, segment_count, state);
+        return Err(err);
+    }
+    Ok(Some(seg))
 }
 
 unsafe fn insert_free_segment_to_subheap_by_header(
@@ -339,59 +1306,84 @@ unsafe fn insert_free_segment_to_subheap_by_header(
 ) {
     let segment_space = &mut header.segment_space;
     let subheap_cls = &mut header.subheaps[class_of_size];
-    let seg_ptr = floated_seg.compact_header.as_ptr();
-    match NonNull::new(subheap_cls.free_segments_begin) {
+    let seg_index = floated_seg.index;
+    subheap_cls.partial_segment_count += 1;
+    match subheap_cls.free_segments_begin {
         None => {
-            subheap_cls.free_segments_begin = seg_ptr;
-            subheap_cls.free_segments_end = seg_ptr;
+            subheap_cls.free_segments_begin = Some(seg_index);
+            subheap_cls.free_segments_end = Some(seg_index);
         }
-        Some(free_segments_begin_ptr) => {
-            let mut free_segments_begin = segment_space.segment_by_cmp_header(free_segments_begin_ptr);
-            if seg_ptr < free_segments_begin_ptr.as_ptr() {
+        Some(free_segments_begin_index) => {
+            let mut free_segments_begin = segment_space.segment_by_index(free_segments_begin_index);
+            if seg_index < free_segments_begin_index {
                 floated_seg.append(&mut free_segments_begin);
-                subheap_cls.free_segments_begin = seg_ptr;
+                subheap_cls.free_segments_begin = Some(seg_index);
             } else {
-                let free_segments_end_ptr = NonNull::new_unchecked(subheap_cls.free_segments_end);
-                let mut free_segments_end = segment_space.segment_by_cmp_header(free_segments_end_ptr);
-                free_segments_end.append(floated_seg);
-                subheap_cls.free_segments_end = seg_ptr;
+                // Keep the list sorted by addresses, so that lower segments are reused first.
+                let free_segments_end_index = subheap_cls.free_segments_end.unwrap_unchecked();
+                let mut prev_seg = segment_space.segment_by_index(free_segments_end_index);
+                while seg_index < prev_seg.index {
+                    prev_seg = segment_space.segment_by_index(prev_seg.prev().unwrap_unchecked());
+                }
+                match prev_seg.next() {
+                    Some(next_index) => {
+                        let mut next_seg = segment_space.segment_by_index(next_index);
+                        floated_seg.set_prev(Some(prev_seg.index));
+                        floated_seg.set_next(Some(next_index));
+                        prev_seg.set_next(Some(seg_index));
+                        next_seg.set_prev(Some(seg_index));
+                    }
+                    None => {
+                        prev_seg.append(floated_seg);
+                        subheap_cls.free_segments_end = Some(seg_index);
+                    }
+                }
             }
         }
     }
 }
 
+// A single segment of the list is floated too.
+unsafe fn is_segment_in_subheap_by_header(
+    header: &Header,
+    class_of_size: usize,
+    seg: &segment::Segment,
+) -> bool {
+    !seg.is_floated() || header.subheaps[class_of_size].free_segments_begin == Some(seg.index)
+}
+
 unsafe fn remove_segment_from_subheap_by_header(
     header: &mut Header,
     class_of_size: usize,
     seg: &mut segment::Segment,
 ) {
-    let subheap_cls = &mut header.subheaps[class_of_size];
+    assert!(is_segment_in_subheap_by_header(header, class_of_size, seg));
 
-    assert!(!subheap_cls.free_segments_begin.is_null());
-    assert!(!subheap_cls.free_segments_end.is_null());
+    let subheap_cls = &mut header.subheaps[class_of_size];
+    subheap_cls.partial_segment_count -= 1;
 
     let segment_space = &mut header.segment_space;
-    let seg_ptr = seg.compact_header.as_ptr();
+    let seg_index = Some(seg.index);
 
-    if seg_ptr == subheap_cls.free_segments_begin {
+    if seg_index == subheap_cls.free_segments_begin {
         subheap_cls.free_segments_begin = seg.next();
     }
-    if seg_ptr == subheap_cls.free_segments_end {
+    if seg_index == subheap_cls.free_segments_end {
         subheap_cls.free_segments_end = seg.prev();
     }
 
-    match NonNull::new(seg.next()) {
-        Some(seg_next_ptr) => {
-            let mut seg_next = segment_space.segment_by_cmp_header(seg_next_ptr);
+    match seg.next() {
+        Some(seg_next_index) => {
+            let mut seg_next = segment_space.segment_by_index(seg_next_index);
             seg_next.set_prev(seg.prev());
         }
         None => {
             // do nothing
         }
     }
-    match NonNull::new(seg.prev()) {
-        Some(seg_prev_ptr) => {
-            let mut seg_prev = segment_space.segment_by_cmp_header(seg_prev_ptr);
+    match seg.prev() {
+        Some(seg_prev_index) => {
+            let mut seg_prev = segment_space.segment_by_index(seg_prev_index);
             seg_prev.set_next(seg.next());
         }
         None => {
@@ -399,6 +1391,6 @@ unsafe fn remove_segment_from_subheap_by_header(
         }
     }
 
-    seg.set_prev(std::ptr::null_mut());
-    seg.set_next(std::ptr::null_mut());
+    seg.set_prev(None);
+    seg.set_next(None);
 }
@@ -0,0 +1,140 @@
+use crate::sys::ptr::AnyNonNullPtr;
+
+const QUARANTINE_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Entry {
+    // Wrapping from the context space, like the large blocks table.
+    block_offset: usize,
+    block_size: usize,
+    mapping_offset: usize,
+    // Zero for blocks on subheaps, which have no mapping of their own.
+    mapped_size: usize,
+}
+
+// Freed blocks are released in the FIFO order once their total size is over the max size, or the ring is full.
+// Entries are kept in the ring out of blocks, so that writes after free never break the quarantine,
+// and whole mappings of large blocks can be protected, including their headers.
+#[derive(Debug)]
+pub struct Quarantine {
+    max_size: usize,
+    size: usize,
+    count: usize,
+    begin: usize,
+    entries: [Entry; QUARANTINE_CAPACITY],
+}
+
+impl Quarantine {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            size: 0,
+            count: 0,
+            begin: 0,
+            entries: [Entry::default(); QUARANTINE_CAPACITY],
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    #[inline]
+    pub fn is_over(&self) -> bool {
+        self.size > self.max_size || self.count == QUARANTINE_CAPACITY
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    // Returns the block, and the mapping of it for large blocks.
+    #[inline]
+    pub unsafe fn first(
+        &self,
+        context_space: AnyNonNullPtr,
+    ) -> Option<(AnyNonNullPtr, Option<(AnyNonNullPtr, usize)>)> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(entry_ptrs(context_space, &self.entries[self.begin]))
+    }
+
+    // The mapping is given for large blocks, and none for blocks on subheaps.
+    pub fn push(
+        &mut self,
+        context_space: AnyNonNullPtr,
+        block_ptr: AnyNonNullPtr,
+        block_size: usize,
+        mapping: Option<(AnyNonNullPtr, usize)>,
+    ) {
+        assert!(self.count < QUARANTINE_CAPACITY);
+
+        let (mapping_offset, mapped_size) = match mapping {
+            Some((mapping_ptr, mapped_size)) => {
+                (mapping_ptr.wrapping_offset_from(context_space), mapped_size)
+            }
+            None => (0, 0),
+        };
+        self.entries[(self.begin + self.count) % QUARANTINE_CAPACITY] = Entry {
+            block_offset: block_ptr.wrapping_offset_from(context_space),
+            block_size,
+            mapping_offset,
+            mapped_size,
+        };
+        self.size += block_size;
+        self.count += 1;
+    }
+
+    pub unsafe fn pop_first(&mut self, context_space: AnyNonNullPtr) -> AnyNonNullPtr {
+        if self.count == 0 {
+            panic!("unreachable: popped from the empty quarantine.");
+        }
+
+        let entry = self.entries[self.begin];
+        self.begin = (self.begin + 1) % QUARANTINE_CAPACITY;
+        self.size -= entry.block_size;
+        self.count -= 1;
+        context_space.wrapping_add(entry.block_offset)
+    }
+
+    pub unsafe fn for_each<F>(&self, context_space: AnyNonNullPtr, mut f: F)
+    where
+        F: FnMut(AnyNonNullPtr, Option<(AnyNonNullPtr, usize)>),
+    {
+        for offset in 0..self.count {
+            let (block_ptr, mapping) = entry_ptrs(
+                context_space,
+                &self.entries[(self.begin + offset) % QUARANTINE_CAPACITY],
+            );
+            f(block_ptr, mapping);
+        }
+    }
+
+    pub unsafe fn contains(&self, context_space: AnyNonNullPtr, block_addr: usize) -> bool {
+        let mut contained = false;
+        self.for_each(context_space, |block_ptr, _| {
+            contained |= block_ptr.as_addr() == block_addr
+        });
+        contained
+    }
+}
+
+unsafe fn entry_ptrs(
+    context_space: AnyNonNullPtr,
+    entry: &Entry,
+) -> (AnyNonNullPtr, Option<(AnyNonNullPtr, usize)>) {
+    let block_ptr = context_space.wrapping_add(entry.block_offset);
+    if entry.mapped_size == 0 {
+        return (block_ptr, None);
+    }
+    let mapping_ptr = context_space.wrapping_add(entry.mapping_offset);
+    (block_ptr, Some((mapping_ptr, entry.mapped_size)))
+}
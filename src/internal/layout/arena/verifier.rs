@@ -0,0 +1,563 @@
+use crate::internal::layout::arena::Header;
+use crate::internal::layout::segment;
+use crate::internal::layout::subheap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentList {
+    SubHeap(usize),
+    Kept,
+    Free,
+    FreeSpans,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    // The index is over the allocated segments.
+    OutOfSpace {
+        list: SegmentList,
+        index: segment::SegmentIndex,
+    },
+    // The segment is in another list, or twice in the same list.
+    Duplicated {
+        list: SegmentList,
+        index: segment::SegmentIndex,
+        other: SegmentList,
+    },
+    MismatchedStatus {
+        list: SegmentList,
+        index: segment::SegmentIndex,
+        status: segment::Status,
+    },
+    BrokenLink {
+        list: SegmentList,
+        index: segment::SegmentIndex,
+        expected_prev: Option<segment::SegmentIndex>,
+        actual_prev: Option<segment::SegmentIndex>,
+    },
+    MismatchedEnd {
+        list: SegmentList,
+        expected: Option<segment::SegmentIndex>,
+        actual: Option<segment::SegmentIndex>,
+    },
+    // The segment is not after the previous one, or overlaps it for spans.
+    Unsorted {
+        list: SegmentList,
+        index: segment::SegmentIndex,
+    },
+    MismatchedCount {
+        list: SegmentList,
+        recorded: usize,
+        counted: usize,
+    },
+    InvalidClass {
+        index: segment::SegmentIndex,
+        class_of_size: usize,
+    },
+    MismatchedClass {
+        index: segment::SegmentIndex,
+        expected: usize,
+        actual: usize,
+    },
+    FullSegment {
+        index: segment::SegmentIndex,
+        class_of_size: usize,
+    },
+    EmptySegment {
+        index: segment::SegmentIndex,
+        class_of_size: usize,
+    },
+    MismatchedUsedBlockCount {
+        index: segment::SegmentIndex,
+        recorded: usize,
+        counted: usize,
+    },
+    // More segments are linked in the keep list than the limit.
+    OverKept {
+        keep_count: usize,
+        counted: usize,
+    },
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct Report {
+    pub checked_segment_count: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    #[allow(unused)]
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+// Lists are walked with the compact headers first, since additional headers of unused segments may be decommitted.
+// A list is not walked further after an issue which makes its links unreliable.
+struct Verifier<'a> {
+    header: &'a Header,
+    owners: Vec<Option<SegmentList>>,
+    issues: Vec<Issue>,
+}
+
+pub unsafe fn verify(header: &Header) -> Report {
+    let mut verifier = Verifier::new(header);
+    verifier.verify_lists();
+    verifier.verify_used_block_counts();
+
+    Report {
+        checked_segment_count: verifier.owners.len(),
+        issues: verifier.issues,
+    }
+}
+
+// The list of each segment in the segment space, as far as the lists are walked without issues.
+// The following segments of free spans are in the list of their first segments.
+pub unsafe fn segment_lists(header: &Header) -> Vec<Option<SegmentList>> {
+    let mut verifier = Verifier::new(header);
+    verifier.verify_lists();
+    verifier.owners
+}
+
+impl<'a> Verifier<'a> {
+    fn new(header: &'a Header) -> Self {
+        Self {
+            header,
+            owners: vec![None; header.segment_space.allocated_segment_count()],
+            issues: Vec::new(),
+        }
+    }
+
+    unsafe fn verify_lists(&mut self) {
+        for class_of_size in 0..subheap::CLASS_COUNT {
+            self.verify_subheap(class_of_size);
+        }
+        self.verify_keep_segments();
+        self.verify_free_segments();
+        self.verify_free_spans();
+    }
+
+    fn claim(&mut self, list: SegmentList, index: segment::SegmentIndex) -> bool {
+        match self.owners.get_mut(index.get()) {
+            None => {
+                self.issues.push(Issue::OutOfSpace { list, index });
+                false
+            }
+            Some(Some(other)) => {
+                let other = *other;
+                self.issues.push(Issue::Duplicated { list, index, other });
+                false
+            }
+            Some(owner) => {
+                *owner = Some(list);
+                true
+            }
+        }
+    }
+
+    unsafe fn claim_with_status(
+        &mut self,
+        list: SegmentList,
+        index: segment::SegmentIndex,
+        expected_status: segment::Status,
+    ) -> Option<segment::Segment> {
+        if !self.claim(list, index) {
+            return None;
+        }
+
+        let seg = self.header.segment_space.segment_by_index(index);
+        let status = seg.status();
+        if status != expected_status {
+            self.issues.push(Issue::MismatchedStatus {
+                list,
+                index,
+                status,
+            });
+            return None;
+        }
+        Some(seg)
+    }
+
+    unsafe fn verify_subheap(&mut self, class_of_size: usize) {
+        let list = SegmentList::SubHeap(class_of_size);
+        let subheap_cls = &self.header.subheaps[class_of_size];
+
+        let mut prev_index = None;
+        let mut counted = 0;
+        let mut next_index = subheap_cls.free_segments_begin;
+        while let Some(index) = next_index {
+            let seg = match self.claim_with_status(list, index, segment::Status::SubHeap) {
+                Some(seg) => seg,
+                None => return,
+            };
+
+            if seg.prev() != prev_index {
+                self.issues.push(Issue::BrokenLink {
+                    list,
+                    index,
+                    expected_prev: prev_index,
+                    actual_prev: seg.prev(),
+                });
+            }
+            if prev_index.is_some_and(|prev_index| index <= prev_index) {
+                self.issues.push(Issue::Unsorted { list, index });
+            }
+
+            if seg.subheap_class() != class_of_size {
+                self.issues.push(Issue::MismatchedClass {
+                    index,
+                    expected: class_of_size,
+                    actual: seg.subheap_class(),
+                });
+            } else if seg.used_block_count() == 0 {
+                self.issues.push(Issue::EmptySegment {
+                    index,
+                    class_of_size,
+                });
+            } else if seg.used_block_count() >= segment::BLOCK_COUNT_OF_CLASS[class_of_size] {
+                self.issues.push(Issue::FullSegment {
+                    index,
+                    class_of_size,
+                });
+            }
+
+            counted += 1;
+            prev_index = Some(index);
+            next_index = seg.next();
+        }
+
+        if subheap_cls.free_segments_end != prev_index {
+            self.issues.push(Issue::MismatchedEnd {
+                list,
+                expected: prev_index,
+                actual: subheap_cls.free_segments_end,
+            });
+        }
+        if subheap_cls.partial_segment_count != counted {
+            self.issues.push(Issue::MismatchedCount {
+                list,
+                recorded: subheap_cls.partial_segment_count,
+                counted,
+            });
+        }
+    }
+
+    // Kept segments are out of any subheap, but linked in the order of the keep list, not of addresses.
+    unsafe fn verify_keep_segments(&mut self) {
+        let list = SegmentList::Kept;
+        let keep_segments = &self.header.keep_segments;
+
+        let mut prev_index = None;
+        let mut counted = 0;
+        let mut next_index = keep_segments.begin();
+        while let Some(index) = next_index {
+            let seg = match self.claim_with_status(list, index, segment::Status::Kept) {
+                Some(seg) => seg,
+                None => return,
+            };
+
+            if seg.prev() != prev_index {
+                self.issues.push(Issue::BrokenLink {
+                    list,
+                    index,
+                    expected_prev: prev_index,
+                    actual_prev: seg.prev(),
+                });
+            }
+
+            counted += 1;
+            prev_index = Some(index);
+            next_index = seg.next();
+        }
+
+        if keep_segments.end() != prev_index {
+            self.issues.push(Issue::MismatchedEnd {
+                list,
+                expected: prev_index,
+                actual: keep_segments.end(),
+            });
+        }
+        // The recorded count wraps on a broken header, which is reported as a mismatch.
+        let recorded = keep_segments
+            .keep_count()
+            .wrapping_sub(keep_segments.should_keep_count());
+        if counted > keep_segments.keep_count() {
+            self.issues.push(Issue::OverKept {
+                keep_count: keep_segments.keep_count(),
+                counted,
+            });
+        } else if recorded != counted {
+            self.issues.push(Issue::MismatchedCount {
+                list,
+                recorded,
+                counted,
+            });
+        }
+    }
+
+    // Free segments are linked by the next links only.
+    unsafe fn verify_free_segments(&mut self) {
+        let list = SegmentList::Free;
+
+        let mut counted = 0;
+        let mut next_index = self.header.free_segments_begin;
+        while let Some(index) = next_index {
+            let seg = match self.claim_with_status(list, index, segment::Status::Free) {
+                Some(seg) => seg,
+                None => return,
+            };
+
+            counted += 1;
+            next_index = seg.next();
+        }
+
+        if self.header.free_segments_count != counted {
+            self.issues.push(Issue::MismatchedCount {
+                list,
+                recorded: self.header.free_segments_count,
+                counted,
+            });
+        }
+    }
+
+    // Free spans are linked by the next links of their first segments, and sorted by addresses.
+    unsafe fn verify_free_spans(&mut self) {
+        let list = SegmentList::FreeSpans;
+        let free_spans = &self.header.free_spans;
+
+        let mut prev_span_end = None;
+        let mut counted = 0;
+        let mut next_index = free_spans.begin();
+        while let Some(index) = next_index {
+            let seg = match self.claim_with_status(list, index, segment::Status::FreeSpan) {
+                Some(seg) => seg,
+                None => return,
+            };
+
+            if prev_span_end.is_some_and(|prev_span_end| index.get() < prev_span_end) {
+                self.issues.push(Issue::Unsorted { list, index });
+                return;
+            }
+
+            let (segment_count, _) = seg.free_span();
+            for following_index in index.get() + 1..index.get() + segment_count {
+                if !self.claim(list, segment::SegmentIndex::new(following_index)) {
+                    return;
+                }
+            }
+
+            counted += segment_count;
+            prev_span_end = Some(index.get() + segment_count);
+            next_index = seg.next();
+        }
+
+        if free_spans.segment_count() != counted {
+            self.issues.push(Issue::MismatchedCount {
+                list,
+                recorded: free_spans.segment_count(),
+                counted,
+            });
+        }
+    }
+
+    // All segments of subheaps are checked, including full ones out of the lists.
+    unsafe fn verify_used_block_counts(&mut self) {
+        let segment_space = &self.header.segment_space;
+
+        let mut index = 0;
+        while index < segment_space.allocated_segment_count() {
+            let seg = segment_space.segment_by_index(segment::SegmentIndex::new(index));
+            let segment_count = match seg.status() {
+                segment::Status::SubHeap => {
+                    self.verify_used_block_count(&seg);
+                    1
+                }
+                segment::Status::Span => seg.span_segment_count(),
+                segment::Status::FreeSpan => seg.free_span().0,
+                segment::Status::Free | segment::Status::Kept | segment::Status::InSpan => 1,
+            };
+            // Not to loop forever on a broken count.
+            index += segment_count.max(1);
+        }
+    }
+
+    unsafe fn verify_used_block_count(&mut self, seg: &segment::Segment) {
+        let class_of_size = seg.subheap_class();
+        if class_of_size >= subheap::CLASS_COUNT {
+            self.issues.push(Issue::InvalidClass {
+                index: seg.index,
+                class_of_size,
+            });
+            return;
+        }
+
+        let mut counted = 0;
+        seg.for_each_used_block(|_| counted += 1);
+        if seg.used_block_count() != counted {
+            self.issues.push(Issue::MismatchedUsedBlockCount {
+                index: seg.index,
+                recorded: seg.used_block_count(),
+                counted,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::layout::arena::{Arena, Config, GuardPage};
+    use crate::sys;
+    use crate::sys::SysMemEnvImpl;
+
+    const KEEP_COUNT: usize = 2;
+    const CLASS_OF_SIZE: usize = 0;
+
+    // A partial segment on a subheap, full kept segments, and a free segment.
+    struct TestArena {
+        env: SysMemEnvImpl,
+        arena: Arena,
+        subheap_seg: segment::Segment,
+        kept_segs: Vec<segment::Segment>,
+        free_seg: segment::Segment,
+    }
+
+    impl TestArena {
+        unsafe fn new() -> Self {
+            let mut env = sys::new_env();
+            let config = Config {
+                min_heap_size: 1 << 18,
+                max_heap_size: 64 << 20,
+                keep_segments_count: KEEP_COUNT,
+                use_huge_pages: false,
+                large_blocks_cache_size: 0,
+                poisons_blocks: false,
+                quarantine_size: 0,
+                large_block_guard_page: GuardPage::None,
+            };
+            let mut arena = Arena::init(&mut env, config).unwrap();
+
+            let mut subheap_seg = arena.alloc_new_segment(&mut env).unwrap().unwrap();
+            subheap_seg.init_single(CLASS_OF_SIZE);
+            arena.subheap(CLASS_OF_SIZE).segment_count += 1;
+            arena.insert_free_segment_to_subheap(CLASS_OF_SIZE, &mut subheap_seg);
+            arena.subheap(CLASS_OF_SIZE).used_block_count += 1;
+            subheap_seg.mark_block_and_check_full(0);
+
+            let mut unused_segs: Vec<segment::Segment> = (0..KEEP_COUNT + 1)
+                .map(|_| arena.alloc_new_segment(&mut env).unwrap().unwrap())
+                .collect();
+            for seg in &mut unused_segs {
+                arena.free_unused_segment(&mut env, seg).unwrap();
+            }
+            let free_seg = unused_segs.pop().unwrap();
+
+            Self {
+                env,
+                arena,
+                subheap_seg,
+                kept_segs: unused_segs,
+                free_seg,
+            }
+        }
+
+        unsafe fn issues(&self) -> Vec<Issue> {
+            verify(self.arena.header()).issues
+        }
+    }
+
+    impl Drop for TestArena {
+        fn drop(&mut self) {
+            unsafe {
+                let arena = std::ptr::read(&self.arena);
+                arena.release(&mut self.env).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn passes_consistent_lists() {
+        unsafe {
+            let test_arena = TestArena::new();
+            let report = verify(test_arena.arena.header());
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.checked_segment_count, KEEP_COUNT + 2);
+        }
+    }
+
+    #[test]
+    fn reports_a_broken_link() {
+        unsafe {
+            let mut test_arena = TestArena::new();
+            let subheap_index = test_arena.subheap_seg.index;
+            test_arena.kept_segs[1].set_prev(Some(subheap_index));
+            assert_eq!(
+                test_arena.issues(),
+                [Issue::BrokenLink {
+                    list: SegmentList::Kept,
+                    index: test_arena.kept_segs[1].index,
+                    expected_prev: Some(test_arena.kept_segs[0].index),
+                    actual_prev: Some(subheap_index),
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn reports_a_bit_set_without_the_used_count() {
+        unsafe {
+            let test_arena = TestArena::new();
+            let mut seg = test_arena.subheap_seg;
+            seg.mark_block_and_check_full(1);
+            seg.additional_header.as_mut().used_block_count -= 1;
+            assert_eq!(
+                test_arena.issues(),
+                [Issue::MismatchedUsedBlockCount {
+                    index: seg.index,
+                    recorded: 1,
+                    counted: 2,
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn reports_a_mismatched_count_of_free_segments() {
+        unsafe {
+            let mut test_arena = TestArena::new();
+            test_arena.arena.header_mut().free_segments_count += 1;
+            assert_eq!(
+                test_arena.issues(),
+                [Issue::MismatchedCount {
+                    list: SegmentList::Free,
+                    recorded: 2,
+                    counted: 1,
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn reports_segments_over_the_keep_count() {
+        unsafe {
+            let mut test_arena = TestArena::new();
+            let free_index = test_arena.free_seg.index;
+            let last_kept_index = test_arena.kept_segs[KEEP_COUNT - 1].index;
+            test_arena.kept_segs[KEEP_COUNT - 1].set_next(Some(free_index));
+            test_arena.free_seg.set_prev(Some(last_kept_index));
+            test_arena.free_seg.set_status(segment::Status::Kept);
+
+            let issues = test_arena.issues();
+            assert!(issues.contains(&Issue::OverKept {
+                keep_count: KEEP_COUNT,
+                counted: KEEP_COUNT + 1,
+            }));
+            assert!(issues.contains(&Issue::MismatchedEnd {
+                list: SegmentList::Kept,
+                expected: Some(free_index),
+                actual: Some(last_kept_index),
+            }));
+        }
+    }
+}
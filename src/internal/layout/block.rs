@@ -3,25 +3,76 @@ use std::ptr::NonNull;
 use crate::internal::layout::constants::ALIGNMENT_SIZE;
 use crate::util;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Type {
     FreeSize,
     OnSubHeap,
+    OnSpan,
 }
 
+// The header is not at the begin of the mapping, which is the page of the header.
+pub const FLAG_ALIGNED_OFFSET: usize = 1 << 0;
+// The last page of the mapping is a guard page, and the payload ends at it.
+pub const FLAG_GUARD_PAGE: usize = 1 << 1;
+// The first page of the mapping is a guard page, and the header follows it.
+pub const FLAG_GUARD_PAGE_BEFORE: usize = 1 << 2;
+
+const FLAGS_MASK: usize = FLAG_ALIGNED_OFFSET | FLAG_GUARD_PAGE | FLAG_GUARD_PAGE_BEFORE;
+// Flags are in the low bits of the block size, which are always zero by the alignment.
+const _: () = assert!(FLAGS_MASK < ALIGNMENT_SIZE);
+
 pub struct HeaderForFreeSize {
     block_size_with_flags: usize,
 }
 
 impl HeaderForFreeSize {
-    pub unsafe fn init(mut ptr: NonNull<HeaderForFreeSize>, block_size: usize) {
+    pub unsafe fn init(mut ptr: NonNull<HeaderForFreeSize>, block_size: usize, flags: usize) {
         assert!(util::bits::is_aligned(block_size, ALIGNMENT_SIZE));
+        assert_eq!(flags & !FLAGS_MASK, 0);
 
         *ptr.as_mut() = HeaderForFreeSize {
-            block_size_with_flags: block_size,
+            block_size_with_flags: block_size | flags,
         };
     }
 
+    #[inline]
     pub fn block_size(&self) -> usize {
-        util::bits::max_aligned_size(self.block_size_with_flags, ALIGNMENT_SIZE)
+        self.block_size_with_flags & !FLAGS_MASK
+    }
+
+    #[inline]
+    pub fn flags(&self) -> usize {
+        self.block_size_with_flags & FLAGS_MASK
+    }
+
+    #[inline]
+    pub fn has_flags(&self, flags: usize) -> bool {
+        self.flags() & flags == flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_flags_out_of_the_block_size() {
+        let mut header = HeaderForFreeSize {
+            block_size_with_flags: 0,
+        };
+        for flags in [
+            0,
+            FLAG_ALIGNED_OFFSET,
+            FLAG_GUARD_PAGE | FLAG_ALIGNED_OFFSET,
+            FLAG_GUARD_PAGE_BEFORE,
+        ] {
+            unsafe {
+                HeaderForFreeSize::init(NonNull::from(&mut header), 3 * ALIGNMENT_SIZE, flags)
+            };
+            assert_eq!(header.block_size(), 3 * ALIGNMENT_SIZE);
+            assert_eq!(header.flags(), flags);
+            assert!(header.has_flags(flags));
+        }
+        assert!(!header.has_flags(FLAG_GUARD_PAGE));
     }
 }
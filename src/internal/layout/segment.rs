@@ -1,4 +1,5 @@
 use std::mem::size_of;
+use std::num::NonZeroU32;
 use std::ptr::NonNull;
 
 use crate::internal::layout::constants::BYTE_BIT_SIZE;
@@ -6,44 +7,145 @@ use crate::internal::layout::segment;
 use crate::internal::layout::segment_space;
 use crate::internal::layout::subheap;
 use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
+use crate::sys::COMMIT_STATE_BIT_SIZE;
 use crate::util;
 
-pub const SEGMENT_SIZE: usize = 1 << 16;
+const SEGMENT_SIZE_SHIFT: usize = if cfg!(feature = "segment-size-1m") {
+    20
+} else if cfg!(feature = "segment-size-512k") {
+    19
+} else if cfg!(feature = "segment-size-256k") {
+    18
+} else if cfg!(feature = "segment-size-128k") {
+    17
+} else if cfg!(feature = "segment-size-32k") {
+    15
+} else {
+    16
+};
+pub const SEGMENT_SIZE: usize = 1 << SEGMENT_SIZE_SHIFT;
 pub const COMPACT_HEADER_SIZE: usize = size_of::<CompactHeader>();
 const ADDITIONAL_HEADER_SIZE: usize = size_of::<AdditionalHeader>();
 pub const BITMAP_ITEM_SIZE: usize = size_of::<usize>();
 const BITMAP_ITEM_BIT_SIZE: usize = BITMAP_ITEM_SIZE * BYTE_BIT_SIZE;
 const BITMAP_ITEM_SP_BIT_SIZE: usize = 1;
+const MAX_TAIL_WASTE_PERCENT: usize = 50;
 pub const BITMAP_ITEM_EFF_BIT_SIZE: usize = BITMAP_ITEM_BIT_SIZE - BITMAP_ITEM_SP_BIT_SIZE;
+pub const SPAN_CLASS: usize = usize::MAX;
+pub const MAX_SPAN_BLOCK_SIZE: usize = 1 << 20;
+pub const MAX_SEGMENT_COUNT: usize = u32::MAX as usize;
+
+// Segments are linked by indices in the segment space, which are narrower than pointers.
+// The index is stored with 1 offset, so that `Option<SegmentIndex>` fits in 32 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SegmentIndex(NonZeroU32);
+
+impl SegmentIndex {
+    #[inline]
+    pub const fn new(index: usize) -> Self {
+        assert!(index < MAX_SEGMENT_COUNT);
+        match NonZeroU32::new((index + 1) as u32) {
+            Some(raw) => Self(raw),
+            None => panic!("unreachable: the index is offset by 1."),
+        }
+    }
+
+    #[inline]
+    pub const fn get(self) -> usize {
+        self.0.get() as usize - 1
+    }
+}
 
 #[derive(Clone, Copy)]
 pub struct Segment {
+    pub index: SegmentIndex,
     pub compact_header: NonNull<CompactHeader>,
     pub additional_header: NonNull<AdditionalHeader>,
 }
 
 impl Segment {
     #[inline]
-    pub fn new(compact_header: NonNull<CompactHeader>, segment: AnyNonNullPtr) -> Self {
+    pub fn new(
+        index: SegmentIndex,
+        compact_header: NonNull<CompactHeader>,
+        segment: AnyNonNullPtr,
+    ) -> Self {
         Self {
-            compact_header: compact_header,
+            index,
+            compact_header,
             additional_header: segment.as_nonnull(),
         }
     }
 
     pub unsafe fn init_single(&mut self, class_of_size: usize) {
         *self.compact_header.as_mut() = CompactHeader {
-            next: std::ptr::null_mut(),
+            next: None,
+            status: Status::SubHeap,
             bitmap: 1,
         };
         *self.additional_header.as_mut() = AdditionalHeader {
-            prev: std::ptr::null_mut(),
+            prev: None,
             subheap_class: class_of_size,
             used_block_count: 0,
         };
 
-        let _ = SUB_BITMAP_SIZE_OF_CLASS[class_of_size];
-        todo!()
+        // Bits of nonexistent blocks are set beforehand, so that a full bitmap has all bits set.
+        let block_count = BLOCK_COUNT_OF_CLASS[class_of_size];
+        let sub_bitmap_size = SUB_BITMAP_SIZE_OF_CLASS[class_of_size];
+        if sub_bitmap_size == 0 {
+            self.compact_header.as_mut().bitmap |= absent_bits_mask(block_count);
+            return;
+        }
+
+        // Then the compact header bitmap tracks which sub bitmaps are full.
+        self.compact_header.as_mut().bitmap |= absent_bits_mask(sub_bitmap_size);
+        for unit_index in 0..sub_bitmap_size {
+            for item_offset in 0..BITMAP_ITEM_EFF_BIT_SIZE {
+                let item_index = unit_index * BITMAP_ITEM_EFF_BIT_SIZE + item_offset;
+                let first_block_index = item_index * BITMAP_ITEM_EFF_BIT_SIZE;
+                *self.bitmap_item(item_index).as_mut() =
+                    1 | absent_bits_mask(block_count.saturating_sub(first_block_index));
+            }
+            if self.is_sub_bitmap_full(unit_index) {
+                self.compact_header.as_mut().bitmap |= 1 << (BITMAP_ITEM_SP_BIT_SIZE + unit_index);
+            }
+        }
+    }
+
+    // A span uses segments continuously, and its first segment has the header only.
+    pub unsafe fn init_span(&mut self, segment_count: usize) {
+        *self.compact_header.as_mut() = CompactHeader {
+            next: None,
+            status: Status::Span,
+            bitmap: 0,
+        };
+        *self.additional_header.as_mut() = AdditionalHeader {
+            prev: None,
+            subheap_class: SPAN_CLASS,
+            used_block_count: segment_count,
+        };
+
+        // Compact headers are continuous, so the following ones are marked too.
+        for offset in 1..segment_count {
+            self.compact_header.add(offset).as_mut().status = Status::InSpan;
+        }
+    }
+
+    #[inline]
+    pub const fn span_segment_count_of_size(block_size: usize) -> usize {
+        (ADDITIONAL_HEADER_SIZE + block_size).div_ceil(SEGMENT_SIZE)
+    }
+
+    #[inline]
+    pub unsafe fn span_segment_count(&self) -> usize {
+        assert!(self.status() == Status::Span);
+        self.additional_header.as_ref().used_block_count
+    }
+
+    #[inline]
+    pub unsafe fn span_block_ptr(&self) -> AnyNonNullPtr {
+        self.seg_ptr().add(ADDITIONAL_HEADER_SIZE)
     }
 
     #[inline]
@@ -63,166 +165,336 @@ impl Segment {
             .add(SUB_BITMAP_UNIT_SIZE * sub_bitmap_size)
     }
 
+    #[inline]
+    unsafe fn bitmap_item(&self, item_index: usize) -> NonNull<usize> {
+        self.bitmap_space_begin()
+            .add(BITMAP_ITEM_SIZE * item_index)
+            .as_nonnull()
+    }
+
+    unsafe fn is_sub_bitmap_full(&self, unit_index: usize) -> bool {
+        let first_item_index = unit_index * BITMAP_ITEM_EFF_BIT_SIZE;
+        (first_item_index..first_item_index + BITMAP_ITEM_EFF_BIT_SIZE)
+            .all(|item_index| *self.bitmap_item(item_index).as_ref() == usize::MAX)
+    }
+
+    #[inline]
+    pub unsafe fn status(&self) -> Status {
+        self.compact_header.as_ref().status
+    }
+
+    #[inline]
+    pub unsafe fn set_status(&mut self, status: Status) {
+        self.compact_header.as_mut().status = status;
+    }
+
     #[inline]
     pub unsafe fn subheap_class(&self) -> usize {
         self.additional_header.as_ref().subheap_class
     }
 
+    #[inline]
+    pub unsafe fn used_block_count(&self) -> usize {
+        self.additional_header.as_ref().used_block_count
+    }
+
     #[inline]
     pub unsafe fn block_size(&self) -> usize {
         subheap::SUBHEAP_SIZE_OF_CLASS[self.subheap_class()]
     }
 
     #[inline]
-    pub unsafe fn next(&self) -> *mut CompactHeader {
+    pub unsafe fn next(&self) -> Option<SegmentIndex> {
         self.compact_header.as_ref().next
     }
 
     #[inline]
-    pub unsafe fn set_next(&mut self, ptr: *mut CompactHeader) {
-        self.compact_header.as_mut().next = ptr;
+    pub unsafe fn set_next(&mut self, index: Option<SegmentIndex>) {
+        self.compact_header.as_mut().next = index;
     }
 
     #[inline]
-    pub unsafe fn prev(&self) -> *mut CompactHeader {
+    pub unsafe fn prev(&self) -> Option<SegmentIndex> {
         self.additional_header.as_ref().prev
     }
 
     #[inline]
-    pub unsafe fn set_prev(&mut self, ptr: *mut CompactHeader) {
-        self.additional_header.as_mut().prev = ptr;
+    pub unsafe fn set_prev(&mut self, index: Option<SegmentIndex>) {
+        self.additional_header.as_mut().prev = index;
+    }
+
+    // Free segments do not use the bitmap, so it holds the commit state instead.
+    #[inline]
+    pub unsafe fn commit_state(&self) -> CommitState {
+        CommitState::from_bits(self.compact_header.as_ref().bitmap)
     }
 
     #[inline]
-    pub unsafe fn block_ptr(&mut self, index: usize) -> AnyNonNullPtr {
+    pub unsafe fn set_commit_state(&mut self, state: CommitState) {
+        self.compact_header.as_mut().bitmap = state.into_bits();
+    }
+
+    // Free spans do not use the bitmap, so it holds the segment count and the commit state.
+    #[inline]
+    pub unsafe fn free_span(&self) -> (usize, CommitState) {
+        let bitmap = self.compact_header.as_ref().bitmap;
+        let state_mask = (1 << COMMIT_STATE_BIT_SIZE) - 1;
+        (
+            bitmap >> COMMIT_STATE_BIT_SIZE,
+            CommitState::from_bits(bitmap & state_mask),
+        )
+    }
+
+    #[inline]
+    pub unsafe fn set_free_span(&mut self, segment_count: usize, state: CommitState) {
+        self.compact_header.as_mut().status = Status::FreeSpan;
+        self.compact_header.as_mut().bitmap =
+            (segment_count << COMMIT_STATE_BIT_SIZE) | state.into_bits();
+    }
+
+    #[inline]
+    pub unsafe fn block_ptr(&self, index: usize) -> AnyNonNullPtr {
         self.block_space_begin().add(self.block_size() * index)
     }
 
     #[inline]
-    pub unsafe fn from_block_ptr<'a>(
+    pub unsafe fn from_block_ptr(
         seg_space: &mut segment_space::SegmentSpace,
         block_ptr: AnyNonNullPtr,
     ) -> (Self, usize) {
-        let seg_ptr = AnyNonNullPtr::new(NonNull::new_unchecked(util::bits::max_aligned_size(block_ptr.as_addr(), segment::SEGMENT_SIZE) as *mut ()));
+        let seg_ptr = AnyNonNullPtr::new(NonNull::new_unchecked(util::bits::max_aligned_size(
+            block_ptr.as_addr(),
+            segment::SEGMENT_SIZE,
+        ) as *mut ()));
         let seg = seg_space.segment_by_header(seg_ptr);
 
         let block_space_begin = seg.block_space_begin();
         assert!(block_space_begin <= block_ptr);
 
-        let block_index = (block_ptr.offset_bytes_from(block_space_begin) as usize) / seg.block_size();
+        let block_index =
+            (block_ptr.offset_bytes_from(block_space_begin) as usize) / seg.block_size();
 
         (seg, block_index)
     }
 
     #[inline]
     pub unsafe fn is_floated(&self) -> bool {
-        self.additional_header.as_ref().prev.is_null()
-            && self.compact_header.as_ref().next.is_null()
+        self.additional_header.as_ref().prev.is_none()
+            && self.compact_header.as_ref().next.is_none()
     }
 
-    pub fn find_free_block(&mut self) -> Option<usize> {
-        todo!()
+    pub unsafe fn find_free_block(&self) -> Option<usize> {
+        let bitmap = self.compact_header.as_ref().bitmap;
+        if bitmap == usize::MAX {
+            return None;
+        }
+
+        let bit_index = (!bitmap).trailing_zeros() as usize;
+        if SUB_BITMAP_SIZE_OF_CLASS[self.subheap_class()] == 0 {
+            return Some(bit_index - BITMAP_ITEM_SP_BIT_SIZE);
+        }
+
+        let unit_index = bit_index - BITMAP_ITEM_SP_BIT_SIZE;
+        for item_offset in 0..BITMAP_ITEM_EFF_BIT_SIZE {
+            let item_index = unit_index * BITMAP_ITEM_EFF_BIT_SIZE + item_offset;
+            let item = *self.bitmap_item(item_index).as_ref();
+            if item != usize::MAX {
+                let bit_index = (!item).trailing_zeros() as usize;
+                return Some(
+                    item_index * BITMAP_ITEM_EFF_BIT_SIZE + bit_index - BITMAP_ITEM_SP_BIT_SIZE,
+                );
+            }
+        }
+        panic!("unreachable: sub bitmaps not marked as full have free blocks.")
     }
 
-    pub fn mark_block_and_check_full(&mut self, index: usize) -> bool {
-        todo!()
+    #[cfg(feature = "debug-checks")]
+    #[inline]
+    pub unsafe fn is_block_used(&self, index: usize) -> bool {
+        let bit = 1 << (BITMAP_ITEM_SP_BIT_SIZE + index % BITMAP_ITEM_EFF_BIT_SIZE);
+        let item = if SUB_BITMAP_SIZE_OF_CLASS[self.subheap_class()] == 0 {
+            self.compact_header.as_ref().bitmap
+        } else {
+            *self.bitmap_item(index / BITMAP_ITEM_EFF_BIT_SIZE).as_ref()
+        };
+        item & bit != 0
     }
 
-    pub fn free_block_and_check_empty(&mut self, index: usize) -> bool {
-        todo!()
+    pub unsafe fn mark_block_and_check_full(&mut self, index: usize) -> bool {
+        let class_of_size = self.subheap_class();
+        assert!(index < BLOCK_COUNT_OF_CLASS[class_of_size]);
+
+        let bit = 1 << (BITMAP_ITEM_SP_BIT_SIZE + index % BITMAP_ITEM_EFF_BIT_SIZE);
+        if SUB_BITMAP_SIZE_OF_CLASS[class_of_size] == 0 {
+            let bitmap = &mut self.compact_header.as_mut().bitmap;
+            assert!(*bitmap & bit == 0);
+            *bitmap |= bit;
+        } else {
+            let item_index = index / BITMAP_ITEM_EFF_BIT_SIZE;
+            let mut item_ptr = self.bitmap_item(item_index);
+            let item = item_ptr.as_mut();
+            assert!(*item & bit == 0);
+            *item |= bit;
+
+            let unit_index = item_index / BITMAP_ITEM_EFF_BIT_SIZE;
+            if *item == usize::MAX && self.is_sub_bitmap_full(unit_index) {
+                self.compact_header.as_mut().bitmap |= 1 << (BITMAP_ITEM_SP_BIT_SIZE + unit_index);
+            }
+        }
+
+        let additional_header = self.additional_header.as_mut();
+        additional_header.used_block_count += 1;
+        additional_header.used_block_count == BLOCK_COUNT_OF_CLASS[class_of_size]
+    }
+
+    pub unsafe fn free_block_and_check_empty(&mut self, index: usize) -> bool {
+        let class_of_size = self.subheap_class();
+        assert!(index < BLOCK_COUNT_OF_CLASS[class_of_size]);
+
+        let bit = 1 << (BITMAP_ITEM_SP_BIT_SIZE + index % BITMAP_ITEM_EFF_BIT_SIZE);
+        if SUB_BITMAP_SIZE_OF_CLASS[class_of_size] == 0 {
+            let bitmap = &mut self.compact_header.as_mut().bitmap;
+            assert!(*bitmap & bit != 0);
+            *bitmap &= !bit;
+        } else {
+            let item_index = index / BITMAP_ITEM_EFF_BIT_SIZE;
+            let mut item_ptr = self.bitmap_item(item_index);
+            let item = item_ptr.as_mut();
+            assert!(*item & bit != 0);
+            *item &= !bit;
+
+            let unit_index = item_index / BITMAP_ITEM_EFF_BIT_SIZE;
+            self.compact_header.as_mut().bitmap &= !(1 << (BITMAP_ITEM_SP_BIT_SIZE + unit_index));
+        }
+
+        let additional_header = self.additional_header.as_mut();
+        additional_header.used_block_count -= 1;
+        additional_header.used_block_count == 0
+    }
+
+    pub unsafe fn for_each_used_block<F>(&self, mut f: F)
+    where
+        F: FnMut(usize),
+    {
+        let class_of_size = self.subheap_class();
+        let block_count = BLOCK_COUNT_OF_CLASS[class_of_size];
+        let item_count = if SUB_BITMAP_SIZE_OF_CLASS[class_of_size] == 0 {
+            1
+        } else {
+            block_count.div_ceil(BITMAP_ITEM_EFF_BIT_SIZE)
+        };
+
+        for item_index in 0..item_count {
+            let item = if SUB_BITMAP_SIZE_OF_CLASS[class_of_size] == 0 {
+                self.compact_header.as_ref().bitmap
+            } else {
+                *self.bitmap_item(item_index).as_ref()
+            };
+
+            let mut block_bits = item >> BITMAP_ITEM_SP_BIT_SIZE;
+            while block_bits != 0 {
+                let index =
+                    item_index * BITMAP_ITEM_EFF_BIT_SIZE + block_bits.trailing_zeros() as usize;
+                // Bits of nonexistent blocks are set too.
+                if index >= block_count {
+                    break;
+                }
+                f(index);
+                block_bits &= block_bits - 1;
+            }
+        }
     }
 
     #[inline]
     pub unsafe fn append(&mut self, after: &mut Self) {
-        assert!(self.next().is_null());
-        assert!(after.prev().is_null());
+        assert!(self.next().is_none());
+        assert!(after.prev().is_none());
 
-        self.compact_header.as_mut().next = after.compact_header.as_ptr();
-        after.additional_header.as_mut().prev = self.compact_header.as_ptr();
+        self.compact_header.as_mut().next = Some(after.index);
+        after.additional_header.as_mut().prev = Some(self.index);
     }
 }
 
+// Kept in the compact header, since the additional header of an unused segment may be decommitted.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Free,
+    Kept,
+    FreeSpan,
+    SubHeap,
+    Span,
+    // One of the following segments of a span or a free span, which have no header.
+    InSpan,
+}
+
 pub struct CompactHeader {
-    pub next: *mut CompactHeader,
+    pub next: Option<SegmentIndex>,
+    pub status: Status,
     pub bitmap: usize,
 }
 
 pub struct AdditionalHeader {
-    pub prev: *mut CompactHeader,
+    pub prev: Option<SegmentIndex>,
     pub subheap_class: usize,
     pub used_block_count: usize,
 }
 
 const SUB_BITMAP_UNIT_SIZE: usize = BITMAP_ITEM_EFF_BIT_SIZE * BITMAP_ITEM_SIZE;
-const SUB_BITMAP_SIZE_OF_CLASS: [usize; subheap::CLASS_COUNT] = [
-    sub_bitmap_size_of_class(0),
-    sub_bitmap_size_of_class(1),
-    sub_bitmap_size_of_class(2),
-    sub_bitmap_size_of_class(3),
-    sub_bitmap_size_of_class(4),
-    sub_bitmap_size_of_class(5),
-    sub_bitmap_size_of_class(6),
-    sub_bitmap_size_of_class(7),
-    sub_bitmap_size_of_class(8),
-    sub_bitmap_size_of_class(9),
-    sub_bitmap_size_of_class(10),
-    sub_bitmap_size_of_class(11),
-    sub_bitmap_size_of_class(12),
-    sub_bitmap_size_of_class(13),
-    sub_bitmap_size_of_class(14),
-    sub_bitmap_size_of_class(15),
-    sub_bitmap_size_of_class(16),
-    sub_bitmap_size_of_class(17),
-    sub_bitmap_size_of_class(18),
-    sub_bitmap_size_of_class(19),
-    sub_bitmap_size_of_class(20),
-    sub_bitmap_size_of_class(21),
-    sub_bitmap_size_of_class(22),
-    sub_bitmap_size_of_class(23),
-    sub_bitmap_size_of_class(24),
-    sub_bitmap_size_of_class(25),
-    sub_bitmap_size_of_class(26),
-    sub_bitmap_size_of_class(27),
-    sub_bitmap_size_of_class(28),
-    sub_bitmap_size_of_class(29),
-    sub_bitmap_size_of_class(30),
-    sub_bitmap_size_of_class(31),
-];
-
-const BLOCK_COUNT_OF_CLASS: [usize; subheap::CLASS_COUNT] = [
-    block_count_of_class(0),
-    block_count_of_class(1),
-    block_count_of_class(2),
-    block_count_of_class(3),
-    block_count_of_class(4),
-    block_count_of_class(5),
-    block_count_of_class(6),
-    block_count_of_class(7),
-    block_count_of_class(8),
-    block_count_of_class(9),
-    block_count_of_class(10),
-    block_count_of_class(11),
-    block_count_of_class(12),
-    block_count_of_class(13),
-    block_count_of_class(14),
-    block_count_of_class(15),
-    block_count_of_class(16),
-    block_count_of_class(17),
-    block_count_of_class(18),
-    block_count_of_class(19),
-    block_count_of_class(20),
-    block_count_of_class(21),
-    block_count_of_class(22),
-    block_count_of_class(23),
-    block_count_of_class(24),
-    block_count_of_class(25),
-    block_count_of_class(26),
-    block_count_of_class(27),
-    block_count_of_class(28),
-    block_count_of_class(29),
-    block_count_of_class(30),
-    block_count_of_class(31),
-];
+
+// The bits after the sentinel bit and the first `present_count` bits.
+#[inline]
+const fn absent_bits_mask(present_count: usize) -> usize {
+    if present_count >= BITMAP_ITEM_EFF_BIT_SIZE {
+        0
+    } else {
+        usize::MAX << (BITMAP_ITEM_SP_BIT_SIZE + present_count)
+    }
+}
+const SUB_BITMAP_SIZE_OF_CLASS: [usize; subheap::CLASS_COUNT] = sub_bitmap_size_table();
+pub const BLOCK_COUNT_OF_CLASS: [usize; subheap::CLASS_COUNT] = block_count_table();
+
+const _: () = validate_class_tables();
+
+const fn sub_bitmap_size_table() -> [usize; subheap::CLASS_COUNT] {
+    let mut table = [0; subheap::CLASS_COUNT];
+    let mut class_of_size = 0;
+    while class_of_size < subheap::CLASS_COUNT {
+        table[class_of_size] = sub_bitmap_size_of_class(class_of_size);
+        class_of_size += 1;
+    }
+    table
+}
+
+const fn block_count_table() -> [usize; subheap::CLASS_COUNT] {
+    let mut table = [0; subheap::CLASS_COUNT];
+    let mut class_of_size = 0;
+    while class_of_size < subheap::CLASS_COUNT {
+        table[class_of_size] = block_count_of_class(class_of_size);
+        class_of_size += 1;
+    }
+    table
+}
+
+const fn validate_class_tables() {
+    let mut class_of_size = 0;
+    while class_of_size < subheap::CLASS_COUNT {
+        // Each sub bitmap is tracked by a bit of the compact header bitmap.
+        assert!(
+            SUB_BITMAP_SIZE_OF_CLASS[class_of_size] <= BITMAP_ITEM_EFF_BIT_SIZE,
+            "The segment size is too large for the smallest size class."
+        );
+        assert!(
+            BLOCK_COUNT_OF_CLASS[class_of_size] > 0,
+            "The segment size is too small for the largest size class."
+        );
+        assert!(
+            tail_waste_size_of_class(class_of_size) * 100 <= SEGMENT_SIZE * MAX_TAIL_WASTE_PERCENT,
+            "A size class wastes too much space of segments."
+        );
+        class_of_size += 1;
+    }
+}
 
 const fn sub_bitmap_size_of_class(class_of_size: usize) -> usize {
     let block_size = subheap::SUBHEAP_SIZE_OF_CLASS[class_of_size];
@@ -246,3 +518,146 @@ const fn block_count_of_class(class_of_size: usize) -> usize {
 
     block_space_size / block_size
 }
+
+// The additional header and the sub bitmaps, before the blocks.
+pub const fn header_size_of_class(class_of_size: usize) -> usize {
+    ADDITIONAL_HEADER_SIZE + SUB_BITMAP_UNIT_SIZE * SUB_BITMAP_SIZE_OF_CLASS[class_of_size]
+}
+
+pub const fn tail_waste_size_of_class(class_of_size: usize) -> usize {
+    let block_size = subheap::SUBHEAP_SIZE_OF_CLASS[class_of_size];
+    let sub_bitmap_size = SUB_BITMAP_SIZE_OF_CLASS[class_of_size];
+    let segment_available_size = SEGMENT_SIZE - ADDITIONAL_HEADER_SIZE;
+    let block_space_size = segment_available_size - SUB_BITMAP_UNIT_SIZE * sub_bitmap_size;
+
+    block_space_size - BLOCK_COUNT_OF_CLASS[class_of_size] * block_size
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{self, Layout};
+
+    use super::*;
+
+    struct TestSegment {
+        seg: Segment,
+    }
+
+    impl TestSegment {
+        fn new(class_of_size: usize) -> Self {
+            unsafe {
+                let compact_header = alloc::alloc_zeroed(Layout::new::<CompactHeader>());
+                let seg_ptr = alloc::alloc_zeroed(Self::segment_layout());
+                let mut seg = Segment::new(
+                    SegmentIndex::new(0),
+                    NonNull::new(compact_header).unwrap().cast(),
+                    AnyNonNullPtr::new(NonNull::new(seg_ptr).unwrap()),
+                );
+                seg.init_single(class_of_size);
+                Self { seg }
+            }
+        }
+
+        fn segment_layout() -> Layout {
+            Layout::from_size_align(SEGMENT_SIZE, SEGMENT_SIZE).unwrap()
+        }
+
+        fn used_blocks(&self) -> Vec<usize> {
+            let mut used_blocks = Vec::new();
+            unsafe {
+                self.seg
+                    .for_each_used_block(|index| used_blocks.push(index))
+            };
+            used_blocks
+        }
+    }
+
+    impl Drop for TestSegment {
+        fn drop(&mut self) {
+            unsafe {
+                alloc::dealloc(
+                    self.seg.compact_header.cast().as_ptr(),
+                    Layout::new::<CompactHeader>(),
+                );
+                alloc::dealloc(
+                    self.seg.seg_ptr().as_nonnull().as_ptr(),
+                    Self::segment_layout(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fills_blocks_in_order_until_full() {
+        for (class_of_size, &block_count) in BLOCK_COUNT_OF_CLASS.iter().enumerate() {
+            let mut test_seg = TestSegment::new(class_of_size);
+            let seg = &mut test_seg.seg;
+
+            for expected_index in 0..block_count {
+                unsafe {
+                    assert_eq!(seg.find_free_block(), Some(expected_index));
+                    let is_full = seg.mark_block_and_check_full(expected_index);
+                    assert_eq!(is_full, expected_index == block_count - 1);
+                }
+            }
+            unsafe {
+                assert_eq!(seg.find_free_block(), None);
+                assert_eq!(seg.additional_header.as_ref().used_block_count, block_count);
+            }
+        }
+    }
+
+    #[test]
+    fn reuses_freed_blocks_until_empty() {
+        for (class_of_size, &block_count) in BLOCK_COUNT_OF_CLASS.iter().enumerate() {
+            let mut test_seg = TestSegment::new(class_of_size);
+            let seg = &mut test_seg.seg;
+
+            unsafe {
+                for index in 0..block_count {
+                    seg.mark_block_and_check_full(index);
+                }
+
+                // The block in the last sub bitmap is found after the full ones.
+                // A segment of a single block is empty again once it is freed.
+                let freed_index = block_count - 1;
+                assert_eq!(
+                    seg.free_block_and_check_empty(freed_index),
+                    block_count == 1
+                );
+                assert_eq!(seg.find_free_block(), Some(freed_index));
+                assert!(seg.mark_block_and_check_full(freed_index));
+
+                for index in 0..block_count {
+                    let is_empty = seg.free_block_and_check_empty(index);
+                    assert_eq!(is_empty, index == block_count - 1);
+                }
+                assert_eq!(seg.find_free_block(), Some(0));
+            }
+        }
+    }
+
+    #[test]
+    fn lists_used_blocks() {
+        for (class_of_size, &block_count) in BLOCK_COUNT_OF_CLASS.iter().enumerate() {
+            let mut test_seg = TestSegment::new(class_of_size);
+            assert!(test_seg.used_blocks().is_empty());
+
+            let marked_blocks: Vec<usize> = (0..block_count).step_by(3).collect();
+            for &index in &marked_blocks {
+                unsafe { test_seg.seg.mark_block_and_check_full(index) };
+            }
+            assert_eq!(test_seg.used_blocks(), marked_blocks);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_marking_a_used_block() {
+        let mut test_seg = TestSegment::new(0);
+        unsafe {
+            test_seg.seg.mark_block_and_check_full(0);
+            test_seg.seg.mark_block_and_check_full(0);
+        }
+    }
+}
@@ -1,120 +1,333 @@
 use std::error::Error;
+use std::ops::Range;
 use std::ptr::NonNull;
 
 use crate::internal::layout::segment;
 use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
 use crate::sys::SysMemEnv;
+use crate::util;
 
 #[derive(Debug)]
 pub struct SegmentSpace {
     // immutable
     pub page_size: usize,
-    segment_compact_header_space: AnyNonNullPtr,
-    segment_space_begin: AnyNonNullPtr,
-    segment_space_end: AnyNonNullPtr,
+    // Spaces are kept as offsets from the context space, so that the arena can be mapped at another address.
+    // The context space is found back by the offset of this struct in it.
+    offset_in_context_space: usize,
+    compact_header_space_offset: usize,
+    // Wrapping, since the segment space may be below the context space.
+    space_begin_offset: usize,
+    space_size: usize,
 
     // mutable
     pub available_size: usize,
+    commit_granularity: usize,
     next_alloc_segment_compact_header_index: usize,
     next_alloc_segment_index: usize,
+    next_commit_segment_index: usize,
 }
 
 impl SegmentSpace {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         page_size: usize,
-        segment_compact_header_space: AnyNonNullPtr,
-        segment_space_begin: AnyNonNullPtr,
-        segment_space_end: AnyNonNullPtr,
+        offset_in_context_space: usize,
+        compact_header_space_offset: usize,
+        space_begin_offset: usize,
+        space_size: usize,
         available_size: usize,
+        commit_granularity: usize,
         next_alloc_segment_compact_header_index: usize,
         next_alloc_segment_index: usize,
     ) -> Self {
+        assert!(util::bits::is_aligned(
+            commit_granularity,
+            segment::SEGMENT_SIZE
+        ));
+
         Self {
             page_size,
-            segment_compact_header_space,
-            segment_space_begin,
-            segment_space_end,
+            offset_in_context_space,
+            compact_header_space_offset,
+            space_begin_offset,
+            space_size,
             available_size,
+            commit_granularity,
             next_alloc_segment_compact_header_index,
             next_alloc_segment_index,
+            next_commit_segment_index: next_alloc_segment_index,
         }
     }
 
-    pub unsafe fn ptr_in_space(&self, ptr: AnyNonNullPtr) -> bool {
-        ptr.offset_bytes_from(self.segment_space_begin) >= 0
-            && self.segment_space_end.offset_bytes_from(ptr) > 0
+    #[inline]
+    unsafe fn context_space(&self) -> AnyNonNullPtr {
+        AnyNonNullPtr::new(NonNull::from(self)).sub(self.offset_in_context_space)
     }
 
-    pub unsafe fn segment_by_cmp_header(&self, seg_ptr: NonNull<segment::CompactHeader>) -> segment::Segment {
-        let raw_seg_ptr = AnyNonNullPtr::new(seg_ptr);
-        assert!(self.segment_compact_header_space <= raw_seg_ptr);
+    #[inline]
+    pub unsafe fn compact_header_space(&self) -> AnyNonNullPtr {
+        self.context_space().add(self.compact_header_space_offset)
+    }
 
-        let seg_index = (raw_seg_ptr.offset_bytes_from(self.segment_compact_header_space) as usize)
-            / segment::COMPACT_HEADER_SIZE;
-        assert!(seg_index < self.next_alloc_segment_index);
+    #[inline]
+    pub unsafe fn space_begin(&self) -> AnyNonNullPtr {
+        self.context_space().wrapping_add(self.space_begin_offset)
+    }
 
-        let raw_additional_header = self
-            .segment_space_begin
-            .add(seg_index * segment::SEGMENT_SIZE);
+    #[inline]
+    unsafe fn space_end(&self) -> AnyNonNullPtr {
+        self.space_begin().add(self.space_size)
+    }
+
+    #[inline]
+    pub fn allocated_segment_count(&self) -> usize {
+        self.next_alloc_segment_index
+    }
 
-        segment::Segment::new(seg_ptr, raw_additional_header)
+    #[inline]
+    pub fn uses_huge_pages(&self) -> bool {
+        self.commit_granularity > segment::SEGMENT_SIZE
+    }
+
+    pub unsafe fn ptr_in_space(&self, ptr: AnyNonNullPtr) -> bool {
+        ptr.offset_bytes_from(self.space_begin()) >= 0
+            && self.space_end().offset_bytes_from(ptr) > 0
     }
 
     pub unsafe fn segment_by_header(&self, seg_ptr: AnyNonNullPtr) -> segment::Segment {
-        assert!(self.segment_space_begin <= seg_ptr);
+        assert!(self.space_begin() <= seg_ptr);
 
-        let seg_index = (seg_ptr.offset_bytes_from(self.segment_space_begin) as usize)
-            / segment::SEGMENT_SIZE;
+        let seg_index =
+            (seg_ptr.offset_bytes_from(self.space_begin()) as usize) / segment::SEGMENT_SIZE;
         assert!(seg_index < self.next_alloc_segment_index);
 
         let raw_compact_header = self
-            .segment_compact_header_space
+            .compact_header_space()
             .add(seg_index * segment::COMPACT_HEADER_SIZE)
             .as_nonnull();
 
-        segment::Segment::new(raw_compact_header, seg_ptr)
+        segment::Segment::new(
+            segment::SegmentIndex::new(seg_index),
+            raw_compact_header,
+            seg_ptr,
+        )
+    }
+
+    #[inline]
+    pub unsafe fn segment_by_inner_ptr(&self, ptr: AnyNonNullPtr) -> segment::Segment {
+        let seg_addr = util::bits::max_aligned_size(ptr.as_addr(), segment::SEGMENT_SIZE);
+        let seg_ptr = AnyNonNullPtr::new(NonNull::new_unchecked(seg_addr as *mut ()));
+        self.segment_by_header(seg_ptr)
     }
 
-    pub unsafe fn is_last_segment(&self, seg: segment::Segment) -> bool {
-        let seg_index = (seg.seg_ptr().offset_bytes_from(self.segment_space_begin) as usize)
-            / segment::SEGMENT_SIZE;
+    #[inline]
+    pub unsafe fn segment_by_index(&self, index: segment::SegmentIndex) -> segment::Segment {
+        let seg_index = index.get();
         assert!(seg_index < self.next_alloc_segment_index);
 
-        seg_index == self.next_alloc_segment_index - 1
+        let raw_compact_header = self
+            .compact_header_space()
+            .add(seg_index * segment::COMPACT_HEADER_SIZE)
+            .as_nonnull();
+        let raw_additional_header = self.space_begin().add(seg_index * segment::SEGMENT_SIZE);
+
+        segment::Segment::new(index, raw_compact_header, raw_additional_header)
+    }
+
+    #[inline]
+    unsafe fn max_segment_count(&self) -> usize {
+        self.space_size / segment::SEGMENT_SIZE
     }
 
+    #[inline]
     pub unsafe fn alloc_new_segment<Env: SysMemEnv>(
         &mut self,
         env: &mut Env,
     ) -> Result<Option<segment::Segment>, Box<dyn Error>> {
-        if self.next_alloc_segment_compact_header_index == self.next_alloc_segment_index {
+        self.alloc_new_segments(env, 1)
+    }
+
+    pub unsafe fn alloc_new_segments<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        segment_count: usize,
+    ) -> Result<Option<segment::Segment>, Box<dyn Error>> {
+        assert!(segment_count > 0);
+
+        if self.max_segment_count() - self.next_alloc_segment_index < segment_count {
+            return Ok(None);
+        }
+
+        let next_end_segment_index = self.next_alloc_segment_index + segment_count;
+        while self.next_alloc_segment_compact_header_index < next_end_segment_index {
             if !self.alloc_new_segment_compact_headers(env)? {
                 return Ok(None);
             }
         }
-
-        if self.available_size < segment::SEGMENT_SIZE {
-            return Ok(None);
+        while self.next_commit_segment_index < next_end_segment_index {
+            if !self.commit_new_segments(env)? {
+                return Ok(None);
+            }
         }
 
         let next_alloc_segment_index = self.next_alloc_segment_index;
         let new_segment_compact_header_space_begin = self
-            .segment_compact_header_space
+            .compact_header_space()
             .add(next_alloc_segment_index * segment::COMPACT_HEADER_SIZE);
         let new_segment_space_begin = self
-            .segment_space_begin
+            .space_begin()
             .add(next_alloc_segment_index * segment::SEGMENT_SIZE);
-        env.commit(new_segment_space_begin, segment::SEGMENT_SIZE)?;
-        self.available_size -= segment::SEGMENT_SIZE;
 
-        self.next_alloc_segment_index += 1;
+        self.next_alloc_segment_index = next_end_segment_index;
 
         Ok(Some(segment::Segment::new(
+            segment::SegmentIndex::new(next_alloc_segment_index),
             new_segment_compact_header_space_begin.as_nonnull(),
             new_segment_space_begin,
         )))
     }
 
+    pub unsafe fn decommit_segment<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        seg: segment::Segment,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        self.decommit_segments(env, seg, 1)
+    }
+
+    pub unsafe fn decommit_segments<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        seg: segment::Segment,
+        segment_count: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        if !self.uses_huge_pages() {
+            return env.soft_decommit(seg.seg_ptr(), segment_count * segment::SEGMENT_SIZE);
+        }
+
+        // Decommitting a part of a huge page splits it, so huge pages are decommitted once all of their segments are free.
+        let huge_page_segment_count = self.commit_granularity / segment::SEGMENT_SIZE;
+        let freed_segments = seg.index.get()..seg.index.get() + segment_count;
+        let mut page_begin_index =
+            util::bits::max_aligned_size(freed_segments.start, huge_page_segment_count);
+        let mut state: Option<CommitState> = None;
+        while page_begin_index < freed_segments.end {
+            let page_segments = page_begin_index..page_begin_index + huge_page_segment_count;
+            let page_state =
+                self.decommit_huge_page_if_free(env, &page_segments, &freed_segments)?;
+            state = Some(state.map_or(page_state, |state| state.merge(page_state)));
+            page_begin_index = page_segments.end;
+        }
+
+        Ok(state.unwrap_or(CommitState::Committed))
+    }
+
+    unsafe fn decommit_huge_page_if_free<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+        page_segments: &Range<usize>,
+        freed_segments: &Range<usize>,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        if !self.for_each_free_segment_head(page_segments, freed_segments, |_| {}) {
+            return Ok(CommitState::Committed);
+        }
+
+        let page_state = env.soft_decommit(
+            self.space_begin()
+                .add(page_segments.start * segment::SEGMENT_SIZE),
+            self.commit_granularity,
+        )?;
+
+        // The free neighbors in the huge page are decommitted together.
+        self.for_each_free_segment_head(page_segments, freed_segments, |mut head_seg| {
+            if head_seg.status() == segment::Status::Free {
+                head_seg.set_commit_state(head_seg.commit_state().merge(page_state));
+            } else {
+                let (span_segment_count, span_state) = head_seg.free_span();
+                head_seg.set_free_span(span_segment_count, span_state.merge(page_state));
+            }
+        });
+        Ok(page_state)
+    }
+
+    // Calls `f` with the first segments of free segments and free spans in the range except the freed ones,
+    // or returns false if any segment in it is used or kept.
+    unsafe fn for_each_free_segment_head<F>(
+        &self,
+        segments: &Range<usize>,
+        freed_segments: &Range<usize>,
+        mut f: F,
+    ) -> bool
+    where
+        F: FnMut(segment::Segment),
+    {
+        // Segments not allocated yet are going to be used soon.
+        if segments.end > self.next_alloc_segment_index {
+            return false;
+        }
+
+        let mut index = segments.start;
+        while index < segments.end {
+            if freed_segments.contains(&index) {
+                index = freed_segments.end;
+                continue;
+            }
+
+            let mut head_index = index;
+            // The range may begin in the middle of a free span, whose first segment is before it.
+            while head_index > 0 && self.status_by_index(head_index) == segment::Status::InSpan {
+                head_index -= 1;
+            }
+            let head_seg = self.segment_by_index(segment::SegmentIndex::new(head_index));
+            match head_seg.status() {
+                segment::Status::Free => index = head_index + 1,
+                segment::Status::FreeSpan => index = head_index + head_seg.free_span().0,
+                _ => return false,
+            }
+            f(head_seg);
+        }
+        true
+    }
+
+    #[inline]
+    unsafe fn status_by_index(&self, index: usize) -> segment::Status {
+        self.segment_by_index(segment::SegmentIndex::new(index))
+            .status()
+    }
+
+    unsafe fn commit_new_segments<Env: SysMemEnv>(
+        &mut self,
+        env: &mut Env,
+    ) -> Result<bool, Box<dyn Error>> {
+        let max_segment_count = self.max_segment_count();
+        let granularity_segment_count = self.commit_granularity / segment::SEGMENT_SIZE;
+
+        // Commit up to the next boundary of the granularity, so that huge pages are filled.
+        let commit_segment_count = (granularity_segment_count
+            - self.next_commit_segment_index % granularity_segment_count)
+            .min(max_segment_count - self.next_commit_segment_index)
+            .min(self.available_size / segment::SEGMENT_SIZE);
+        if commit_segment_count == 0 {
+            return Ok(false);
+        }
+
+        let commit_space_begin = self
+            .space_begin()
+            .add(self.next_commit_segment_index * segment::SEGMENT_SIZE);
+        let commit_size = commit_segment_count * segment::SEGMENT_SIZE;
+        env.commit(commit_space_begin, commit_size)?;
+        self.available_size -= commit_size;
+        self.next_commit_segment_index += commit_segment_count;
+
+        if self.uses_huge_pages() && !env.advise_huge_pages(commit_space_begin, commit_size)? {
+            // THP is disabled, so fall back to commit each segment.
+            self.commit_granularity = segment::SEGMENT_SIZE;
+        }
+
+        Ok(true)
+    }
+
     unsafe fn alloc_new_segment_compact_headers<Env: SysMemEnv>(
         &mut self,
         env: &mut Env,
@@ -124,7 +337,7 @@ impl SegmentSpace {
         }
 
         let new_segment_compact_header_space_begin = self
-            .segment_compact_header_space
+            .compact_header_space()
             .add(self.next_alloc_segment_compact_header_index * segment::COMPACT_HEADER_SIZE);
         let new_segment_compact_headers_count = self.page_size / segment::COMPACT_HEADER_SIZE;
         env.commit(new_segment_compact_header_space_begin, self.page_size)?;
@@ -1,31 +1,42 @@
-use std::ptr::NonNull;
-
 use crate::internal::layout::constants::ALIGNMENT_SIZE;
 use crate::internal::layout::segment;
 use crate::util;
 
-pub const CLASS_COUNT: usize = 32;
+include!(concat!(env!("OUT_DIR"), "/size_classes.rs"));
+
+pub const CLASS_COUNT: usize = match CUSTOM_SIZE_OF_CLASS {
+    Some(size_of_class) => size_of_class.len(),
+    None => DEFAULT_SIZE_OF_CLASS.len(),
+};
+const MAX_INTERNAL_WASTE_PERCENT: usize = 50;
 
 #[derive(Debug)]
 pub struct SubHeap {
-    pub free_segments_begin: *mut segment::CompactHeader,
-    pub free_segments_end: *mut segment::CompactHeader,
+    pub free_segments_begin: Option<segment::SegmentIndex>,
+    pub free_segments_end: Option<segment::SegmentIndex>,
+    pub segment_count: usize,
+    pub partial_segment_count: usize,
+    pub used_block_count: usize,
 }
 
 impl SubHeap {
     pub fn init() -> Self {
         Self {
-            free_segments_begin: std::ptr::null_mut(),
-            free_segments_end: std::ptr::null_mut(),
+            free_segments_begin: None,
+            free_segments_end: None,
+            segment_count: 0,
+            partial_segment_count: 0,
+            used_block_count: 0,
         }
     }
 
-    pub fn next_free_segment(&self) -> Option<NonNull<segment::CompactHeader>> {
-        NonNull::new(self.free_segments_begin)
+    pub fn next_free_segment(&self) -> Option<segment::SegmentIndex> {
+        self.free_segments_begin
     }
 }
 
-pub const SUBHEAP_SIZE_OF_CLASS: [usize; CLASS_COUNT] = [
+#[allow(clippy::identity_op)]
+const DEFAULT_SIZE_OF_CLASS: [usize; 32] = [
     // 0-3
     0x0001 * ALIGNMENT_SIZE,
     0x0002 * ALIGNMENT_SIZE,
@@ -64,23 +75,168 @@ pub const SUBHEAP_SIZE_OF_CLASS: [usize; CLASS_COUNT] = [
     0x0800 * ALIGNMENT_SIZE,
 ];
 
+pub const SUBHEAP_SIZE_OF_CLASS: [usize; CLASS_COUNT] = size_of_class_table();
+const MAX_CLASS_ALIGNED_SIZE: usize = SUBHEAP_SIZE_OF_CLASS[CLASS_COUNT - 1] / ALIGNMENT_SIZE;
+const CLASS_OF_ALIGNED_SIZE: [u8; MAX_CLASS_ALIGNED_SIZE + 1] = class_of_aligned_size_table();
+
+// Larger blocks are on spans, which take whole segments, so custom tables must reach the default largest class.
+const MIN_LARGEST_CLASS_SIZE: usize = DEFAULT_SIZE_OF_CLASS[DEFAULT_SIZE_OF_CLASS.len() - 1];
+
+const _: () = match validate_size_of_class_table(&SUBHEAP_SIZE_OF_CLASS) {
+    Ok(()) => {}
+    Err(reason) => panic!("{}", reason),
+};
+
+const fn size_of_class_table() -> [usize; CLASS_COUNT] {
+    let mut table = [0; CLASS_COUNT];
+    let mut class_of_size = 0;
+    while class_of_size < CLASS_COUNT {
+        table[class_of_size] = match CUSTOM_SIZE_OF_CLASS {
+            Some(size_of_class) => size_of_class[class_of_size],
+            None => DEFAULT_SIZE_OF_CLASS[class_of_size],
+        };
+        class_of_size += 1;
+    }
+    table
+}
+
+const fn class_of_aligned_size_table() -> [u8; MAX_CLASS_ALIGNED_SIZE + 1] {
+    let mut table = [0; MAX_CLASS_ALIGNED_SIZE + 1];
+    let mut class_of_size = 0;
+    let mut align_size = 0;
+    while align_size <= MAX_CLASS_ALIGNED_SIZE {
+        if align_size * ALIGNMENT_SIZE > SUBHEAP_SIZE_OF_CLASS[class_of_size] {
+            class_of_size += 1;
+        }
+        table[align_size] = class_of_size as u8;
+        align_size += 1;
+    }
+    table
+}
+
+const fn validate_size_of_class_table(size_of_class: &[usize]) -> Result<(), &'static str> {
+    if size_of_class.is_empty() {
+        return Err("No size classes.");
+    }
+    if size_of_class.len() > u8::MAX as usize + 1 {
+        return Err("Too many size classes.");
+    }
+
+    let mut class_of_size = 0;
+    while class_of_size < size_of_class.len() {
+        let block_size = size_of_class[class_of_size];
+        if block_size == 0 {
+            return Err("A size class is empty.");
+        }
+        if !util::bits::is_aligned(block_size, ALIGNMENT_SIZE) {
+            return Err("A size class is not aligned.");
+        }
+        if class_of_size > 0 && size_of_class[class_of_size - 1] >= block_size {
+            return Err("Size classes are not sorted.");
+        }
+
+        let max_internal_waste = max_internal_waste_size(size_of_class, class_of_size);
+        if max_internal_waste * 100 > block_size * MAX_INTERNAL_WASTE_PERCENT {
+            return Err("A size class wastes too much space for blocks.");
+        }
+        class_of_size += 1;
+    }
+
+    if size_of_class[size_of_class.len() - 1] < MIN_LARGEST_CLASS_SIZE {
+        return Err("The largest size class is too small, and smaller blocks would be on spans.");
+    }
+    Ok(())
+}
+
+// The worst case is the smallest size which does not fit the previous class.
+pub const fn max_internal_waste_size_of_class(class_of_size: usize) -> usize {
+    max_internal_waste_size(&SUBHEAP_SIZE_OF_CLASS, class_of_size)
+}
+
+const fn max_internal_waste_size(size_of_class: &[usize], class_of_size: usize) -> usize {
+    let block_size = size_of_class[class_of_size];
+    if class_of_size == 0 {
+        block_size - ALIGNMENT_SIZE
+    } else {
+        block_size - (size_of_class[class_of_size - 1] + ALIGNMENT_SIZE)
+    }
+}
+
 pub const fn class_of_size(size: usize) -> Option<usize> {
     assert!(util::bits::is_aligned(size, ALIGNMENT_SIZE));
 
     let align_size = size / ALIGNMENT_SIZE;
 
-    if align_size > 0x800 {
+    if align_size > MAX_CLASS_ALIGNED_SIZE {
         None
-    } else if align_size > 0x100 {
-        let i = (align_size - 1) / 0x100;
-        Some(24 + i)
-    } else if align_size > 0x10 {
-        let i = (align_size - 1) / 0x10;
-        Some(9 + i)
-    } else if align_size > 0x4 {
-        let i = (align_size - 1) / 0x2;
-        Some(2 + i)
     } else {
-        Some(align_size - 1)
+        Some(CLASS_OF_ALIGNED_SIZE[align_size] as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Up to the default largest class, with the class sizes doubled.
+    fn doubling_table() -> Vec<usize> {
+        let mut table = vec![ALIGNMENT_SIZE];
+        while table[table.len() - 1] < MIN_LARGEST_CLASS_SIZE {
+            table.push(table[table.len() - 1] * 2);
+        }
+        table
+    }
+
+    #[test]
+    fn accepts_the_default_and_doubling_tables() {
+        assert_eq!(validate_size_of_class_table(&DEFAULT_SIZE_OF_CLASS), Ok(()));
+        assert_eq!(validate_size_of_class_table(&doubling_table()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_tables_stopping_short_of_spans() {
+        let table: Vec<usize> = [8, 16, 24, 40, 72, 136]
+            .iter()
+            .map(|size| size / 8 * ALIGNMENT_SIZE)
+            .collect();
+        assert!(validate_size_of_class_table(&table)
+            .unwrap_err()
+            .contains("too small"));
+    }
+
+    #[test]
+    fn rejects_tables_wasting_space() {
+        let mut table = doubling_table();
+        table[0] = 4 * ALIGNMENT_SIZE;
+        table.remove(1);
+        table.remove(1);
+        assert!(validate_size_of_class_table(&table)
+            .unwrap_err()
+            .contains("wastes"));
+
+        let mut table = doubling_table();
+        table.remove(3);
+        assert!(validate_size_of_class_table(&table)
+            .unwrap_err()
+            .contains("wastes"));
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        let mut table = doubling_table();
+        table.swap(1, 2);
+        assert_eq!(
+            validate_size_of_class_table(&table),
+            Err("Size classes are not sorted.")
+        );
+
+        let mut table = doubling_table();
+        table[1] += 1;
+        assert_eq!(
+            validate_size_of_class_table(&table),
+            Err("A size class is not aligned.")
+        );
+
+        assert_eq!(validate_size_of_class_table(&[]), Err("No size classes."));
     }
 }
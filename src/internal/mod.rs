@@ -1,2 +1,5 @@
 pub mod allocator;
+pub mod error;
 pub mod layout;
+#[cfg(feature = "site-tracking")]
+pub mod sites;
@@ -0,0 +1,216 @@
+use std::backtrace::Backtrace;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+
+// The same as the default of jemalloc, for allocators which are opened without the config.
+pub const DEFAULT_SAMPLE_INTERVAL: usize = 512 << 10;
+
+#[derive(Debug)]
+struct SampledAllocation {
+    size: usize,
+    backtrace: Backtrace,
+}
+
+// Allocations are sampled once per the interval of allocated bytes on average, and all of them with zero.
+// The intervals are random in the exponential distribution, not to alias with periodic allocations.
+// The side table is on the system allocator, not to be tracked itself.
+#[derive(Debug)]
+pub struct SiteTracker {
+    sample_interval: usize,
+    bytes_until_sample: usize,
+    random_state: u64,
+    allocations: HashMap<usize, SampledAllocation>,
+}
+
+#[derive(Debug)]
+pub struct SiteStats {
+    pub backtrace: String,
+    pub count: usize,
+    pub size: usize,
+    // Sizes divided by the probabilities to be sampled.
+    pub estimated_size: usize,
+}
+
+// Sites are sorted by the estimated sizes, the largest first.
+#[derive(Debug)]
+pub struct SiteReport {
+    pub sample_interval: usize,
+    pub sites: Vec<SiteStats>,
+}
+
+impl fmt::Display for SiteReport {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            formatter,
+            "{} allocation sites, sampled every {} bytes",
+            self.sites.len(),
+            self.sample_interval
+        )?;
+        for site in &self.sites {
+            writeln!(
+                formatter,
+                "~{} bytes in {} sampled allocations of {} bytes at:\n{}",
+                site.estimated_size, site.count, site.size, site.backtrace
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl SiteTracker {
+    pub fn new(sample_interval: usize) -> Self {
+        let mut tracker = Self {
+            sample_interval,
+            bytes_until_sample: 0,
+            random_state: 0x9e37_79b9_7f4a_7c15,
+            allocations: HashMap::new(),
+        };
+        tracker.bytes_until_sample = tracker.next_bytes_until_sample();
+        tracker
+    }
+
+    fn next_bytes_until_sample(&mut self) -> usize {
+        // xorshift64, which is enough for sampling.
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 7;
+        self.random_state ^= self.random_state << 17;
+
+        let uniform = (self.random_state >> 11) as f64 / (1u64 << 53) as f64;
+        (-(1.0 - uniform).ln() * self.sample_interval as f64) as usize
+    }
+
+    fn estimated_size(&self, size: usize) -> usize {
+        if self.sample_interval == 0 {
+            return size;
+        }
+        let probability = 1.0 - (-(size as f64) / self.sample_interval as f64).exp();
+        (size as f64 / probability) as usize
+    }
+
+    pub fn record_alloc(&mut self, addr: usize, size: usize) {
+        if size < self.bytes_until_sample {
+            self.bytes_until_sample -= size;
+            return;
+        }
+
+        self.bytes_until_sample = self.next_bytes_until_sample();
+        self.allocations.insert(
+            addr,
+            SampledAllocation {
+                size,
+                backtrace: Backtrace::force_capture(),
+            },
+        );
+    }
+
+    #[inline]
+    pub fn record_free(&mut self, addr: usize) {
+        self.allocations.remove(&addr);
+    }
+
+    // Backtraces are resolved here, so that it is slow for many sampled allocations.
+    pub fn report(&self) -> SiteReport {
+        let mut sites: HashMap<String, SiteStats> = HashMap::new();
+        for allocation in self.allocations.values() {
+            let backtrace = allocation.backtrace.to_string();
+            let site = sites.entry(backtrace.clone()).or_insert_with(|| SiteStats {
+                backtrace,
+                count: 0,
+                size: 0,
+                estimated_size: 0,
+            });
+            site.count += 1;
+            site.size += allocation.size;
+            site.estimated_size += self.estimated_size(allocation.size);
+        }
+
+        let mut sites: Vec<SiteStats> = sites.into_values().collect();
+        sites.sort_by_key(|site| Reverse(site.estimated_size));
+        SiteReport {
+            sample_interval: self.sample_interval,
+            sites,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each function is a call site of its own in backtraces.
+    #[inline(never)]
+    fn record_alloc_at_site_a(tracker: &mut SiteTracker, addr: usize, size: usize) {
+        tracker.record_alloc(addr, size)
+    }
+
+    #[inline(never)]
+    fn record_alloc_at_site_b(tracker: &mut SiteTracker, addr: usize, size: usize) {
+        tracker.record_alloc(addr, size)
+    }
+
+    #[test]
+    fn samples_every_allocation_with_zero_interval() {
+        let mut tracker = SiteTracker::new(0);
+        for index in 0..100 {
+            tracker.record_alloc(index * 8, 8);
+        }
+        assert_eq!(tracker.allocations.len(), 100);
+    }
+
+    #[test]
+    fn samples_about_once_per_interval() {
+        let sample_interval = 4 << 10;
+        let mut tracker = SiteTracker::new(sample_interval);
+        let (count, size) = (10000, 64);
+        for index in 0..count {
+            tracker.record_alloc(index * size, size);
+        }
+
+        let expected_count = count * size / sample_interval;
+        let sampled_count = tracker.allocations.len();
+        assert!(sampled_count > expected_count / 2, "{}", sampled_count);
+        assert!(sampled_count < expected_count * 2, "{}", sampled_count);
+        let estimated_size: usize = tracker
+            .report()
+            .sites
+            .iter()
+            .map(|site| site.estimated_size)
+            .sum();
+        assert!(estimated_size > count * size / 2, "{}", estimated_size);
+        assert!(estimated_size < count * size * 2, "{}", estimated_size);
+    }
+
+    #[test]
+    fn removes_freed_allocations() {
+        let mut tracker = SiteTracker::new(0);
+        tracker.record_alloc(0x1000, 8);
+        tracker.record_alloc(0x2000, 8);
+        tracker.record_free(0x1000);
+        assert_eq!(tracker.allocations.len(), 1);
+        assert!(tracker.allocations.contains_key(&0x2000));
+
+        tracker.record_free(0x2000);
+        assert!(tracker.report().sites.is_empty());
+    }
+
+    #[test]
+    fn groups_allocations_by_sites() {
+        let mut tracker = SiteTracker::new(0);
+        for index in 0..3 {
+            record_alloc_at_site_a(&mut tracker, index * 64, 64);
+        }
+        for index in 3..5 {
+            record_alloc_at_site_b(&mut tracker, index * 64, 16);
+        }
+
+        let report = tracker.report();
+        assert_eq!(report.sites.len(), 2);
+        let counts_and_sizes: Vec<(usize, usize, usize)> = report
+            .sites
+            .iter()
+            .map(|site| (site.count, site.size, site.estimated_size))
+            .collect();
+        assert_eq!(counts_and_sizes, [(3, 192, 192), (2, 32, 32)]);
+    }
+}
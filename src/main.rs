@@ -1,4 +1,6 @@
 use std::error::Error;
+use std::mem::size_of;
+use std::path::Path;
 use std::result::Result;
 
 mod allocator;
@@ -11,10 +13,50 @@ use crate::allocator::Allocator;
 const ALLOC_CONFIG: allocator::Config = allocator::Config {
     min_heap_size: 1 << 18,
     max_heap_size: 500 << 20,
+    use_huge_pages: false,
+    large_blocks_cache_size: 8 << 20,
+    poison_blocks: false,
+    quarantine_size: 0,
+    large_block_guard_page: internal::layout::arena::GuardPage::None,
+    leak_sink: None,
+    #[cfg(feature = "site-tracking")]
+    site_sample_interval: 512 << 10,
 };
 
+const HEAP_FILE_SIZE: usize = 64 << 20;
+const HEAP_FILE_BASE_ADDR: usize = 0x5800_0000_0000;
+
 fn main() {
-    unsafe { main_try() }.unwrap();
+    match std::env::args_os().nth(1) {
+        Some(path) => unsafe { count_runs(Path::new(&path)) }.unwrap(),
+        None => unsafe { main_try() }.unwrap(),
+    }
+}
+
+// Keeps the run count in the only live block of the arena in the file, which is found again on reopening.
+unsafe fn count_runs(path: &Path) -> Result<(), Box<dyn Error>> {
+    let env = sys::file::SysMemEnvForFile::open(path, HEAP_FILE_SIZE, HEAP_FILE_BASE_ADDR)?;
+    let mut manager = if env.is_created() {
+        let config = allocator::Config {
+            max_heap_size: 32 << 20,
+            ..ALLOC_CONFIG
+        };
+        let mut manager = allocator::init(env, config)?;
+        let mut ptr = manager.alloc(size_of::<usize>())?;
+        *ptr.as_mut::<usize>() = 0;
+        manager
+    } else {
+        let root_space = env.root_space();
+        allocator::open(env, root_space)?
+    };
+
+    let mut counter_ptr = None;
+    manager.for_each_live_block(|ptr, _, _| counter_ptr = Some(ptr));
+    let counter: &mut usize = counter_ptr.ok_or("No counter block.")?.as_mut();
+    *counter += 1;
+    println!("run count: {}", *counter);
+
+    manager.env().sync()
 }
 
 unsafe fn main_try() -> Result<(), Box<dyn Error>> {
@@ -32,5 +74,19 @@ unsafe fn main_try() -> Result<(), Box<dyn Error>> {
     *item = 10;
     println!("{:?}", *item);
 
-    Ok(())
+    let aligned_ptr = manager.alloc_aligned(1 << 20, 1 << 16)?;
+    println!("aligned block: {:?}", aligned_ptr);
+    manager.free(aligned_ptr)?;
+    manager.free(ptr)?;
+
+    let stats = manager.stats();
+    println!(
+        "used budget: {} / {} bytes",
+        stats.used_budget_size, stats.reserved_size
+    );
+    println!(
+        "external fragmentation: {:.3}",
+        manager.fragmentation_report().external_fragmentation()
+    );
+    manager.release()
 }
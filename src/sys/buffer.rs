@@ -0,0 +1,290 @@
+use std::error::Error;
+use std::io;
+use std::ops::Range;
+use std::ptr::NonNull;
+use std::result::Result;
+
+use crate::sys::free_ranges::FreeRanges;
+use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
+use crate::sys::SysMemEnv;
+use crate::util;
+
+// Spaces are carved out of the free ranges of the buffer, and released spaces are merged back into them.
+// The ranges are offsets from the begin of the buffer, and sorted by them.
+#[derive(Debug)]
+pub struct SysMemEnvForBuffer {
+    page_size: usize,
+    buffer_begin: AnyNonNullPtr,
+    buffer_size: usize,
+    free_ranges: FreeRanges,
+}
+
+impl SysMemEnvForBuffer {
+    pub fn new(buffer: &'static mut [u8], page_size: usize) -> Self {
+        assert!(util::bits::is_power_of_2(page_size));
+
+        let raw_begin_addr = buffer.as_ptr() as usize;
+        let raw_end_addr = raw_begin_addr + buffer.len();
+        let begin_addr = util::bits::min_aligned_size(raw_begin_addr, page_size).min(raw_end_addr);
+        let buffer_size = util::bits::max_aligned_size(raw_end_addr - begin_addr, page_size);
+
+        let raw_begin_ptr = AnyNonNullPtr::new(NonNull::from(buffer).cast::<u8>());
+        Self {
+            page_size,
+            buffer_begin: unsafe { raw_begin_ptr.add(begin_addr - raw_begin_addr) },
+            buffer_size,
+            free_ranges: FreeRanges::new(0..buffer_size),
+        }
+    }
+
+    #[inline]
+    pub fn used_size(&self) -> usize {
+        self.buffer_size - self.remaining_size()
+    }
+
+    // Including the spaces which are too fragmented to be used at once.
+    #[inline]
+    pub fn remaining_size(&self) -> usize {
+        self.free_ranges.total_size()
+    }
+
+    fn buffer_overflow(&self) -> Box<dyn Error> {
+        Box::new(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            "Over the buffer size.",
+        ))
+    }
+
+    fn invalid_space(&self, reason: &str) -> Box<dyn Error> {
+        Box::new(io::Error::new(io::ErrorKind::InvalidInput, reason))
+    }
+
+    // Spaces are zeroed on commit instead, so that reserving a large space is cheap.
+    unsafe fn take_space(
+        &mut self,
+        len: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        if len == 0 || !util::bits::is_aligned(len, self.page_size) {
+            return Err(self.invalid_space("The length is not a multiple of the page size."));
+        }
+
+        match self
+            .free_ranges
+            .take_lowest(self.buffer_begin.as_addr(), len, alignment_size)
+        {
+            Some(offset) => Ok(self.buffer_begin.add(offset)),
+            None => Err(self.buffer_overflow()),
+        }
+    }
+
+    // Returns the offsets of the space, which must be taken and not released yet.
+    fn used_range(&self, addr: AnyNonNullPtr, len: usize) -> Result<Range<usize>, Box<dyn Error>> {
+        if !util::bits::is_aligned(addr.as_addr(), self.page_size)
+            || !util::bits::is_aligned(len, self.page_size)
+        {
+            return Err(self.invalid_space("The space is not aligned to pages."));
+        }
+
+        let begin_addr = self.buffer_begin.as_addr();
+        if addr.as_addr() < begin_addr || addr.as_addr() + len > begin_addr + self.buffer_size {
+            return Err(self.invalid_space("The space is out of the buffer."));
+        }
+
+        let offset = addr.as_addr() - begin_addr;
+        let range = offset..offset + len;
+        if self.free_ranges.overlaps(&range) {
+            return Err(self.invalid_space("The space is not reserved."));
+        }
+
+        Ok(range)
+    }
+}
+
+impl SysMemEnv for SysMemEnvForBuffer {
+    unsafe fn get_pagesize(&mut self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.page_size)
+    }
+
+    unsafe fn reserve(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        self.take_space(len, self.page_size)
+    }
+
+    unsafe fn alloc(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        let ptr = self.take_space(len, self.page_size)?;
+        self.commit(ptr, len)?;
+        Ok(ptr)
+    }
+
+    // Committed spaces look like new pages, which are filled with zero.
+    unsafe fn commit(&mut self, mut addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        self.used_range(addr, len)?;
+        addr.as_mut_ptr::<u8>().write_bytes(0, len);
+        Ok(())
+    }
+
+    unsafe fn soft_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        self.used_range(addr, len)?;
+        Ok(CommitState::SoftDecommitted)
+    }
+
+    unsafe fn hard_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        self.used_range(addr, len)?;
+        Ok(CommitState::HardDecommitted)
+    }
+
+    unsafe fn release(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        let range = self.used_range(addr, len)?;
+        self.free_ranges.insert(range);
+        Ok(())
+    }
+
+    unsafe fn reserve_aligned_space(
+        &mut self,
+        space_size: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        if !util::bits::is_aligned(space_size, alignment_size) {
+            return Err(self.invalid_space("The length is not a multiple of the alignment."));
+        }
+
+        // No need to over-reserve, since free ranges are known.
+        self.take_space(space_size, alignment_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::allocator::SampleAlloc;
+    use crate::internal::layout::arena;
+
+    const PAGE_SIZE: usize = 4096;
+
+    // The buffer is freed after the env, so that the tests are clean under Miri.
+    // An extra page is for the alignment of the begin.
+    fn with_buffer_env<F>(buffer_size: usize, f: F)
+    where
+        F: FnOnce(&mut SysMemEnvForBuffer),
+    {
+        let raw_buffer = Box::into_raw(vec![0xffu8; buffer_size + PAGE_SIZE].into_boxed_slice());
+        unsafe {
+            let mut env = SysMemEnvForBuffer::new(&mut *raw_buffer, PAGE_SIZE);
+            f(&mut env);
+            drop(env);
+            drop(Box::from_raw(raw_buffer));
+        }
+    }
+
+    #[test]
+    fn reuses_spaces_released_out_of_order() {
+        with_buffer_env(64 * PAGE_SIZE, |env| unsafe {
+            let first_ptr = env.reserve(4 * PAGE_SIZE).unwrap();
+            let second_ptr = env.reserve(4 * PAGE_SIZE).unwrap();
+            let third_ptr = env.reserve(4 * PAGE_SIZE).unwrap();
+            assert_eq!(env.used_size(), 12 * PAGE_SIZE);
+
+            env.release(second_ptr, 4 * PAGE_SIZE).unwrap();
+            env.release(first_ptr, 4 * PAGE_SIZE).unwrap();
+            assert_eq!(env.reserve(8 * PAGE_SIZE).unwrap(), first_ptr);
+
+            env.release(first_ptr, 8 * PAGE_SIZE).unwrap();
+            env.release(third_ptr, 4 * PAGE_SIZE).unwrap();
+            assert_eq!(env.used_size(), 0);
+            assert_eq!(env.reserve(64 * PAGE_SIZE).unwrap(), first_ptr);
+        });
+    }
+
+    #[test]
+    fn reserves_aligned_spaces() {
+        with_buffer_env(64 * PAGE_SIZE, |env| unsafe {
+            env.reserve(PAGE_SIZE).unwrap();
+            let ptr = env
+                .reserve_aligned_space(8 * PAGE_SIZE, 8 * PAGE_SIZE)
+                .unwrap();
+            assert_eq!(ptr.as_addr() % (8 * PAGE_SIZE), 0);
+
+            // The space skipped for the alignment is still available.
+            assert_eq!(env.used_size(), 9 * PAGE_SIZE);
+        });
+    }
+
+    #[test]
+    fn zeroes_spaces_on_commit() {
+        with_buffer_env(16 * PAGE_SIZE, |env| unsafe {
+            let mut ptr = env.reserve(4 * PAGE_SIZE).unwrap();
+            env.commit(ptr, 4 * PAGE_SIZE).unwrap();
+            let bytes = std::slice::from_raw_parts_mut(ptr.as_mut_ptr::<u8>(), 4 * PAGE_SIZE);
+            assert!(bytes.iter().all(|byte| *byte == 0));
+            bytes.fill(1);
+
+            env.release(ptr, 4 * PAGE_SIZE).unwrap();
+            let mut ptr = env.alloc(4 * PAGE_SIZE).unwrap();
+            let bytes = std::slice::from_raw_parts(ptr.as_mut_ptr::<u8>(), 4 * PAGE_SIZE);
+            assert!(bytes.iter().all(|byte| *byte == 0));
+        });
+    }
+
+    #[test]
+    fn rejects_invalid_spaces() {
+        with_buffer_env(16 * PAGE_SIZE, |env| unsafe {
+            let ptr = env.reserve(4 * PAGE_SIZE).unwrap();
+
+            assert!(env.reserve(PAGE_SIZE + 1).is_err());
+            assert!(env.reserve(32 * PAGE_SIZE).is_err());
+            assert!(env.commit(ptr.add(1), PAGE_SIZE).is_err());
+            assert!(env.commit(ptr, 8 * PAGE_SIZE).is_err());
+            assert!(env.release(ptr.add(4 * PAGE_SIZE), PAGE_SIZE).is_err());
+
+            env.release(ptr, 4 * PAGE_SIZE).unwrap();
+            assert!(env.release(ptr, 4 * PAGE_SIZE).is_err());
+            assert!(env.soft_decommit(ptr, PAGE_SIZE).is_err());
+        });
+    }
+
+    #[test]
+    fn runs_the_allocator_on_the_buffer() {
+        with_buffer_env(16 << 20, |env| unsafe {
+            let config = arena::Config {
+                min_heap_size: 1 << 18,
+                max_heap_size: 8 << 20,
+                keep_segments_count: 4,
+                use_huge_pages: false,
+                large_blocks_cache_size: 2 << 20,
+                poisons_blocks: false,
+                quarantine_size: 0,
+                large_block_guard_page: arena::GuardPage::None,
+            };
+            let mut manager = SampleAlloc::init(env, config).unwrap();
+
+            let mut ptrs = Vec::new();
+            for size in [24, 4 << 10, 300 << 10, (1 << 20) + 8, (2 << 20) + 8] {
+                let mut ptr = manager.alloc_with_env(env, size).unwrap();
+                ptr.as_mut_ptr::<u8>().write_bytes(1, size);
+                ptrs.push(ptr);
+            }
+            let aligned_ptr = manager
+                .alloc_aligned_with_env(env, 64 << 10, 64 << 10)
+                .unwrap();
+            assert_eq!(aligned_ptr.as_addr() % (64 << 10), 0);
+            ptrs.push(aligned_ptr);
+
+            for ptr in ptrs.into_iter().rev() {
+                manager.free_with_env(env, ptr).unwrap();
+            }
+            assert!(manager.verify().is_ok());
+            manager.release_with_env(env).unwrap();
+
+            assert_eq!(env.used_size(), 0);
+        });
+    }
+}
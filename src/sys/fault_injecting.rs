@@ -0,0 +1,194 @@
+use std::error::Error;
+use std::fmt;
+use std::result::Result;
+
+use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
+use crate::sys::SysCall;
+use crate::sys::SysCallKind;
+use crate::sys::SysMemEnv;
+
+#[derive(Debug)]
+pub struct InjectedFault {
+    pub call_index: usize,
+    pub call: SysCall,
+}
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Injected fault at the call #{}: {:?}",
+            self.call_index, self.call
+        )
+    }
+}
+
+impl Error for InjectedFault {}
+
+type Predicate = Box<dyn FnMut(&SysCall) -> bool>;
+
+// Calls are counted from 0, and faulted calls are counted too.
+pub struct SysMemEnvWithFaults<Env> {
+    inner: Env,
+    call_count: usize,
+    fail_at_call_index: Option<usize>,
+    fail_predicate: Option<Predicate>,
+}
+
+impl<Env> SysMemEnvWithFaults<Env>
+where
+    Env: SysMemEnv,
+{
+    pub fn new(inner: Env) -> Self {
+        Self {
+            inner,
+            call_count: 0,
+            fail_at_call_index: None,
+            fail_predicate: None,
+        }
+    }
+
+    #[inline]
+    pub fn inner(&self) -> &Env {
+        &self.inner
+    }
+
+    #[inline]
+    pub fn call_count(&self) -> usize {
+        self.call_count
+    }
+
+    pub fn fail_at_call(&mut self, call_index: usize) {
+        self.fail_at_call_index = Some(call_index);
+    }
+
+    pub fn fail_calls_matching<F>(&mut self, predicate: F)
+    where
+        F: FnMut(&SysCall) -> bool + 'static,
+    {
+        self.fail_predicate = Some(Box::new(predicate));
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.fail_at_call_index = None;
+        self.fail_predicate = None;
+    }
+
+    fn inject(
+        &mut self,
+        kind: SysCallKind,
+        addr: Option<AnyNonNullPtr>,
+        len: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let call_index = self.call_count;
+        self.call_count += 1;
+
+        let call = SysCall { kind, addr, len };
+        let should_fail = self.fail_at_call_index == Some(call_index)
+            || match &mut self.fail_predicate {
+                Some(predicate) => predicate(&call),
+                None => false,
+            };
+        if should_fail {
+            return Err(Box::new(InjectedFault { call_index, call }));
+        }
+
+        Ok(())
+    }
+}
+
+impl<Env> fmt::Debug for SysMemEnvWithFaults<Env>
+where
+    Env: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SysMemEnvWithFaults")
+            .field("inner", &self.inner)
+            .field("call_count", &self.call_count)
+            .field("fail_at_call_index", &self.fail_at_call_index)
+            .field("has_fail_predicate", &self.fail_predicate.is_some())
+            .finish()
+    }
+}
+
+impl<Env> SysMemEnv for SysMemEnvWithFaults<Env>
+where
+    Env: SysMemEnv,
+{
+    unsafe fn get_pagesize(&mut self) -> Result<usize, Box<dyn Error>> {
+        self.inner.get_pagesize()
+    }
+
+    unsafe fn get_huge_page_size(&mut self) -> Result<Option<usize>, Box<dyn Error>> {
+        self.inner.get_huge_page_size()
+    }
+
+    unsafe fn reserve(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        self.inject(SysCallKind::Reserve, None, len)?;
+        self.inner.reserve(len)
+    }
+
+    unsafe fn alloc(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        self.inject(SysCallKind::Alloc, None, len)?;
+        self.inner.alloc(len)
+    }
+
+    unsafe fn commit(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        self.inject(SysCallKind::Commit, Some(addr), len)?;
+        self.inner.commit(addr, len)
+    }
+
+    unsafe fn soft_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        self.inject(SysCallKind::SoftDecommit, Some(addr), len)?;
+        self.inner.soft_decommit(addr, len)
+    }
+
+    unsafe fn hard_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        self.inject(SysCallKind::HardDecommit, Some(addr), len)?;
+        self.inner.hard_decommit(addr, len)
+    }
+
+    unsafe fn release(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        self.inject(SysCallKind::Release, Some(addr), len)?;
+        self.inner.release(addr, len)
+    }
+
+    unsafe fn advise_huge_pages(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.inject(SysCallKind::AdviseHugePages, Some(addr), len)?;
+        self.inner.advise_huge_pages(addr, len)
+    }
+
+    // Delegate as one call, since the inner env may recommit in its own way.
+    unsafe fn recommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+        state: CommitState,
+    ) -> Result<(), Box<dyn Error>> {
+        self.inject(SysCallKind::Recommit, Some(addr), len)?;
+        self.inner.recommit(addr, len, state)
+    }
+
+    // Delegate as one call, since the inner env may reserve in its own way.
+    unsafe fn reserve_aligned_space(
+        &mut self,
+        space_size: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        self.inject(SysCallKind::ReserveAlignedSpace, None, space_size)?;
+        self.inner.reserve_aligned_space(space_size, alignment_size)
+    }
+}
@@ -0,0 +1,521 @@
+use std::error::Error;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::result::Result;
+
+use crate::sys::free_ranges::FreeRanges;
+use crate::sys::linux;
+use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
+use crate::sys::SysMemEnv;
+use crate::util;
+
+const FILE_MAGIC: u64 = u64::from_le_bytes(*b"SAMPLEAF");
+const FILE_FREE_RANGE_CAPACITY: usize = 254;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileRange {
+    begin: usize,
+    end: usize,
+}
+
+// Persisted at the first page, to restore the env on reopening.
+// Free ranges over the capacity are lost on reopening, which only leaks their space.
+#[repr(C)]
+struct FileHeader {
+    magic: u64,
+    file_size: usize,
+    free_range_count: usize,
+    free_ranges: [FileRange; FILE_FREE_RANGE_CAPACITY],
+}
+
+const _: () = assert!(size_of::<FileHeader>() <= 4096);
+
+// The whole file is mapped at the base address, which may differ on reopening,
+// since the arena keeps offsets rather than pointers.
+// Protections are not kept in the file, so the arena applies them again on opening.
+// Spaces are reserved from the bottom in the deterministic order, and allocated from the top.
+#[derive(Debug)]
+pub struct SysMemEnvForFile {
+    fd: RawFd,
+    page_size: usize,
+    mapping_begin: AnyNonNullPtr,
+    file_size: usize,
+    is_created: bool,
+    file_header: NonNull<FileHeader>,
+    free_ranges: FreeRanges,
+}
+
+impl SysMemEnvForFile {
+    pub unsafe fn open(
+        path: &Path,
+        file_size: usize,
+        base_addr: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let fd = linux::open_file(path)?;
+        match Self::open_by_fd(fd, file_size, base_addr) {
+            Ok(env) => Ok(env),
+            Err(err) => {
+                linux::close_file(fd)?;
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn open_by_fd(
+        fd: RawFd,
+        file_size: usize,
+        base_addr: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let page_size = linux::get_pagesize()?;
+        assert!(util::bits::is_aligned(file_size, page_size));
+        assert!(util::bits::is_aligned(base_addr, page_size));
+        assert!(file_size > page_size);
+
+        let current_file_size = linux::file_size(fd)?;
+        let is_created = current_file_size == 0;
+        if is_created {
+            linux::resize_file(fd, file_size)?;
+        } else if current_file_size != file_size {
+            return Err(invalid_file("Mismatched file size."));
+        }
+
+        let (mapping_begin, file_header) =
+            match map_file(fd, page_size, file_size, base_addr, is_created) {
+                Ok(mapped) => mapped,
+                Err(err) => {
+                    // Empty the file again, so that it is initialized on the next opening.
+                    if is_created {
+                        linux::resize_file(fd, 0)?;
+                    }
+                    return Err(err);
+                }
+            };
+
+        let mut free_ranges = FreeRanges::new(0..0);
+        let file_header_ref = file_header.as_ref();
+        for range in &file_header_ref.free_ranges[..file_header_ref.free_range_count] {
+            free_ranges.insert(range.begin..range.end);
+        }
+
+        Ok(Self {
+            fd,
+            page_size,
+            mapping_begin,
+            file_size,
+            is_created,
+            file_header,
+            free_ranges,
+        })
+    }
+
+    // Whether the file was empty, and the arena on it should be initialized rather than opened.
+    #[inline]
+    pub fn is_created(&self) -> bool {
+        self.is_created
+    }
+
+    // The first reserved space, which holds the context space of the arena.
+    #[inline]
+    pub unsafe fn root_space(&self) -> AnyNonNullPtr {
+        self.mapping_begin.add(self.page_size)
+    }
+
+    pub unsafe fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        linux::sync_mapping(self.mapping_begin, self.file_size)
+    }
+
+    unsafe fn offset_of(&self, addr: AnyNonNullPtr, len: usize) -> usize {
+        assert!(util::bits::is_aligned(addr.as_addr(), self.page_size));
+        assert!(self.mapping_begin <= addr);
+
+        let offset = addr.offset_bytes_from(self.mapping_begin) as usize;
+        assert!(self.page_size <= offset && offset + len <= self.file_size);
+        assert!(!self.free_ranges.overlaps(&(offset..offset + len)));
+
+        offset
+    }
+
+    unsafe fn take_space_from_bottom(
+        &mut self,
+        len: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        assert!(util::bits::is_aligned(len, self.page_size));
+
+        let offset = self
+            .free_ranges
+            .take_lowest(self.mapping_begin.as_addr(), len, alignment_size)
+            .ok_or_else(file_overflow)?;
+        self.store_free_ranges();
+
+        Ok(self.mapping_begin.add(offset))
+    }
+
+    unsafe fn take_space_from_top(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        assert!(util::bits::is_aligned(len, self.page_size));
+
+        let offset = self
+            .free_ranges
+            .take_highest(len)
+            .ok_or_else(file_overflow)?;
+        self.store_free_ranges();
+
+        Ok(self.mapping_begin.add(offset))
+    }
+
+    unsafe fn store_free_ranges(&mut self) {
+        let file_header = self.file_header.as_mut();
+        let ranges = self.free_ranges.ranges();
+        file_header.free_range_count = ranges.len().min(FILE_FREE_RANGE_CAPACITY);
+        for (file_range, range) in file_header.free_ranges.iter_mut().zip(ranges) {
+            *file_range = FileRange {
+                begin: range.start,
+                end: range.end,
+            };
+        }
+    }
+}
+
+// The mapping is released on failure, since no env owns it yet.
+unsafe fn map_file(
+    fd: RawFd,
+    page_size: usize,
+    file_size: usize,
+    base_addr: usize,
+    is_created: bool,
+) -> Result<(AnyNonNullPtr, NonNull<FileHeader>), Box<dyn Error>> {
+    let mapping_begin = linux::map_shared_file_fixed(fd, file_size, base_addr)?;
+    let mut file_header = mapping_begin.as_nonnull::<FileHeader>();
+    let result = if is_created {
+        linux::allocate_file_space(fd, 0, page_size).map(|_| {
+            let mut free_ranges = [FileRange { begin: 0, end: 0 }; FILE_FREE_RANGE_CAPACITY];
+            free_ranges[0] = FileRange {
+                begin: page_size,
+                end: file_size,
+            };
+            *file_header.as_mut() = FileHeader {
+                magic: FILE_MAGIC,
+                file_size,
+                free_range_count: 1,
+                free_ranges,
+            };
+        })
+    } else {
+        check_file_header(file_header.as_ref(), file_size)
+    };
+    if let Err(err) = result {
+        linux::release(mapping_begin, file_size)?;
+        return Err(err);
+    }
+
+    Ok((mapping_begin, file_header))
+}
+
+fn check_file_header(file_header: &FileHeader, file_size: usize) -> Result<(), Box<dyn Error>> {
+    let error = if file_header.magic != FILE_MAGIC {
+        Some("Not a file of the arena.")
+    } else if file_header.file_size != file_size {
+        Some("Mismatched file size.")
+    } else if file_header.free_range_count > FILE_FREE_RANGE_CAPACITY {
+        Some("Too many free ranges.")
+    } else {
+        None
+    };
+    match error {
+        Some(reason) => Err(invalid_file(reason)),
+        None => Ok(()),
+    }
+}
+
+fn file_overflow() -> Box<dyn Error> {
+    Box::new(io::Error::new(
+        io::ErrorKind::OutOfMemory,
+        "Over the file size.",
+    ))
+}
+
+fn invalid_file(reason: &'static str) -> Box<dyn Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, reason))
+}
+
+impl SysMemEnv for SysMemEnvForFile {
+    unsafe fn get_pagesize(&mut self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.page_size)
+    }
+
+    unsafe fn reserve(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        self.take_space_from_bottom(len, self.page_size)
+    }
+
+    unsafe fn alloc(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        let ptr = self.take_space_from_top(len)?;
+        self.commit(ptr, len)?;
+        Ok(ptr)
+    }
+
+    unsafe fn commit(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        let offset = self.offset_of(addr, len);
+        // Allocate the backing blocks now, not to get SIGBUS on a full disk later.
+        linux::allocate_file_space(self.fd, offset, len)?;
+        linux::protect(addr, len, true)
+    }
+
+    unsafe fn soft_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        let offset = self.offset_of(addr, len);
+        // Punched holes are read as zero.
+        linux::punch_file_hole(self.fd, offset, len)?;
+        Ok(CommitState::SoftDecommitted)
+    }
+
+    unsafe fn hard_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        let offset = self.offset_of(addr, len);
+        linux::protect(addr, len, false)?;
+        linux::punch_file_hole(self.fd, offset, len)?;
+        Ok(CommitState::HardDecommitted)
+    }
+
+    unsafe fn release(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        let offset = self.offset_of(addr, len);
+        linux::protect(addr, len, false)?;
+        linux::punch_file_hole(self.fd, offset, len)?;
+        self.free_ranges.insert(offset..offset + len);
+        self.store_free_ranges();
+
+        Ok(())
+    }
+
+    // Soft decommitted pages are holes of the file, so they are allocated again not to get SIGBUS.
+    unsafe fn recommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+        state: CommitState,
+    ) -> Result<(), Box<dyn Error>> {
+        match state {
+            CommitState::Committed => Ok(()),
+            CommitState::SoftDecommitted => {
+                let offset = self.offset_of(addr, len);
+                linux::allocate_file_space(self.fd, offset, len)
+            }
+            CommitState::HardDecommitted => self.commit(addr, len),
+        }
+    }
+
+    unsafe fn reserve_aligned_space(
+        &mut self,
+        space_size: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        assert!(util::bits::is_aligned(space_size, alignment_size));
+
+        // No need to over-reserve, since free ranges are known.
+        self.take_space_from_bottom(space_size, alignment_size)
+    }
+}
+
+impl Drop for SysMemEnvForFile {
+    fn drop(&mut self) {
+        unsafe {
+            // Errors cannot be returned from drop.
+            let _ = linux::release(self.mapping_begin, self.file_size);
+            let _ = linux::close_file(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::allocator::SampleAlloc;
+    use crate::internal::layout::arena;
+    use crate::internal::layout::segment;
+    use crate::sys;
+
+    // Each test maps its file at its own address, since tests run in parallel.
+    unsafe fn with_file_env<F>(name: &str, base_addr: usize, mut f: F)
+    where
+        F: FnMut(&Path, usize),
+    {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        f(&path, base_addr);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recommits_soft_decommitted_spaces() {
+        unsafe {
+            with_file_env("recommit-test", 0x5a00_0000_0000, |path, base_addr| {
+                let mut env = SysMemEnvForFile::open(path, 1 << 20, base_addr).unwrap();
+                let len = 16 * env.get_pagesize().unwrap();
+                let mut ptr = env.alloc(len).unwrap();
+                let state = env.soft_decommit(ptr, len).unwrap();
+                let allocated_size = linux::file_allocated_size(env.fd).unwrap();
+
+                env.recommit(ptr, len, state).unwrap();
+                assert!(linux::file_allocated_size(env.fd).unwrap() >= allocated_size + len);
+                std::ptr::write_bytes(ptr.as_mut_ptr::<u8>(), 1, len);
+            });
+        }
+    }
+
+    #[test]
+    fn keeps_guard_pages_on_reopening() {
+        unsafe {
+            with_file_env("guard-page-test", 0x5b00_0000_0000, |path, base_addr| {
+                let config = arena::Config {
+                    min_heap_size: 1 << 18,
+                    max_heap_size: 32 << 20,
+                    keep_segments_count: 16,
+                    use_huge_pages: false,
+                    large_blocks_cache_size: 0,
+                    poisons_blocks: false,
+                    quarantine_size: 0,
+                    large_block_guard_page: arena::GuardPage::After,
+                };
+                let size = (2 << 20) + 8;
+                let ptr = {
+                    let mut env = SysMemEnvForFile::open(path, 64 << 20, base_addr).unwrap();
+                    assert!(env.is_created());
+                    let mut manager = SampleAlloc::init(&mut env, config).unwrap();
+                    manager.alloc_with_env(&mut env, size).unwrap()
+                };
+
+                let mut env = SysMemEnvForFile::open(path, 64 << 20, base_addr).unwrap();
+                assert!(!env.is_created());
+                let root_space = env.root_space();
+                let manager = SampleAlloc::open(&mut env, root_space).unwrap();
+                assert!(sys::faults_on_write(ptr.add(size)));
+                assert!(!sys::faults_on_write(ptr.add(size - 1)));
+                manager.release_with_env(&mut env).unwrap();
+            });
+        }
+    }
+
+    #[test]
+    fn reuses_released_spaces_across_reopening() {
+        unsafe {
+            with_file_env("churn-test", 0x5d00_0000_0000, |path, base_addr| {
+                let config = arena::Config {
+                    min_heap_size: 1 << 18,
+                    max_heap_size: 8 << 20,
+                    keep_segments_count: 16,
+                    use_huge_pages: false,
+                    large_blocks_cache_size: 0,
+                    poisons_blocks: false,
+                    quarantine_size: 0,
+                    large_block_guard_page: arena::GuardPage::None,
+                };
+                let file_size = 32 << 20;
+                let block_size = 2 * segment::MAX_SPAN_BLOCK_SIZE;
+                let (live_ptr, free_ranges) = {
+                    let mut env = SysMemEnvForFile::open(path, file_size, base_addr).unwrap();
+                    let mut manager = SampleAlloc::init(&mut env, config).unwrap();
+
+                    // The block allocated later stays live, so the earlier one is released in the middle.
+                    for _ in 0..4 * file_size / block_size {
+                        let ptr = manager.alloc_with_env(&mut env, block_size).unwrap();
+                        let pinned_ptr = manager.alloc_with_env(&mut env, block_size).unwrap();
+                        manager.free_with_env(&mut env, ptr).unwrap();
+                        assert!(sys::faults_on_write(ptr));
+                        manager.free_with_env(&mut env, pinned_ptr).unwrap();
+                    }
+
+                    let live_ptr = manager.alloc_with_env(&mut env, block_size).unwrap();
+                    (live_ptr, env.free_ranges.ranges().to_vec())
+                };
+
+                let mut env = SysMemEnvForFile::open(path, file_size, base_addr).unwrap();
+                assert_eq!(env.free_ranges.ranges(), free_ranges);
+                let root_space = env.root_space();
+                let mut manager = SampleAlloc::open(&mut env, root_space).unwrap();
+                manager.free_with_env(&mut env, live_ptr).unwrap();
+                manager.release_with_env(&mut env).unwrap();
+                assert_eq!(env.free_ranges.ranges(), vec![env.page_size..file_size]);
+            });
+        }
+    }
+
+    #[test]
+    fn opens_at_a_different_base_address() {
+        unsafe {
+            with_file_env("relocation-test", 0x5e00_0000_0000, |path, base_addr| {
+                let config = arena::Config {
+                    min_heap_size: 1 << 18,
+                    max_heap_size: 32 << 20,
+                    keep_segments_count: 16,
+                    use_huge_pages: false,
+                    large_blocks_cache_size: 8 << 20,
+                    poisons_blocks: false,
+                    quarantine_size: 0,
+                    large_block_guard_page: arena::GuardPage::None,
+                };
+                let file_size = 64 << 20;
+                // On a subheap, on a span, and of free size.
+                let sizes = [64, segment::SEGMENT_SIZE, 2 * segment::MAX_SPAN_BLOCK_SIZE];
+                let (ptrs, cached_ptr) = {
+                    let mut env = SysMemEnvForFile::open(path, file_size, base_addr).unwrap();
+                    let mut manager = SampleAlloc::init(&mut env, config).unwrap();
+                    let ptrs = sizes.map(|size| {
+                        let mut ptr = manager.alloc_with_env(&mut env, size).unwrap();
+                        std::ptr::write_bytes(ptr.as_mut_ptr::<u8>(), 0xa5, size);
+                        ptr
+                    });
+                    let cached_ptr = manager.alloc_with_env(&mut env, sizes[2]).unwrap();
+                    manager.free_with_env(&mut env, cached_ptr).unwrap();
+                    (ptrs, cached_ptr)
+                };
+
+                let other_base_addr = base_addr + (1 << 40);
+                let relocate = |ptr: AnyNonNullPtr| ptr.add(other_base_addr - base_addr);
+                let mut env = SysMemEnvForFile::open(path, file_size, other_base_addr).unwrap();
+                let root_space = env.root_space();
+                let mut manager = SampleAlloc::open(&mut env, root_space).unwrap();
+
+                // The cached mapping is found at the new address.
+                let ptr = manager.alloc_with_env(&mut env, sizes[2]).unwrap();
+                assert_eq!(ptr, relocate(cached_ptr));
+                manager.free_with_env(&mut env, ptr).unwrap();
+                for (ptr, size) in ptrs.into_iter().zip(sizes) {
+                    let ptr = relocate(ptr);
+                    let bytes = std::slice::from_raw_parts(ptr.as_nonnull::<u8>().as_ptr(), size);
+                    assert!(bytes.iter().all(|&byte| byte == 0xa5));
+                    manager.free_with_env(&mut env, ptr).unwrap();
+                }
+                manager.release_with_env(&mut env).unwrap();
+                assert_eq!(env.free_ranges.ranges(), vec![env.page_size..file_size]);
+            });
+        }
+    }
+
+    #[test]
+    fn unmaps_the_file_on_invalid_headers() {
+        unsafe {
+            with_file_env(
+                "invalid-header-test",
+                0x5c00_0000_0000,
+                |path, base_addr| {
+                    std::fs::write(path, vec![0xff; 1 << 20]).unwrap();
+                    assert!(SysMemEnvForFile::open(path, 1 << 20, base_addr).is_err());
+
+                    // The address is free again.
+                    std::fs::write(path, []).unwrap();
+                    SysMemEnvForFile::open(path, 1 << 20, base_addr).unwrap();
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::ops::Range;
+
+use crate::util;
+
+// Free ranges of offsets in a space, which are sorted and merged with their neighbors on insertion.
+#[derive(Debug)]
+pub struct FreeRanges {
+    ranges: Vec<Range<usize>>,
+}
+
+impl FreeRanges {
+    pub fn new(range: Range<usize>) -> Self {
+        let mut ranges = Vec::new();
+        if !range.is_empty() {
+            ranges.push(range);
+        }
+        Self { ranges }
+    }
+
+    #[inline]
+    pub fn ranges(&self) -> &[Range<usize>] {
+        &self.ranges
+    }
+
+    // Including the spaces which are too fragmented to be used at once.
+    #[inline]
+    pub fn total_size(&self) -> usize {
+        self.ranges.iter().map(|range| range.len()).sum()
+    }
+
+    pub fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.ranges
+            .iter()
+            .any(|free_range| free_range.start < range.end && range.start < free_range.end)
+    }
+
+    // Takes the lowest space whose address from `begin_addr` is aligned, and returns the offset of it.
+    pub fn take_lowest(
+        &mut self,
+        begin_addr: usize,
+        len: usize,
+        alignment_size: usize,
+    ) -> Option<usize> {
+        let (index, offset) = self.ranges.iter().enumerate().find_map(|(index, range)| {
+            let offset =
+                util::bits::min_aligned_size(begin_addr + range.start, alignment_size) - begin_addr;
+            (offset < range.end && range.end - offset >= len).then_some((index, offset))
+        })?;
+
+        self.take_at(index, offset..offset + len);
+        Some(offset)
+    }
+
+    // Takes the highest space, so that the lowest ones are kept for spaces taken from the bottom.
+    pub fn take_highest(&mut self, len: usize) -> Option<usize> {
+        let index = self.ranges.iter().rposition(|range| range.len() >= len)?;
+        let end = self.ranges[index].end;
+
+        self.take_at(index, end - len..end);
+        Some(end - len)
+    }
+
+    fn take_at(&mut self, index: usize, taken: Range<usize>) {
+        let range = self.ranges.remove(index);
+        for rest in [taken.end..range.end, range.start..taken.start] {
+            if !rest.is_empty() {
+                self.ranges.insert(index, rest);
+            }
+        }
+    }
+
+    pub fn insert(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let index = self
+            .ranges
+            .partition_point(|free_range| free_range.start < range.start);
+
+        let merges_next = index < self.ranges.len() && self.ranges[index].start == range.end;
+        let merges_prev = index > 0 && self.ranges[index - 1].end == range.start;
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                self.ranges[index - 1].end = self.ranges[index].end;
+                self.ranges.remove(index);
+            }
+            (true, false) => self.ranges[index - 1].end = range.end,
+            (false, true) => self.ranges[index].start = range.start,
+            (false, false) => self.ranges.insert(index, range),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_ranges_inserted_out_of_order() {
+        let mut free_ranges = FreeRanges::new(0..0);
+        for range in [40..50, 0..10, 20..30, 10..20, 30..40] {
+            free_ranges.insert(range);
+        }
+        assert_eq!(free_ranges.ranges, vec![0..50]);
+        assert!(free_ranges.overlaps(&(45..55)));
+        assert!(!free_ranges.overlaps(&(50..60)));
+    }
+
+    #[test]
+    fn takes_aligned_spaces() {
+        let mut free_ranges = FreeRanges::new(0..100);
+        assert_eq!(free_ranges.take_lowest(0x1003, 10, 8), Some(5));
+        assert_eq!(free_ranges.ranges, [0..5, 15..100]);
+        assert_eq!(free_ranges.total_size(), 90);
+
+        assert_eq!(free_ranges.take_lowest(0, 90, 1), None);
+        assert_eq!(free_ranges.take_lowest(0, 85, 1), Some(15));
+        assert_eq!(free_ranges.ranges, vec![0..5]);
+    }
+
+    #[test]
+    fn takes_spaces_from_the_top() {
+        let mut free_ranges = FreeRanges::new(0..10);
+        free_ranges.insert(20..30);
+        free_ranges.insert(40..45);
+        assert_eq!(free_ranges.take_highest(8), Some(22));
+        assert_eq!(free_ranges.take_highest(8), Some(2));
+        assert_eq!(free_ranges.take_highest(8), None);
+        assert_eq!(free_ranges.ranges(), [0..2, 20..22, 40..45]);
+    }
+}
@@ -1,7 +1,11 @@
 extern crate libc;
 
 use std::error::Error;
+use std::ffi::CString;
 use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
 use std::ptr::NonNull;
 use std::result::Result;
 
@@ -68,23 +72,6 @@ pub unsafe fn commit(
     }
 }
 
-pub unsafe fn force_commit(mut addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
-    // Remapping FIXED region is an unrecommended strategy.
-    let p = libc::mmap(
-        addr.as_mut_ptr(),
-        len,
-        libc::PROT_READ | libc::PROT_WRITE,
-        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED,
-        -1,
-        0,
-    );
-    if p == libc::MAP_FAILED {
-        Err(Box::new(io::Error::last_os_error()))
-    } else {
-        Ok(())
-    }
-}
-
 #[derive(PartialOrd, Ord, PartialEq, Eq, Clone, Copy, Debug)]
 pub enum SoftDecommitStrategy {
     MadviseFree,
@@ -167,6 +154,40 @@ pub unsafe fn hard_decommit(
     }
 }
 
+pub fn is_transparent_huge_page_enabled() -> bool {
+    match std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled") {
+        Ok(mode) => !mode.contains("[never]"),
+        Err(_) => false,
+    }
+}
+
+// The size of huge pages of THP, which is not always 2 MiB, e.g. on arm64 with 64 KiB pages.
+pub fn get_huge_page_size() -> Option<usize> {
+    match std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size") {
+        Ok(size) => size.trim().parse().ok(),
+        Err(_) => None,
+    }
+}
+
+pub unsafe fn advise_huge_pages(
+    mut addr: AnyNonNullPtr,
+    len: usize,
+) -> Result<bool, Box<dyn Error>> {
+    // MADV_HUGEPAGE was added in Linux 2.6.38.
+    let r = libc::madvise(addr.as_mut_ptr(), len, libc::MADV_HUGEPAGE);
+    if r == 0 {
+        return Ok(true);
+    }
+
+    // EINVAL is returned if the kernel is built without THP.
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EINVAL) {
+        Ok(false)
+    } else {
+        Err(Box::new(err))
+    }
+}
+
 pub unsafe fn alloc(len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
     let p = libc::mmap(
         std::ptr::null_mut(),
@@ -191,3 +212,165 @@ pub unsafe fn release(mut addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn
         Ok(())
     }
 }
+
+pub unsafe fn create_memfd(name: &str) -> Result<RawFd, Box<dyn Error>> {
+    let name = CString::new(name)?;
+    // memfd_create was added in Linux 3.17.
+    let fd = libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC);
+    if fd < 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(fd)
+    }
+}
+
+pub unsafe fn resize_file(fd: RawFd, len: usize) -> Result<(), Box<dyn Error>> {
+    let r = libc::ftruncate(fd, len as libc::off_t);
+    if r != 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+pub unsafe fn close_file(fd: RawFd) -> Result<(), Box<dyn Error>> {
+    let r = libc::close(fd);
+    if r != 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+pub unsafe fn map_shared_file(fd: RawFd, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+    let p = libc::mmap(
+        std::ptr::null_mut(),
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED,
+        fd,
+        0,
+    );
+    if p == libc::MAP_FAILED {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(AnyNonNullPtr::new(NonNull::new_unchecked(p)))
+    }
+}
+
+// Unlike commit and hard_decommit, never remap the region, which unshares it.
+pub unsafe fn protect(
+    mut addr: AnyNonNullPtr,
+    len: usize,
+    writable: bool,
+) -> Result<(), Box<dyn Error>> {
+    let prot = if writable {
+        libc::PROT_READ | libc::PROT_WRITE
+    } else {
+        libc::PROT_NONE
+    };
+    let r = libc::mprotect(addr.as_mut_ptr(), len, prot);
+    if r != 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+pub unsafe fn allocate_file_space(
+    fd: RawFd,
+    offset: usize,
+    len: usize,
+) -> Result<(), Box<dyn Error>> {
+    let r = libc::fallocate(fd, 0, offset as libc::off_t, len as libc::off_t);
+    if r != 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+pub unsafe fn punch_file_hole(fd: RawFd, offset: usize, len: usize) -> Result<(), Box<dyn Error>> {
+    // FALLOC_FL_PUNCH_HOLE was added in Linux 2.6.38, and in Linux 3.5 for tmpfs.
+    let r = libc::fallocate(
+        fd,
+        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+        offset as libc::off_t,
+        len as libc::off_t,
+    );
+    if r != 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+pub unsafe fn open_file(path: &Path) -> Result<RawFd, Box<dyn Error>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = libc::open(
+        path.as_ptr(),
+        libc::O_RDWR | libc::O_CREAT | libc::O_CLOEXEC,
+        0o600,
+    );
+    if fd < 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(fd)
+    }
+}
+
+// The size of blocks backing the file, which excludes holes.
+#[cfg(test)]
+pub unsafe fn file_allocated_size(fd: RawFd) -> Result<usize, Box<dyn Error>> {
+    let mut stat: libc::stat = std::mem::zeroed();
+    let r = libc::fstat(fd, &mut stat);
+    if r != 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(stat.st_blocks as usize * 512)
+    }
+}
+
+pub unsafe fn file_size(fd: RawFd) -> Result<usize, Box<dyn Error>> {
+    let mut stat: libc::stat = std::mem::zeroed();
+    let r = libc::fstat(fd, &mut stat);
+    if r != 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(stat.st_size as usize)
+    }
+}
+
+pub unsafe fn map_shared_file_fixed(
+    fd: RawFd,
+    len: usize,
+    addr: usize,
+) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+    // MAP_FIXED_NOREPLACE was added in Linux 4.17, and older kernels take it as a hint.
+    let p = libc::mmap(
+        addr as *mut libc::c_void,
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED | libc::MAP_FIXED_NOREPLACE,
+        fd,
+        0,
+    );
+    if p == libc::MAP_FAILED {
+        return Err(Box::new(io::Error::last_os_error()));
+    }
+    if p as usize != addr {
+        libc::munmap(p, len);
+        return Err(Box::new(io::Error::from_raw_os_error(libc::EEXIST)));
+    }
+
+    Ok(AnyNonNullPtr::new(NonNull::new_unchecked(p)))
+}
+
+pub unsafe fn sync_mapping(mut addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+    let r = libc::msync(addr.as_mut_ptr(), len, libc::MS_SYNC);
+    if r != 0 {
+        Err(Box::new(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
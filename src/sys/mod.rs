@@ -1,8 +1,19 @@
 use std::error::Error;
 use std::result::Result;
 
+// The binary maps its heaps from the system or a file, and the other envs are for embedders.
+#[allow(unused)]
+pub mod buffer;
+#[cfg(test)]
+pub mod fault_injecting;
+pub mod file;
+mod free_ranges;
 mod linux;
 pub mod ptr;
+#[cfg(test)]
+pub mod recording;
+#[allow(unused)]
+pub mod shared_memory;
 
 use crate::util;
 use ptr::AnyNonNullPtr;
@@ -12,23 +23,43 @@ pub trait SysMemEnv {
     unsafe fn reserve(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>>;
     unsafe fn alloc(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>>;
     unsafe fn commit(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>>;
-    unsafe fn force_commit(
-        &mut self,
-        addr: AnyNonNullPtr,
-        len: usize,
-    ) -> Result<(), Box<dyn Error>>;
     unsafe fn soft_decommit(
         &mut self,
         addr: AnyNonNullPtr,
         len: usize,
-    ) -> Result<(), Box<dyn Error>>;
+    ) -> Result<CommitState, Box<dyn Error>>;
     unsafe fn hard_decommit(
         &mut self,
         addr: AnyNonNullPtr,
         len: usize,
-    ) -> Result<(), Box<dyn Error>>;
+    ) -> Result<CommitState, Box<dyn Error>>;
     unsafe fn release(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>>;
 
+    unsafe fn get_huge_page_size(&mut self) -> Result<Option<usize>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    unsafe fn advise_huge_pages(
+        &mut self,
+        _addr: AnyNonNullPtr,
+        _len: usize,
+    ) -> Result<bool, Box<dyn Error>> {
+        Ok(false)
+    }
+
+    unsafe fn recommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+        state: CommitState,
+    ) -> Result<(), Box<dyn Error>> {
+        match state {
+            // Soft decommitted pages are still mapped as read/write.
+            CommitState::Committed | CommitState::SoftDecommitted => Ok(()),
+            CommitState::HardDecommitted => self.commit(addr, len),
+        }
+    }
+
     unsafe fn reserve_aligned_space(
         &mut self,
         space_size: usize,
@@ -54,6 +85,70 @@ pub trait SysMemEnv {
     }
 }
 
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CommitState {
+    Committed,
+    SoftDecommitted,
+    HardDecommitted,
+}
+
+pub const COMMIT_STATE_BIT_SIZE: usize = 2;
+
+impl CommitState {
+    #[inline]
+    pub const fn merge(self, another: Self) -> Self {
+        match (self, another) {
+            (CommitState::HardDecommitted, _) | (_, CommitState::HardDecommitted) => {
+                CommitState::HardDecommitted
+            }
+            (CommitState::Committed, CommitState::Committed) => CommitState::Committed,
+            _ => CommitState::SoftDecommitted,
+        }
+    }
+
+    #[inline]
+    pub const fn into_bits(self) -> usize {
+        match self {
+            CommitState::Committed => 0,
+            CommitState::SoftDecommitted => 1,
+            CommitState::HardDecommitted => 2,
+        }
+    }
+
+    #[inline]
+    pub const fn from_bits(bits: usize) -> Self {
+        match bits {
+            0 => CommitState::Committed,
+            1 => CommitState::SoftDecommitted,
+            2 => CommitState::HardDecommitted,
+            _ => panic!("unreachable: invalid commit state bits."),
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum SysCallKind {
+    Reserve,
+    ReserveAlignedSpace,
+    Alloc,
+    Commit,
+    SoftDecommit,
+    HardDecommit,
+    Release,
+    AdviseHugePages,
+    Recommit,
+}
+
+// The address is unknown until the call returns, for calls which map a new space.
+#[cfg(test)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct SysCall {
+    pub kind: SysCallKind,
+    pub addr: Option<AnyNonNullPtr>,
+    pub len: usize,
+}
+
 pub type SysMemEnvImpl = SysMemEnvForLinux;
 
 pub fn new_env() -> SysMemEnvImpl {
@@ -61,14 +156,31 @@ pub fn new_env() -> SysMemEnvImpl {
         prefer_commit_strategy: linux::CommitStrategy::MprotectRw,
         prefer_soft_decommit_strategy: linux::SoftDecommitStrategy::MadviseFree,
         prefer_hard_decommit_strategy: linux::HardDecommitStrategy::MprotectNone,
+        huge_pages_available: linux::is_transparent_huge_page_enabled(),
     }
 }
 
+// Writes a byte in a forked child, not to crash the test process on the fault.
+#[cfg(test)]
+pub unsafe fn faults_on_write(mut addr: AnyNonNullPtr) -> bool {
+    let pid = libc::fork();
+    assert!(pid >= 0);
+    if pid == 0 {
+        std::ptr::write_volatile(addr.as_mut_ptr::<u8>(), 1);
+        libc::_exit(0);
+    }
+
+    let mut status = 0;
+    assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+    libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+}
+
 #[derive(Debug)]
 pub struct SysMemEnvForLinux {
     prefer_commit_strategy: linux::CommitStrategy,
     prefer_soft_decommit_strategy: linux::SoftDecommitStrategy,
     prefer_hard_decommit_strategy: linux::HardDecommitStrategy,
+    huge_pages_available: bool,
 }
 
 impl SysMemEnv for SysMemEnvForLinux {
@@ -85,32 +197,24 @@ impl SysMemEnv for SysMemEnvForLinux {
         Ok(())
     }
 
-    unsafe fn force_commit(
-        &mut self,
-        addr: AnyNonNullPtr,
-        len: usize,
-    ) -> Result<(), Box<dyn Error>> {
-        linux::force_commit(addr, len)
-    }
-
     unsafe fn soft_decommit(
         &mut self,
         addr: AnyNonNullPtr,
         len: usize,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<CommitState, Box<dyn Error>> {
         self.prefer_soft_decommit_strategy =
             linux::soft_decommit(addr, len, self.prefer_soft_decommit_strategy)?;
-        Ok(())
+        Ok(CommitState::SoftDecommitted)
     }
 
     unsafe fn hard_decommit(
         &mut self,
         addr: AnyNonNullPtr,
         len: usize,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<CommitState, Box<dyn Error>> {
         self.prefer_hard_decommit_strategy =
             linux::hard_decommit(addr, len, self.prefer_hard_decommit_strategy)?;
-        Ok(())
+        Ok(CommitState::HardDecommitted)
     }
 
     unsafe fn alloc(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
@@ -120,4 +224,25 @@ impl SysMemEnv for SysMemEnvForLinux {
     unsafe fn release(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
         linux::release(addr, len)
     }
+
+    unsafe fn get_huge_page_size(&mut self) -> Result<Option<usize>, Box<dyn Error>> {
+        if !self.huge_pages_available {
+            return Ok(None);
+        }
+
+        Ok(linux::get_huge_page_size())
+    }
+
+    unsafe fn advise_huge_pages(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<bool, Box<dyn Error>> {
+        if !self.huge_pages_available {
+            return Ok(false);
+        }
+
+        self.huge_pages_available = linux::advise_huge_pages(addr, len)?;
+        Ok(self.huge_pages_available)
+    }
 }
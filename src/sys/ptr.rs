@@ -51,6 +51,19 @@ impl AnyNonNullPtr {
         }
     }
 
+    // Offsets wrap, so that a pointer below the base is also kept as an offset from it.
+    #[inline]
+    pub fn wrapping_offset_from(&self, base: Self) -> usize {
+        self.as_addr().wrapping_sub(base.as_addr())
+    }
+
+    #[inline]
+    pub unsafe fn wrapping_add(&self, offset: usize) -> Self {
+        Self {
+            raw: NonNull::new_unchecked(self.raw.as_ptr().wrapping_add(offset)),
+        }
+    }
+
     #[inline]
     pub unsafe fn offset_bytes_from(&self, another: Self) -> isize {
         self.raw.as_ptr().offset_from(another.raw.as_ptr())
@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::result::Result;
+
+use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
+use crate::sys::SysCall;
+use crate::sys::SysCallKind;
+use crate::sys::SysMemEnv;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RecordedCall {
+    pub call: SysCall,
+    pub succeeded: bool,
+}
+
+#[derive(Debug)]
+pub struct SysMemEnvWithRecording<Env> {
+    inner: Env,
+    calls: Vec<RecordedCall>,
+    mapped_size: usize,
+}
+
+impl<Env> SysMemEnvWithRecording<Env>
+where
+    Env: SysMemEnv,
+{
+    pub fn new(inner: Env) -> Self {
+        Self {
+            inner,
+            calls: Vec::new(),
+            mapped_size: 0,
+        }
+    }
+
+    #[inline]
+    pub fn calls(&self) -> &[RecordedCall] {
+        &self.calls
+    }
+
+    pub fn clear_calls(&mut self) {
+        self.calls.clear();
+    }
+
+    // The size of spaces which are reserved or allocated, and not released yet.
+    #[inline]
+    pub fn mapped_size(&self) -> usize {
+        self.mapped_size
+    }
+
+    fn record<T>(
+        &mut self,
+        kind: SysCallKind,
+        addr: Option<AnyNonNullPtr>,
+        len: usize,
+        result: &Result<T, Box<dyn Error>>,
+    ) {
+        self.calls.push(RecordedCall {
+            call: SysCall { kind, addr, len },
+            succeeded: result.is_ok(),
+        });
+    }
+
+    fn record_mapping(
+        &mut self,
+        kind: SysCallKind,
+        len: usize,
+        result: &Result<AnyNonNullPtr, Box<dyn Error>>,
+    ) {
+        let addr = result.as_ref().ok().copied();
+        self.record(kind, addr, len, result);
+        if result.is_ok() {
+            self.mapped_size += len;
+        }
+    }
+}
+
+impl<Env> SysMemEnv for SysMemEnvWithRecording<Env>
+where
+    Env: SysMemEnv,
+{
+    unsafe fn get_pagesize(&mut self) -> Result<usize, Box<dyn Error>> {
+        self.inner.get_pagesize()
+    }
+
+    unsafe fn get_huge_page_size(&mut self) -> Result<Option<usize>, Box<dyn Error>> {
+        self.inner.get_huge_page_size()
+    }
+
+    unsafe fn reserve(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        let result = self.inner.reserve(len);
+        self.record_mapping(SysCallKind::Reserve, len, &result);
+        result
+    }
+
+    unsafe fn alloc(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        let result = self.inner.alloc(len);
+        self.record_mapping(SysCallKind::Alloc, len, &result);
+        result
+    }
+
+    unsafe fn commit(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        let result = self.inner.commit(addr, len);
+        self.record(SysCallKind::Commit, Some(addr), len, &result);
+        result
+    }
+
+    unsafe fn soft_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        let result = self.inner.soft_decommit(addr, len);
+        self.record(SysCallKind::SoftDecommit, Some(addr), len, &result);
+        result
+    }
+
+    unsafe fn hard_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        let result = self.inner.hard_decommit(addr, len);
+        self.record(SysCallKind::HardDecommit, Some(addr), len, &result);
+        result
+    }
+
+    unsafe fn release(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        let result = self.inner.release(addr, len);
+        self.record(SysCallKind::Release, Some(addr), len, &result);
+        if result.is_ok() {
+            self.mapped_size -= len;
+        }
+        result
+    }
+
+    unsafe fn advise_huge_pages(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<bool, Box<dyn Error>> {
+        let result = self.inner.advise_huge_pages(addr, len);
+        self.record(SysCallKind::AdviseHugePages, Some(addr), len, &result);
+        result
+    }
+
+    // Delegate as one call, since the inner env may recommit in its own way.
+    unsafe fn recommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+        state: CommitState,
+    ) -> Result<(), Box<dyn Error>> {
+        let result = self.inner.recommit(addr, len, state);
+        self.record(SysCallKind::Recommit, Some(addr), len, &result);
+        result
+    }
+
+    // Delegate as one call, since the inner env may reserve in its own way.
+    unsafe fn reserve_aligned_space(
+        &mut self,
+        space_size: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        let result = self.inner.reserve_aligned_space(space_size, alignment_size);
+        self.record_mapping(SysCallKind::ReserveAlignedSpace, space_size, &result);
+        result
+    }
+}
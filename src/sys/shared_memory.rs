@@ -0,0 +1,288 @@
+use std::error::Error;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::result::Result;
+
+use crate::sys::free_ranges::FreeRanges;
+use crate::sys::linux;
+use crate::sys::ptr::AnyNonNullPtr;
+use crate::sys::CommitState;
+use crate::sys::SysMemEnv;
+use crate::util;
+
+// The whole file is mapped as shared at once, and spaces are carved out of its free ranges like a buffer.
+// Another process can map the same heap by the file descriptor at any address,
+// since the arena keeps offsets rather than pointers.
+#[derive(Debug)]
+pub struct SysMemEnvForSharedMemory {
+    fd: RawFd,
+    page_size: usize,
+    file_size: usize,
+    mapping_begin: AnyNonNullPtr,
+    free_ranges: FreeRanges,
+}
+
+impl SysMemEnvForSharedMemory {
+    pub unsafe fn new(name: &str, file_size: usize) -> Result<Self, Box<dyn Error>> {
+        let fd = linux::create_memfd(name)?;
+        match Self::from_fd(fd, file_size) {
+            Ok(env) => Ok(env),
+            Err(err) => {
+                linux::close_file(fd)?;
+                Err(err)
+            }
+        }
+    }
+
+    // Takes the ownership of the file descriptor, e.g. of a file in /dev/shm.
+    pub unsafe fn from_fd(fd: RawFd, file_size: usize) -> Result<Self, Box<dyn Error>> {
+        let page_size = linux::get_pagesize()?;
+        assert!(util::bits::is_aligned(file_size, page_size));
+
+        linux::resize_file(fd, file_size)?;
+        let mapping_begin = linux::map_shared_file(fd, file_size)?;
+        // Nothing is reserved yet.
+        linux::protect(mapping_begin, file_size, false)?;
+
+        Ok(Self {
+            fd,
+            page_size,
+            file_size,
+            mapping_begin,
+            free_ranges: FreeRanges::new(0..file_size),
+        })
+    }
+
+    #[inline]
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    #[inline]
+    pub fn mapping_begin(&self) -> AnyNonNullPtr {
+        self.mapping_begin
+    }
+
+    #[inline]
+    pub fn file_size(&self) -> usize {
+        self.file_size
+    }
+
+    fn file_overflow(&self) -> Box<dyn Error> {
+        Box::new(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            "Over the shared memory size.",
+        ))
+    }
+
+    #[inline]
+    unsafe fn offset_of(&self, addr: AnyNonNullPtr, len: usize) -> usize {
+        assert!(util::bits::is_aligned(addr.as_addr(), self.page_size));
+        assert!(self.mapping_begin <= addr);
+
+        let offset = addr.offset_bytes_from(self.mapping_begin) as usize;
+        assert!(offset + len <= self.file_size);
+        assert!(!self.free_ranges.overlaps(&(offset..offset + len)));
+
+        offset
+    }
+
+    unsafe fn take_space(
+        &mut self,
+        len: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        assert!(util::bits::is_aligned(len, self.page_size));
+
+        match self
+            .free_ranges
+            .take_lowest(self.mapping_begin.as_addr(), len, alignment_size)
+        {
+            Some(offset) => Ok(self.mapping_begin.add(offset)),
+            None => Err(self.file_overflow()),
+        }
+    }
+}
+
+impl SysMemEnv for SysMemEnvForSharedMemory {
+    unsafe fn get_pagesize(&mut self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.page_size)
+    }
+
+    unsafe fn reserve(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        self.take_space(len, self.page_size)
+    }
+
+    unsafe fn alloc(&mut self, len: usize) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        let ptr = self.take_space(len, self.page_size)?;
+        self.commit(ptr, len)?;
+        Ok(ptr)
+    }
+
+    unsafe fn commit(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        let offset = self.offset_of(addr, len);
+        // Allocate the backing pages now, not to get SIGBUS on a full tmpfs later.
+        linux::allocate_file_space(self.fd, offset, len)?;
+        linux::protect(addr, len, true)
+    }
+
+    unsafe fn soft_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        let offset = self.offset_of(addr, len);
+        // Punched holes are read as zero.
+        linux::punch_file_hole(self.fd, offset, len)?;
+        Ok(CommitState::SoftDecommitted)
+    }
+
+    unsafe fn hard_decommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+    ) -> Result<CommitState, Box<dyn Error>> {
+        let offset = self.offset_of(addr, len);
+        linux::protect(addr, len, false)?;
+        linux::punch_file_hole(self.fd, offset, len)?;
+        Ok(CommitState::HardDecommitted)
+    }
+
+    unsafe fn release(&mut self, addr: AnyNonNullPtr, len: usize) -> Result<(), Box<dyn Error>> {
+        let offset = self.offset_of(addr, len);
+        linux::protect(addr, len, false)?;
+        linux::punch_file_hole(self.fd, offset, len)?;
+        self.free_ranges.insert(offset..offset + len);
+
+        Ok(())
+    }
+
+    // Soft decommitted pages are holes of the file, so they are allocated again not to get SIGBUS.
+    unsafe fn recommit(
+        &mut self,
+        addr: AnyNonNullPtr,
+        len: usize,
+        state: CommitState,
+    ) -> Result<(), Box<dyn Error>> {
+        match state {
+            CommitState::Committed => Ok(()),
+            CommitState::SoftDecommitted => {
+                let offset = self.offset_of(addr, len);
+                linux::allocate_file_space(self.fd, offset, len)
+            }
+            CommitState::HardDecommitted => self.commit(addr, len),
+        }
+    }
+
+    unsafe fn reserve_aligned_space(
+        &mut self,
+        space_size: usize,
+        alignment_size: usize,
+    ) -> Result<AnyNonNullPtr, Box<dyn Error>> {
+        assert!(util::bits::is_aligned(space_size, alignment_size));
+
+        // No need to over-reserve, since free ranges are known.
+        self.take_space(space_size, alignment_size)
+    }
+}
+
+impl Drop for SysMemEnvForSharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            // Errors cannot be returned from drop.
+            let _ = linux::release(self.mapping_begin, self.file_size);
+            let _ = linux::close_file(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::allocator::SampleAlloc;
+    use crate::internal::error;
+    use crate::internal::layout::arena;
+    use crate::internal::layout::segment;
+
+    fn test_arena_config() -> arena::Config {
+        arena::Config {
+            min_heap_size: 1 << 18,
+            max_heap_size: 8 << 20,
+            keep_segments_count: 16,
+            use_huge_pages: false,
+            large_blocks_cache_size: 0,
+            poisons_blocks: false,
+            quarantine_size: 0,
+            large_block_guard_page: arena::GuardPage::None,
+        }
+    }
+
+    #[test]
+    fn recommits_soft_decommitted_spaces() {
+        unsafe {
+            let mut env = SysMemEnvForSharedMemory::new("recommit-test", 1 << 20).unwrap();
+            let len = 16 * env.get_pagesize().unwrap();
+            let mut ptr = env.alloc(len).unwrap();
+            assert!(linux::file_allocated_size(env.fd()).unwrap() >= len);
+
+            let state = env.soft_decommit(ptr, len).unwrap();
+            assert_eq!(linux::file_allocated_size(env.fd()).unwrap(), 0);
+
+            env.recommit(ptr, len, state).unwrap();
+            assert!(linux::file_allocated_size(env.fd()).unwrap() >= len);
+            std::ptr::write_bytes(ptr.as_mut_ptr::<u8>(), 1, len);
+            env.release(ptr, len).unwrap();
+        }
+    }
+
+    #[test]
+    fn reuses_spaces_of_large_blocks_released_out_of_order() {
+        unsafe {
+            let file_size = 16 << 20;
+            let mut env = SysMemEnvForSharedMemory::new("churn-test", file_size).unwrap();
+            let mut manager = SampleAlloc::init(&mut env, test_arena_config()).unwrap();
+
+            // The block allocated later stays live, so the earlier one is released below the top.
+            let block_size = 2 * segment::MAX_SPAN_BLOCK_SIZE;
+            for _ in 0..4 * file_size / block_size {
+                let ptr = manager.alloc_with_env(&mut env, block_size).unwrap();
+                let pinned_ptr = manager.alloc_with_env(&mut env, block_size).unwrap();
+                manager.free_with_env(&mut env, ptr).unwrap();
+                manager.free_with_env(&mut env, pinned_ptr).unwrap();
+            }
+
+            manager.release_with_env(&mut env).unwrap();
+            assert_eq!(env.free_ranges.total_size(), file_size);
+            assert_eq!(linux::file_allocated_size(env.fd()).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn opens_at_a_different_address() {
+        unsafe {
+            let mut env = SysMemEnvForSharedMemory::new("open-test", 64 << 20).unwrap();
+            let mut manager = SampleAlloc::init(&mut env, test_arena_config()).unwrap();
+            let sizes = [64, segment::SEGMENT_SIZE, 2 * segment::MAX_SPAN_BLOCK_SIZE];
+            let offsets = sizes.map(|size| {
+                let mut ptr = manager.alloc_with_env(&mut env, size).unwrap();
+                std::ptr::write_bytes(ptr.as_mut_ptr::<u8>(), 0xa5, size);
+                ptr.offset_bytes_from(env.mapping_begin()) as usize
+            });
+
+            // Another process maps the file at another address.
+            let other_mapping = linux::map_shared_file(env.fd(), env.file_size()).unwrap();
+            linux::release(env.mapping_begin, env.file_size).unwrap();
+            env.mapping_begin = other_mapping;
+
+            let mut manager = SampleAlloc::open(&mut env, other_mapping).unwrap();
+            for (offset, size) in offsets.into_iter().zip(sizes) {
+                let ptr = other_mapping.add(offset);
+                let bytes = std::slice::from_raw_parts(ptr.as_nonnull::<u8>().as_ptr(), size);
+                assert!(bytes.iter().all(|&byte| byte == 0xa5));
+                manager.free_with_env(&mut env, ptr).unwrap();
+            }
+            manager.release_with_env(&mut env).unwrap();
+            assert_eq!(env.free_ranges.total_size(), env.file_size());
+        }
+    }
+}
@@ -1,6 +1,6 @@
 #[inline]
 pub const fn is_aligned(value: usize, alignment_size: usize) -> bool {
-    value % alignment_size == 0
+    value.is_multiple_of(alignment_size)
 }
 
 #[allow(unused)]